@@ -3,8 +3,11 @@
 
 use rand::Rng;
 use serde::{Serialize, Deserialize};
+use bigint::uint::U256;
 use crate::crypto::hash::{H256, Hashable};
 use crate::transaction::{self, SignedTransaction};
+use crate::crypto::address::{self, H160};
+use crate::ledger_state::block_reward;
 
 extern crate chrono;
 use chrono::prelude::*;
@@ -16,6 +19,8 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: i64,
     pub merkle_root:H256,
+    /// address the coinbase of the block has to pay
+    pub payout_addr:H160,
 }
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Content {
@@ -34,6 +39,18 @@ impl Hashable for Block {
     }
 }
 
+impl Header {
+    /// Expected number of hashes needed to meet the difficulty, 2^256 / (difficulty + 1)
+    pub fn work(&self) -> U256 {
+        let target = U256::from_big_endian(self.difficulty.as_ref());
+        if target == U256::max_value() {
+            return U256::one();
+        }
+        // same as 2^256 / (target + 1) without leaving 256 bits
+        (!target / (target + U256::one())) + U256::one()
+    }
+}
+
 impl Hashable for Header {
     fn hash(&self) -> H256 {
         let encodedhead: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -49,10 +66,17 @@ pub fn generate_random_block(parent: &H256) -> Block {
 
     //let mut buffer: [u8; 32] = [0; 32];
     let b:H256 = hex!("00011718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920").into();
-    let h:Header = Header{parenthash:*parent,nonce:r1,difficulty:b,timestamp:local.timestamp_millis(),merkle_root:b};
+    let payout_addr = address::generate_random_address();
+    let mut h:Header = Header{parenthash:*parent,nonce:r1,difficulty:b,timestamp:local.timestamp_millis(),merkle_root:b,payout_addr};
+    //grind the nonce so that the block passes the proof-of-work check
+    while h.hash() >= b {
+        h.nonce = h.nonce.wrapping_add(1);
+    }
     let t = transaction::generate_random_signed_transaction();
     //transaction::pr();
     let mut vect:Vec<SignedTransaction> = vec![];
+    //the parent is unknown, pay the reward of the first blocks
+    vect.push(transaction::coinbase(payout_addr, block_reward(1)));
     vect.push(t);
     let c:Content = Content{data:vect};
     let b:Block = Block{header:h,content:c};
@@ -64,7 +88,7 @@ pub fn generate_genesis_block(parent: &H256) -> Block {
     let r1:u32 = 0;
     let r2:i64 = 0;
     //let local: DateTime<Local> = Local::now();
-    let h:Header = Header{parenthash:*parent,nonce:r1,difficulty:b,timestamp:r2,merkle_root:b};
+    let h:Header = Header{parenthash:*parent,nonce:r1,difficulty:b,timestamp:r2,merkle_root:b,payout_addr:H160::default()};
     let t = transaction::generate_genesis_signed_transaction();
     //transaction::pr();
     let mut vect:Vec<SignedTransaction> = vec![];
@@ -1,18 +1,49 @@
 use crate::block::{self, *};
 use crate::crypto::hash::{H256,Hashable};
+use crate::ledger_state::LedgerState;
+use crate::transaction_checks::validate_ledger;
+use bigint::uint::U256;
 use log::info;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::cmp::Reverse;
 
 extern crate chrono;
 use chrono::prelude::*;
 
+/// How the longest chain changed after an insertion. A plain extension of the
+/// tip has no disconnected blocks.
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// last block shared by the old and the new longest chain
+    pub fork_point: H256,
+    /// blocks that left the longest chain, old tip first
+    pub disconnected: Vec<Block>,
+    /// blocks that joined the longest chain, from the fork point to the new tip
+    pub connected: Vec<Block>,
+}
+
 pub struct Blockchain {
     pub chain:HashMap<H256,Block>,
     pub tiphash:H256,
-    pub heights:HashMap<H256,u8>,
+    pub heights:HashMap<H256,u64>,
+    /// total work of the chain ending at each block, the heaviest one is the longest chain
+    pub chain_work:HashMap<H256,U256>,
+    /// order in which the blocks of the chain and the buffer were received, the first
+    /// of the chains with the same work is the longest chain
+    pub arrival:HashMap<H256,u64>,
+    next_arrival:u64,
     pub buffer:HashMap<H256,Block>,
     pub totaldelay:i64,
+    pub genesis_hash:H256,
+    /// UTXO set of the longest chain
+    pub ledger:LedgerState,
+}
+
+/// The difficulty never changes, so a block has to carry its parent's difficulty
+/// and meet it. Otherwise it could claim more work than it did.
+fn follows_difficulty(block: &Block, parent: &Block) -> bool {
+    block.header.difficulty == parent.header.difficulty && block.hash() < parent.header.difficulty
 }
 
 impl Blockchain {
@@ -22,19 +53,25 @@ impl Blockchain {
         let b:H256 = buffer.into();
         let genesis:Block = block::generate_genesis_block(&b);
         let genhash:H256 = genesis.hash();
+        let genesis_work:U256 = genesis.header.work();
         let mut chainmap:HashMap<H256,Block> = HashMap::new();
-        let mut heightsmap:HashMap<H256,u8> = HashMap::new();
+        let mut heightsmap:HashMap<H256,u64> = HashMap::new();
+        let mut workmap:HashMap<H256,U256> = HashMap::new();
         let buffermap:HashMap<H256,Block> = HashMap::new();
         chainmap.insert(genhash,genesis);
         heightsmap.insert(genhash,0);
+        workmap.insert(genhash,genesis_work);
+        let mut arrivalmap:HashMap<H256,u64> = HashMap::new();
+        arrivalmap.insert(genhash,0);
         let t:H256 = genhash;
-        let newchain:Blockchain = Blockchain{chain:chainmap,tiphash:t,heights:heightsmap,buffer:buffermap,totaldelay:0};
+        let newchain:Blockchain = Blockchain{chain:chainmap,tiphash:t,heights:heightsmap,chain_work:workmap,arrival:arrivalmap,next_arrival:1,buffer:buffermap,totaldelay:0,genesis_hash:genhash,ledger:LedgerState::new(genhash)};
         newchain
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-
+    /// Insert a block into blockchain. If the longest chain changed, the ledger is
+    /// moved to the new tip and the change is returned.
+    pub fn insert(&mut self, block: &Block) -> Option<Reorg> {
+        let old_tip = self.tiphash;
 
         let h:H256 = block.hash();
         //let mut flag:bool = false;
@@ -43,7 +80,7 @@ impl Blockchain {
         match self.chain.get(&block.header.parenthash){
             Some(pblock) => { //insertion into mainchain
 
-                if h < pblock.header.difficulty && !self.chain.contains_key(&h) {
+                if follows_difficulty(block, pblock) && !self.chain.contains_key(&h) {
                 let b_delay = Local::now().timestamp_millis() - block.header.timestamp;
                 self.totaldelay = self.totaldelay + b_delay;
                 info!("Adding block with hash {} to chain",h);
@@ -51,56 +88,163 @@ impl Blockchain {
                 println!("Average delay is {}",self.totaldelay/(self.chain.len() as i64));
                 println!("Total number of blocks in blockchain:{}\n",self.chain.len());
                 self.chain.insert(h,block.clone());
-                let len = self.heights[&block.header.parenthash]+1;
-                self.heights.insert(h,len);
-                if len>self.heights[&self.tiphash] {
-                    self.tiphash = h;
-                }
+                self.record_arrival(h);
+                self.attach(h, &block.header);
 
                 //let mut bhash_copy:H256 = hash::generate_random_hash();
                 //if stale blocks parent has arrived, insert it into main chain
-                let mut bhash_vec = Vec::new();
                 let mut phash_q: VecDeque<H256>= VecDeque::new();
                 phash_q.push_back(h);
-                while !phash_q.is_empty() {
-                    match phash_q.pop_front() {
-                        Some(h) => for (bhash,blck) in self.buffer.iter(){
-                                if blck.header.parenthash == h {
-                                    //flag = true;
-                                    let bhash_copy:H256 = *bhash;
-                                    bhash_vec.push(bhash_copy);
-                                    self.chain.insert(bhash_copy,blck.clone());
-                                    let b_delay = Local::now().timestamp_millis() - block.header.timestamp;
-                                    self.totaldelay = self.totaldelay + b_delay;
-                                    info!("Adding block with hash {} to chain",blck.hash());
-                                    println!("Block delay is: {:?}",(Local::now().timestamp_millis() - blck.header.timestamp));
-                                    println!("Average delay is {}",self.totaldelay/(self.chain.len() as i64));
-                                    println!("Total number of blocks in blockchain:{}\n",self.chain.len());
-                                    let len = self.heights[&blck.header.parenthash]+1;
-                                    self.heights.insert(bhash_copy,len);
-                                    if len>self.heights[&self.tiphash] {
-                                        self.tiphash = bhash_copy;
-                                    }
-                                }
-                            },
-                        None => (),
+                while let Some(h) = phash_q.pop_front() {
+                    let children: Vec<H256> = self.buffer.iter()
+                        .filter(|(_, blck)| blck.header.parenthash == h)
+                        .map(|(bhash, _)| *bhash)
+                        .collect();
+                    for bhash_copy in children {
+                        let blck = self.buffer.remove(&bhash_copy).unwrap();
+                        if !follows_difficulty(&blck, &self.chain[&h]) {
+                            info!("Dropping buffered block {} with wrong difficulty",bhash_copy);
+                            self.arrival.remove(&bhash_copy);
+                            continue;
+                        }
+                        self.chain.insert(bhash_copy,blck.clone());
+                        let b_delay = Local::now().timestamp_millis() - block.header.timestamp;
+                        self.totaldelay = self.totaldelay + b_delay;
+                        info!("Adding block with hash {} to chain",blck.hash());
+                        println!("Block delay is: {:?}",(Local::now().timestamp_millis() - blck.header.timestamp));
+                        println!("Average delay is {}",self.totaldelay/(self.chain.len() as i64));
+                        println!("Total number of blocks in blockchain:{}\n",self.chain.len());
+                        self.attach(bhash_copy, &blck.header);
+                        //its own buffered children can follow now
+                        phash_q.push_back(bhash_copy);
                     }
                 }
-
-
-                for bh in bhash_vec{
-                    self.buffer.remove(&bh);
-                }
              }
             }, // insert stale block into buffer
             _ => {
-                  print!("Adding block with hash {} to buffer\n",h); 
+                  println!("Adding block with hash {} to buffer",h); 
                   if !self.buffer.contains_key(&h){
                   self.buffer.insert(h,block.clone()); 
+                  self.record_arrival(h);
                   }
                  },
         }
 
+        self.reorganize(old_tip)
+    }
+
+    /// Number a block received for the first time
+    fn record_arrival(&mut self, hash: H256) {
+        self.arrival.insert(hash, self.next_arrival);
+        self.next_arrival += 1;
+    }
+
+    /// Record height and chain work of a block whose parent is in the chain, and
+    /// make it the tip if its chain is heavier than the current one
+    fn attach(&mut self, hash: H256, header: &Header) {
+        let height = self.heights[&header.parenthash] + 1;
+        let work = self.chain_work[&header.parenthash] + header.work();
+        self.heights.insert(hash, height);
+        self.chain_work.insert(hash, work);
+        if self.heaviness(&hash) > self.heaviness(&self.tiphash) {
+            self.tiphash = hash;
+        }
+    }
+
+    /// Walk back from the old and the new tip to their fork point, then move the
+    /// ledger over: disconnect the old branch using its undo data, connect the new one.
+    /// A block of the new branch that is invalid on top of its parent, by its spends
+    /// or its coinbase, is dropped together with its descendants, and the heaviest remaining chain is
+    /// tried instead. None if the old tip stays the tip.
+    fn reorganize(&mut self, old_tip: H256) -> Option<Reorg> {
+        while self.tiphash != old_tip {
+            let mut disconnected: Vec<Block> = vec![];
+            let mut connected: Vec<Block> = vec![];
+            let mut old_hash = old_tip;
+            let mut new_hash = self.tiphash;
+            while self.heights[&old_hash] > self.heights[&new_hash] {
+                disconnected.push(self.chain[&old_hash].clone());
+                old_hash = self.chain[&old_hash].header.parenthash;
+            }
+            while self.heights[&new_hash] > self.heights[&old_hash] {
+                connected.push(self.chain[&new_hash].clone());
+                new_hash = self.chain[&new_hash].header.parenthash;
+            }
+            while old_hash != new_hash {
+                disconnected.push(self.chain[&old_hash].clone());
+                old_hash = self.chain[&old_hash].header.parenthash;
+                connected.push(self.chain[&new_hash].clone());
+                new_hash = self.chain[&new_hash].header.parenthash;
+            }
+            connected.reverse();
+
+            for blck in &disconnected {
+                self.ledger.disconnect_block(blck);
+            }
+            match self.connect_branch(&connected) {
+                Ok(()) => {
+                    if !disconnected.is_empty() {
+                        info!("Reorganization at fork point {}: {} blocks disconnected, {} connected",
+                              old_hash, disconnected.len(), connected.len());
+                    }
+                    return Some(Reorg { fork_point: old_hash, disconnected, connected });
+                }
+                Err(invalid) => {
+                    for blck in disconnected.iter().rev() {
+                        self.ledger.connect_block(blck);
+                    }
+                    self.remove_branch(invalid);
+                    self.tiphash = self.heaviest_tip();
+                }
+            }
+        }
+        None
+    }
+
+    /// Connect the blocks of a branch to the ledger, in order. At the first block
+    /// invalid on the ledger, the ones before it are disconnected again and
+    /// its hash is returned.
+    fn connect_branch(&mut self, branch: &[Block]) -> Result<(), H256> {
+        for (i, blck) in branch.iter().enumerate() {
+            if let Err(error) = validate_ledger(blck, &self.ledger) {
+                info!("Dropping block {} invalid on top of its parent: {}", blck.hash(), error);
+                for connected in branch[..i].iter().rev() {
+                    self.ledger.disconnect_block(connected);
+                }
+                return Err(blck.hash());
+            }
+            self.ledger.connect_block(blck);
+        }
+        Ok(())
+    }
+
+    /// Remove a block and all its descendants from the chain
+    fn remove_branch(&mut self, hash: H256) {
+        let mut queue: VecDeque<H256> = VecDeque::new();
+        queue.push_back(hash);
+        while let Some(h) = queue.pop_front() {
+            self.chain.remove(&h);
+            self.heights.remove(&h);
+            self.chain_work.remove(&h);
+            self.arrival.remove(&h);
+            let children: Vec<H256> = self.chain.iter()
+                .filter(|(_, blck)| blck.header.parenthash == h)
+                .map(|(bhash, _)| *bhash)
+                .collect();
+            queue.extend(children);
+        }
+    }
+
+    /// How a block in the chain ranks as a tip: by the work of its chain, then by
+    /// arrival, earlier first
+    fn heaviness(&self, hash: &H256) -> (U256, Reverse<u64>) {
+        (self.chain_work[hash], Reverse(self.arrival[hash]))
+    }
+
+    /// The block ending the chain with the most work, the one received first among
+    /// equally heavy chains
+    fn heaviest_tip(&self) -> H256 {
+        *self.chain_work.keys().max_by_key(|hash| self.heaviness(hash)).unwrap()
     }
 
     /// Get the last block's hash of the longest chain
@@ -108,19 +252,66 @@ impl Blockchain {
         self.tiphash
     }
 
+    /// Get the hashes of the longest chain, genesis first
+    pub fn main_chain(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = vec![];
+        let mut phash: H256 = self.tiphash;
+        while let Some(blck) = self.chain.get(&phash) {
+            hashes.push(phash);
+            phash = blck.header.parenthash;
+        }
+        hashes.reverse();
+        hashes
+    }
+
+    /// Get a block locator: the last ten hashes of the longest chain, then
+    /// exponentially sparser ones, always ending with genesis
+    pub fn block_locator(&self) -> Vec<H256> {
+        let main_chain = self.main_chain();
+        let mut locator: Vec<H256> = vec![];
+        let mut idx = main_chain.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(main_chain[idx]);
+            if idx == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            idx = idx.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Get at most `max_headers` headers of the longest chain following the first
+    /// locator hash which is on the longest chain
+    pub fn headers_after(&self, locator: &[H256], max_headers: usize) -> Vec<Header> {
+        let main_chain = self.main_chain();
+        let start = locator.iter()
+            .find_map(|hash| main_chain.iter().position(|h| h == hash));
+        match start {
+            Some(pos) => main_chain[(pos + 1)..].iter()
+                .take(max_headers)
+                .map(|hash| self.chain[hash].header.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
     /// Get the last block's hash of the longest chain
     #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
 
         let mut phash:H256 = self.tiphash;
         let mut result:Vec<H256>=vec![];
-        let mut buffer: [u8; 32] = [0; 32];
+        let buffer: [u8; 32] = [0; 32];
         let b:H256 = buffer.into();
         while(phash!=b){
             result.push(phash);
             phash = self.chain[&phash].header.parenthash;
         }
-        let mut res = result.reverse();
+        result.reverse();
         result
     }
 }
@@ -129,14 +320,260 @@ impl Blockchain {
 mod tests {
     use super::*;
     use crate::block;
-    use crate::crypto::hash::Hashable;
+    use crate::crypto::hash::{generate_random_hash, Hashable};
+    use crate::crypto::{address, key_pair};
+    use crate::crypto::address::H160;
+    use crate::ledger_state::{block_reward, COINBASE_MATURITY};
+    use crate::mempool::TransactionMempool;
+    use crate::transaction::{self, Amount, ChainId, SignedTransaction, Transaction, UtxoInput, UtxoOutput};
+    use ring::signature::KeyPair;
 
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
-        let block = block::generate_random_block(&genesis_hash);
+        let block = mined_block(&genesis_hash, vec![]);
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn headers_after_locator() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let mut hashes = vec![genesis_hash];
+        for _ in 0..12 {
+            let block = mined_block(&blockchain.tip(), vec![]);
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        assert_eq!(blockchain.main_chain(), hashes);
+
+        let locator = blockchain.block_locator();
+        assert_eq!(locator[0], blockchain.tip());
+        assert_eq!(*locator.last().unwrap(), genesis_hash);
+
+        let headers = blockchain.headers_after(&[hashes[5], genesis_hash], 3);
+        let received: Vec<H256> = headers.iter().map(|h| h.hash()).collect();
+        assert_eq!(received, hashes[6..9].to_vec());
+    }
+
+    #[test]
+    fn reorg_rolls_back_ledger() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+
+        //give a fresh key an output to spend
+        let key = key_pair::random();
+        let funding = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        let funded = UtxoOutput::to_address(address::address_from_public_key_ref(key.public_key()), Amount::new(50));
+        blockchain.ledger.state.state_map.insert(funding.clone(), funded);
+
+        let tx = Transaction{tx_input: vec![funding.clone()],
+                             tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(49))]};
+        let spend = SignedTransaction::signed_by(tx, &ChainId::default(), &key);
+
+        let mut mempool = TransactionMempool::new();
+        assert_eq!(blockchain.ledger.pending_fee(&spend), Amount::new(1));
+        mempool.insert(spend.clone(), Amount::new(1));
+
+        //the coinbase may claim the fee but nothing beyond it
+        let greedy = mined_block_paying(&genesis_hash, address::generate_random_address(),
+                                        block_reward(1).checked_add(Amount::new(2)).unwrap(), vec![spend.clone()]);
+        assert!(blockchain.insert(&greedy).is_none());
+        assert!(!blockchain.chain.contains_key(&greedy.hash()));
+        assert!(blockchain.ledger.state.state_map.contains_key(&funding));
+
+        //short branch spending the output
+        let a1 = mined_block_paying(&genesis_hash, address::generate_random_address(),
+                                    block_reward(1).checked_add(Amount::new(1)).unwrap(), vec![spend.clone()]);
+        let reorg = blockchain.insert(&a1).unwrap();
+        mempool.apply_reorg(&reorg, &blockchain.ledger);
+        assert!(reorg.disconnected.is_empty());
+        assert!(!blockchain.ledger.state.state_map.contains_key(&funding));
+        //the coinbase claims the fee
+        let reward = UtxoInput{tx_hash: a1.content.data[0].tx.hash(), idx: 0};
+        assert_eq!(Some(blockchain.ledger.state.state_map[&reward].value), block_reward(1).checked_add(Amount::new(1)));
+        assert!(mempool.get_transactions(10).is_empty());
+        let a2 = mined_block(&a1.hash(), vec![]);
+        blockchain.insert(&a2);
+
+        //longer branch without it, arriving out of order
+        let b1 = mined_block(&genesis_hash, vec![]);
+        let b2 = mined_block(&b1.hash(), vec![]);
+        let b3 = mined_block(&b2.hash(), vec![]);
+        assert!(blockchain.insert(&b3).is_none());
+        assert!(blockchain.insert(&b2).is_none());
+        let reorg = blockchain.insert(&b1).unwrap();
+        mempool.apply_reorg(&reorg, &blockchain.ledger);
+
+        assert_eq!(blockchain.tip(), b3.hash());
+        assert_eq!(reorg.fork_point, genesis_hash);
+        let disconnected: Vec<H256> = reorg.disconnected.iter().map(|b| b.hash()).collect();
+        let connected: Vec<H256> = reorg.connected.iter().map(|b| b.hash()).collect();
+        assert_eq!(disconnected, vec![a2.hash(), a1.hash()]);
+        assert_eq!(connected, vec![b1.hash(), b2.hash(), b3.hash()]);
+        assert_eq!(blockchain.ledger.tip, b3.hash());
+        assert!(blockchain.ledger.state.state_map.contains_key(&funding));
+        assert_eq!(mempool.get_transactions(10)[0].hash(), spend.hash());
+    }
+
+    #[test]
+    fn invalid_branch_is_dropped() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key = key_pair::random();
+        let funding = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        let funded = UtxoOutput::to_address(address::address_from_public_key_ref(key.public_key()), Amount::new(50));
+        blockchain.ledger.state.state_map.insert(funding.clone(), funded);
+        let spend = |value: u64| {
+            let tx = Transaction{tx_input: vec![funding.clone()],
+                                 tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(value))]};
+            SignedTransaction::signed_by(tx, &ChainId::default(), &key)
+        };
+
+        let a1 = mined_block(&genesis_hash, vec![spend(50)]);
+        assert!(blockchain.insert(&a1).is_some());
+
+        //a heavier branch spending the output twice in one block
+        let b1 = mined_block(&genesis_hash, vec![]);
+        let b2 = mined_block(&b1.hash(), vec![spend(49), spend(48)]);
+        let b3 = mined_block(&b2.hash(), vec![]);
+        assert!(blockchain.insert(&b1).is_none());
+        assert!(blockchain.insert(&b3).is_none());
+        assert!(blockchain.insert(&b2).is_none());
+
+        //the invalid block goes with its descendants, the old tip stays
+        assert!(!blockchain.chain.contains_key(&b2.hash()));
+        assert!(!blockchain.chain.contains_key(&b3.hash()));
+        assert!(blockchain.chain.contains_key(&b1.hash()));
+        assert_eq!(blockchain.tip(), a1.hash());
+        assert_eq!(blockchain.ledger.tip, a1.hash());
+        assert!(!blockchain.ledger.state.state_map.contains_key(&funding));
+
+        let b2 = mined_block(&b1.hash(), vec![spend(49)]);
+        assert!(blockchain.insert(&b2).is_some());
+        assert_eq!(blockchain.ledger.tip, b2.hash());
+    }
+
+    #[test]
+    fn header_work() {
+        let mut header = block::generate_genesis_block(&H256::default()).header;
+        header.difficulty = [255u8; 32].into();
+        assert_eq!(header.work(), U256::one());
+        let mut half = [255u8; 32];
+        half[0] = 127;
+        header.difficulty = half.into();
+        assert_eq!(header.work(), U256::from(2u64));
+        let mut quarter = [255u8; 32];
+        quarter[0] = 63;
+        header.difficulty = quarter.into();
+        assert_eq!(header.work(), U256::from(4u64));
+    }
+
+    #[test]
+    fn heaviest_chain_wins_ties_by_arrival() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = mined_block(&genesis_hash, vec![]);
+        let b1 = mined_block(&genesis_hash, vec![]);
+        blockchain.insert(&a1);
+        //same work, the first one stays the tip
+        assert!(blockchain.insert(&b1).is_none());
+        assert_eq!(blockchain.tip(), a1.hash());
+        assert_eq!(blockchain.chain_work[&a1.hash()], blockchain.chain_work[&genesis_hash] + a1.header.work());
+
+        //a block claiming an easier difficulty than its parent is rejected
+        let mut easy = mined_block(&b1.hash(), vec![]);
+        easy.header.difficulty = [255u8; 32].into();
+        assert!(blockchain.insert(&easy).is_none());
+        assert!(!blockchain.chain.contains_key(&easy.hash()));
+
+        let b2 = mined_block(&b1.hash(), vec![]);
+        assert!(blockchain.insert(&b2).is_some());
+        assert_eq!(blockchain.tip(), b2.hash());
+        assert_eq!(blockchain.heights[&b2.hash()], 2);
+    }
+
+    #[test]
+    fn equally_heavy_buffered_chains_fall_back_to_the_first_received() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let a1 = mined_block(&genesis_hash, vec![]);
+        let a2 = mined_block(&a1.hash(), vec![]);
+        let b2 = mined_block(&a1.hash(), vec![]);
+        let greedy_reward = block_reward(3).checked_add(Amount::new(1)).unwrap();
+        let greedy = mined_block_paying(&a2.hash(), address::generate_random_address(), greedy_reward, vec![]);
+        //all but a1 wait in the buffer, and are attached in no particular order
+        for blck in &[&a2, &b2, &greedy] {
+            assert!(blockchain.insert(blck).is_none());
+        }
+        //the invalid tip is dropped, a2 and b2 are equally heavy and a2 came first
+        let reorg = blockchain.insert(&a1).unwrap();
+        assert!(!blockchain.chain.contains_key(&greedy.hash()));
+        assert_eq!(blockchain.tip(), a2.hash());
+        assert_eq!(reorg.connected.iter().map(|blck| blck.hash()).collect::<Vec<H256>>(), vec![a1.hash(), a2.hash()]);
+    }
+
+    #[test]
+    fn coinbase_reward_and_maturity() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+
+        //a coinbase minting more than the reward is rejected
+        let greedy_reward = block_reward(1).checked_add(Amount::new(1)).unwrap();
+        let greedy = mined_block_paying(&genesis_hash, address::generate_random_address(), greedy_reward, vec![]);
+        assert!(blockchain.insert(&greedy).is_none());
+        assert!(!blockchain.chain.contains_key(&greedy.hash()));
+
+        let key = key_pair::random();
+        let miner_addr = address::address_from_public_key_ref(key.public_key());
+        let b1 = mined_block_paying(&genesis_hash, miner_addr, block_reward(1), vec![]);
+        blockchain.insert(&b1);
+        let reward = UtxoInput{tx_hash: b1.content.data[0].tx.hash(), idx: 0};
+        assert!(blockchain.ledger.state.state_map.contains_key(&reward));
+
+        let tx = Transaction{tx_input: vec![reward.clone()],
+                             tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), block_reward(1))]};
+        let spend = SignedTransaction::signed_by(tx, &ChainId::default(), &key);
+
+        //the spend is skipped until the coinbase is COINBASE_MATURITY blocks deep,
+        //the ledger does not check proof-of-work so the blocks are not mined
+        let ledger = &mut blockchain.ledger;
+        let template = mined_block(&genesis_hash, vec![spend.clone()]);
+        let unmined = |parent: H256, nonce: u32| {
+            let mut block = template.clone();
+            block.header.parenthash = parent;
+            block.header.nonce = nonce;
+            block.content.data[0] = transaction::coinbase(block.header.payout_addr, block_reward(1));
+            block
+        };
+        for nonce in 1..(COINBASE_MATURITY as u32) {
+            ledger.connect_block(&unmined(ledger.tip, nonce));
+            assert!(ledger.state.state_map.contains_key(&reward));
+        }
+        let mature = unmined(ledger.tip, 0);
+        ledger.connect_block(&mature);
+        assert_eq!(ledger.height, 1 + COINBASE_MATURITY);
+        assert!(!ledger.state.state_map.contains_key(&reward));
+
+        ledger.disconnect_block(&mature);
+        assert!(ledger.state.state_map.contains_key(&reward));
+    }
+
+    fn mined_block(parent: &H256, data: Vec<SignedTransaction>) -> Block {
+        //test chains stay far below the first halving
+        mined_block_paying(parent, address::generate_random_address(), block_reward(1), data)
+    }
+
+    fn mined_block_paying(parent: &H256, payout_addr: H160, reward: Amount, mut data: Vec<SignedTransaction>) -> Block {
+        let difficulty: H256 = hex!("00011718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920").into();
+        let mut header = Header{parenthash: *parent, nonce: 0, difficulty,
+                                timestamp: Local::now().timestamp_millis(), merkle_root: generate_random_hash(), payout_addr};
+        while header.hash() >= difficulty {
+            header.nonce += 1;
+        }
+        data.insert(0, transaction::coinbase(payout_addr, reward));
+        Block{header, content: Content{data}}
+    }
 }
@@ -51,6 +51,21 @@ impl std::convert::From<H160> for [u8; 20] {
     }
 }
 
+// Parse an address from its 40 hex digits
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<H160, String> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        if bytes.len() != 20 {
+            return Err(format!("expected 20 bytes, got {}", bytes.len()));
+        }
+        let mut raw_address: [u8; 20] = [0; 20];
+        raw_address.copy_from_slice(&bytes);
+        Ok(H160(raw_address))
+    }
+}
+
 pub fn address_from_public_key_ref(public_key: &<Ed25519KeyPair as KeyPair>::PublicKey) -> H160 {
     let public_key_hash = digest::digest(&digest::SHA256, public_key.as_ref());
     
@@ -1,41 +1,186 @@
-use crate::transaction::{UtxoInput, UtxoOutput};
+use crate::transaction::{Amount, UtxoInput, UtxoOutput, SignedTransaction};
 use crate::crypto::hash::H256;
 use crate::block::Block;
 use crate::crypto::hash::Hashable;
 use crate::crypto::address::{self, H160};
+use crate::script::Spend;
+use crate::transaction_checks::ValidationError;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use log::debug;
 
+/// Reward of a block at height 1, halved every `HALVING_INTERVAL` blocks
+pub const INITIAL_BLOCK_REWARD: Amount = Amount::new(5000);
+pub const HALVING_INTERVAL: u64 = 210_000;
+/// Number of blocks a coinbase output has to be buried under before it can be spent
+pub const COINBASE_MATURITY: u64 = 100;
+
+/// Amount the coinbase of a block at `height` mints
+pub fn block_reward(height: u64) -> Amount {
+    if height / HALVING_INTERVAL >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::new(INITIAL_BLOCK_REWARD.as_u64() >> (height / HALVING_INTERVAL))
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct State{
     pub state_map: HashMap<UtxoInput, UtxoOutput>,  
+    /// height of the block each transaction was confirmed in, for relative timelocks
+    pub confirmed_heights: HashMap<H256, u64>,
 }
 
-pub struct BlockState{
-    pub block_state_map: HashMap<H256, State>,
+/// What connecting a block changed in the UTXO set, enough to disconnect it again
+#[derive(Debug, Default, Clone)]
+pub struct BlockUndo {
+    /// outputs spent by the block, with their contents
+    pub spent: Vec<(UtxoInput, UtxoOutput)>,
+    /// outputs created by the block
+    pub created: Vec<UtxoInput>,
 }
 
-//TODO: Can change the signature of the function if passing some mutex version
-//of block_state
-pub fn update_block_state(block: &Block, block_state: &mut BlockState) {
-  let parent_state = &block_state.block_state_map[&block.header.parenthash];
-  let mut cur_block_state = parent_state.clone();
-  
-  debug!{"parent_state {:?}", parent_state}
-
-  for signed_tx in &block.content.data {
-      for tx_input in &signed_tx.tx.tx_input {
-          cur_block_state.state_map.remove(tx_input);
-      }
-      for (i, tx_output) in (&signed_tx.tx.tx_output).iter().enumerate() {
-          let tx_input = UtxoInput{tx_hash: signed_tx.tx.hash(), idx: i as u8};
-          cur_block_state.state_map.insert(tx_input, tx_output.clone());
-      }
-  }
-  
-  debug!{"cur_block_state {:?}", cur_block_state}
-  block_state.block_state_map.insert(block.hash(), cur_block_state);
+/// Outputs spent and created by the transactions of a block checked so far, on top
+/// of the ledger
+#[derive(Debug, Default)]
+pub struct BlockSpends {
+    spent: HashSet<UtxoInput>,
+    created: HashMap<UtxoInput, UtxoOutput>,
+}
+
+/// UTXO set of the active chain, plus undo data for every connected block
+pub struct LedgerState {
+    pub state: State,
+    /// last block applied to `state`
+    pub tip: H256,
+    /// height of `tip`
+    pub height: u64,
+    /// height of the block each coinbase of the active chain was mined in
+    pub coinbase_heights: HashMap<H256, u64>,
+    undo: HashMap<H256, BlockUndo>,
+}
+
+impl LedgerState {
+    pub fn new(genesis_hash: H256) -> Self {
+        LedgerState {
+            state: ico(),
+            tip: genesis_hash,
+            height: 0,
+            coinbase_heights: HashMap::new(),
+            undo: HashMap::new(),
+        }
+    }
+
+    /// Apply a block extending `tip`. The block was checked against the ledger before,
+    /// its coinbase claims the reward and the fees. Transactions that are not valid
+    /// against the current state are skipped and leave no trace in the undo data.
+    pub fn connect_block(&mut self, block: &Block) {
+        assert_eq!(block.header.parenthash, self.tip, "block does not extend the ledger tip");
+        let height = self.height + 1;
+        let mut undo = BlockUndo::default();
+        for (position, signed_tx) in block.content.data.iter().enumerate() {
+            if position == 0 && signed_tx.tx.is_coinbase() {
+                //the coinbase input spends nothing
+                self.coinbase_heights.insert(signed_tx.tx.hash(), height);
+                self.state.confirmed_heights.insert(signed_tx.tx.hash(), height);
+            } else if self.fee(signed_tx, height).is_some() {
+                for tx_input in &signed_tx.tx.tx_input {
+                    let output = self.state.state_map.remove(tx_input).unwrap();
+                    undo.spent.push((tx_input.clone(), output));
+                }
+                self.state.confirmed_heights.insert(signed_tx.tx.hash(), height);
+            } else {
+                debug!("Skipping tx {} that does not apply to the ledger", signed_tx.hash());
+                continue;
+            }
+            for (i, tx_output) in signed_tx.tx.tx_output.iter().enumerate() {
+                let tx_input = UtxoInput{tx_hash: signed_tx.tx.hash(), idx: i as u32};
+                self.state.state_map.insert(tx_input.clone(), tx_output.clone());
+                undo.created.push(tx_input);
+            }
+        }
+        let block_hash = block.hash();
+        self.undo.insert(block_hash, undo);
+        self.tip = block_hash;
+        self.height = height;
+    }
+
+    /// Undo the block at `tip`, making its parent the tip again
+    pub fn disconnect_block(&mut self, block: &Block) {
+        let block_hash = block.hash();
+        assert_eq!(block_hash, self.tip, "only the ledger tip can be disconnected");
+        let undo = self.undo.remove(&block_hash).expect("missing undo data");
+        for tx_input in undo.created.iter().rev() {
+            self.state.state_map.remove(tx_input);
+            self.state.confirmed_heights.remove(&tx_input.tx_hash);
+        }
+        if let Some(coinbase) = block.content.data.first() {
+            self.coinbase_heights.remove(&coinbase.tx.hash());
+        }
+        for (tx_input, output) in undo.spent.into_iter().rev() {
+            self.state.state_map.insert(tx_input, output);
+        }
+        self.tip = block.header.parenthash;
+        self.height -= 1;
+    }
+
+    /// Check a transaction of a block at `height` against the ledger and the
+    /// transactions of the block before it, recorded in `spends`, and return its fee:
+    /// what its inputs hold beyond its outputs. Every input has to be unspent, unlocked
+    /// by its witness and, for coinbase outputs, mature. On success the transaction is
+    /// added to `spends`.
+    pub fn spend(&self, signed_tx: &SignedTransaction, height: u64, spends: &mut BlockSpends) -> Result<Amount, ValidationError> {
+        if signed_tx.witnesses.len() != signed_tx.tx.tx_input.len() {
+            return Err(ValidationError::WitnessCount { inputs: signed_tx.tx.tx_input.len(), witnesses: signed_tx.witnesses.len() });
+        }
+        let mut total_input_value = Amount::ZERO;
+        for (position, (input, witness)) in signed_tx.tx.tx_input.iter().zip(&signed_tx.witnesses).enumerate() {
+            if signed_tx.tx.tx_input[..position].contains(input) {
+                return Err(ValidationError::DuplicateInput(input.clone()));
+            }
+            //outputs created earlier in the block are confirmed at `height`
+            let (output, confirmed_at) = match spends.created.get(input) {
+                Some(output) => (output, height),
+                None => match self.state.state_map.get(input) {
+                    Some(output) => (output, self.state.confirmed_heights.get(&input.tx_hash).copied().unwrap_or(0)),
+                    None => return Err(ValidationError::MissingInput(input.clone())),
+                },
+            };
+            if spends.spent.contains(input) {
+                return Err(ValidationError::MissingInput(input.clone()));
+            }
+            if !output.script.is_satisfied(&Spend::new(witness, height, confirmed_at)) {
+                return Err(ValidationError::UnsatisfiedScript(input.clone()));
+            }
+            if let Some(coinbase_height) = self.coinbase_heights.get(&input.tx_hash) {
+                if height < coinbase_height + COINBASE_MATURITY {
+                    return Err(ValidationError::ImmatureCoinbase(input.clone()));
+                }
+            }
+            total_input_value = total_input_value.checked_add(output.value).ok_or(ValidationError::ValueOverflow)?;
+        }
+        let total_output_value = signed_tx.tx.output_value().ok_or(ValidationError::ValueOverflow)?;
+        let fee = total_input_value.checked_sub(total_output_value)
+            .ok_or(ValidationError::ValueMismatch { input: total_input_value, output: total_output_value })?;
+
+        spends.spent.extend(signed_tx.tx.tx_input.iter().cloned());
+        let tx_hash = signed_tx.tx.hash();
+        for (i, tx_output) in signed_tx.tx.tx_output.iter().enumerate() {
+            spends.created.insert(UtxoInput{tx_hash, idx: i as u32}, tx_output.clone());
+        }
+        Ok(fee)
+    }
+
+    /// Fee a transaction pays in a block at `height` on top of the ledger, None unless
+    /// it passes `spend`
+    pub fn fee(&self, signed_tx: &SignedTransaction, height: u64) -> Option<Amount> {
+        self.spend(signed_tx, height, &mut BlockSpends::default()).ok()
+    }
+
+    /// Fee of a transaction waiting to be mined on top of `tip`, 0 if it does not
+    /// apply to the ledger yet, e.g. because it spends unconfirmed outputs
+    pub fn pending_fee(&self, signed_tx: &SignedTransaction) -> Amount {
+        self.fee(signed_tx, self.height + 1).unwrap_or(Amount::ZERO)
+    }
 }
 
 pub fn ico() -> State {
@@ -53,12 +198,12 @@ pub fn ico() -> State {
   address_vec.push(address3);
 
   let initial_tx_hash: H256 = hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920").into() ;
-  let val: u32 = 10000000;
+  let val = Amount::new(10000000);
   
-  let mut initial_state: State = State{state_map: HashMap::new()};
+  let mut initial_state = State::default();
   for (i,address) in  address_vec.iter().enumerate() {
-    let input = UtxoInput{tx_hash: initial_tx_hash, idx: i as u8};
-    let output = UtxoOutput{receipient_addr: *address, value: val};
+    let input = UtxoInput{tx_hash: initial_tx_hash, idx: i as u32};
+    let output = UtxoOutput::to_address(*address, val);
     initial_state.state_map.insert(input, output);
   }
 
@@ -8,6 +8,7 @@ pub mod blockchain;
 pub mod crypto;
 pub mod miner;
 pub mod network;
+pub mod script;
 pub mod transaction;
 pub mod mempool;
 pub mod transaction_checks;
@@ -15,9 +16,11 @@ pub mod ledger_state;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
-use network::{server, worker};
+use crypto::address::{self, H160};
+use transaction::ChainId;
+use network::{discovery, server, worker};
 use std::net;
 use std::process;
 use std::thread;
@@ -33,7 +36,12 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg target_peers: --("target-peers") [INT] default_value("8") "Sets the number of outgoing connections kept open to discovered peers")
+     (@arg ban_threshold: --("ban-threshold") [INT] default_value("100") "Sets the misbehavior score at which a peer is disconnected and banned")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("3600") "Sets how long a misbehaving peer stays banned")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg payout_addr: --("payout-addr") [ADDR] "Sets the address, in hex, that block rewards of mined blocks are paid to")
+     (@arg network: --network [NAME] default_value("testnet") "Sets the name of the network, transactions signed for other networks are rejected")
     )
     .get_matches();
 
@@ -66,16 +74,57 @@ fn main() {
 
 
 
+    // parse the ban policy for misbehaving peers
+    let ban_threshold = matches
+        .value_of("ban_threshold")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban threshold: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let ban_policy = server::BanPolicy {
+        threshold: ban_threshold,
+        duration: time::Duration::from_secs(ban_duration),
+    };
+
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, ban_policy).unwrap();
     server_ctx.start().unwrap();
 
-    // start the miner
     let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::new()));
     let tx_mempool = Arc::new(Mutex::new(mempool::TransactionMempool::new()));
+
+    // transactions are signed for this network, identified by its name and genesis
+    let network = matches.value_of("network").unwrap();
+    let chain_id = ChainId::new(network, &blockchain.lock().unwrap().genesis_hash);
+    info!("network {} chain id {}", network, chain_id);
+
+    // start the miner
+    let payout_addr = match matches.value_of("payout_addr") {
+        Some(addr) => addr.parse::<H160>().unwrap_or_else(|e| {
+            error!("Error parsing payout address: {}", e);
+            process::exit(1);
+        }),
+        None => {
+            let addr = address::generate_random_address();
+            warn!("No payout address given, block rewards go to the unspendable address {:?}", addr);
+            addr
+        }
+    };
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
+        &tx_mempool,
+        payout_addr,
     );
     miner_ctx.start();
 
@@ -93,7 +142,8 @@ fn main() {
         msg_rx,
         &server,
         &blockchain,
-        &tx_mempool
+        &tx_mempool,
+        chain_id,
     );
     worker_ctx.start();
 
@@ -103,6 +153,7 @@ fn main() {
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let blockchain = Arc::clone(&blockchain);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -113,9 +164,12 @@ fn main() {
                             break;
                         }
                     };
+                    server.add_addrs(vec![addr]);
                     match server.connect(addr) {
-                        Ok(_) => {
+                        Ok(peer) => {
                             info!("Connected to outgoing peer {}", &addr);
+                            // headers are requested once the peer accepted our version
+                            peer.send_version(worker::local_version(&blockchain.lock().unwrap(), server.listen_addr(), chain_id));
                             break;
                         }
                         Err(e) => {
@@ -133,6 +187,18 @@ fn main() {
     }
 
 
+    // keep outgoing connections to peers learnt through address gossip
+    let target_peers = matches
+        .value_of("target_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing target peers: {}", e);
+            process::exit(1);
+        });
+    let discovery_ctx = discovery::new(&server, &blockchain, target_peers, chain_id);
+    discovery_ctx.start();
+
     // start the API server
     ApiServer::start(
         api_addr,
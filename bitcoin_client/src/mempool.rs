@@ -1,19 +1,131 @@
-use crate::crypto::hash::H256;
-use crate::transaction::SignedTransaction;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{Amount, SignedTransaction};
+use crate::blockchain::Reorg;
+use crate::ledger_state::LedgerState;
 
-use std::collections::VecDeque;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Order in which waiting transactions are mined: fee per kilobyte, highest first,
+/// then arrival order
+type Priority = (Reverse<u64>, u64);
 
 pub struct TransactionMempool{
-  pub tx_hash_queue: VecDeque<H256>,
   pub tx_to_process: HashMap<H256, bool>,
   pub tx_map: HashMap<H256, SignedTransaction>,
+  /// transactions waiting to be mined, by priority
+  queue: BTreeMap<Priority, H256>,
+  /// priority of every transaction in `queue`
+  priorities: HashMap<H256, Priority>,
+  /// arrival counter, breaks ties between equal fee rates
+  sequence: u64,
+}
+
+/// Fee per kilobyte of the serialized transaction, rounded down and saturating for
+/// fees beyond `u64::MAX / 1000`
+fn fee_rate(signed_tx: &SignedTransaction, fee: Amount) -> u64 {
+  let size = bincode::serialize(signed_tx).unwrap().len() as u64;
+  fee.as_u64().saturating_mul(1000) / size.max(1)
 }
 
 impl TransactionMempool{
   pub fn new() -> Self{
-    TransactionMempool{tx_hash_queue: VecDeque::new(), 
-                       tx_to_process: HashMap::new(), 
-                       tx_map: HashMap::new()}  
+    TransactionMempool{tx_to_process: HashMap::new(),
+                       tx_map: HashMap::new(),
+                       queue: BTreeMap::new(),
+                       priorities: HashMap::new(),
+                       sequence: 0}
+  }
+
+  /// Queue a transaction paying `fee` for mining, unless it is already waiting
+  pub fn insert(&mut self, signed_tx: SignedTransaction, fee: Amount) {
+    let signed_tx_hash = signed_tx.hash();
+    if self.tx_to_process.insert(signed_tx_hash, true) != Some(true) {
+      let priority = (Reverse(fee_rate(&signed_tx, fee)), self.sequence);
+      self.sequence += 1;
+      self.queue.insert(priority, signed_tx_hash);
+      self.priorities.insert(signed_tx_hash, priority);
+    }
+    self.tx_map.insert(signed_tx_hash, signed_tx);
   }
+
+  /// Mark a transaction as mined, it stays known but is not offered to the miner
+  pub fn remove(&mut self, signed_tx: &SignedTransaction) {
+    let signed_tx_hash = signed_tx.hash();
+    self.tx_to_process.insert(signed_tx_hash, false);
+    if let Some(priority) = self.priorities.remove(&signed_tx_hash) {
+      self.queue.remove(&priority);
+    }
+    self.tx_map.entry(signed_tx_hash).or_insert_with(|| signed_tx.clone());
+  }
+
+  /// Transactions waiting to be mined, highest fee rate first
+  pub fn iter(&self) -> impl Iterator<Item = &SignedTransaction> {
+    self.queue.values().map(move |hash| &self.tx_map[hash])
+  }
+
+  /// Up to `max` transactions waiting to be mined, highest fee rate first
+  pub fn get_transactions(&self, max: usize) -> Vec<SignedTransaction> {
+    self.iter()
+      .take(max)
+      .cloned()
+      .collect()
+  }
+
+  /// Follow a change of the longest chain: transactions of disconnected blocks are
+  /// waiting again, unless the new branch includes them as well. Their fees are
+  /// looked up in `ledger`, which already follows the new chain.
+  pub fn apply_reorg(&mut self, reorg: &Reorg, ledger: &LedgerState) {
+    let connected: HashSet<H256> = reorg.connected.iter()
+      .flat_map(|blck| blck.content.data.iter().map(|signed_tx| signed_tx.hash()))
+      .collect();
+    for blck in &reorg.disconnected {
+      //coinbases are only valid in the block they were mined in
+      for signed_tx in blck.content.data.iter().filter(|signed_tx| !signed_tx.tx.is_coinbase()) {
+        if !connected.contains(&signed_tx.hash()) {
+          self.insert(signed_tx.clone(), ledger.pending_fee(signed_tx));
+        }
+      }
+    }
+    for blck in &reorg.connected {
+      for signed_tx in &blck.content.data {
+        self.remove(signed_tx);
+      }
+    }
+  }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::transaction::generate_random_signed_transaction;
+
+    #[test]
+    fn highest_fee_rate_first() {
+        let mut mempool = TransactionMempool::new();
+        let txs: Vec<SignedTransaction> = (0..4).map(|_| generate_random_signed_transaction()).collect();
+        for (signed_tx, fee) in txs.iter().zip(vec![0, 5, 0, 50]) {
+            mempool.insert(signed_tx.clone(), Amount::new(fee));
+        }
+
+        //equal fee rates keep their arrival order
+        let order: Vec<H256> = mempool.get_transactions(4).iter().map(|signed_tx| signed_tx.hash()).collect();
+        assert_eq!(order, vec![txs[3].hash(), txs[1].hash(), txs[0].hash(), txs[2].hash()]);
+
+        mempool.remove(&txs[3]);
+        mempool.insert(txs[1].clone(), Amount::new(500));
+        let order: Vec<H256> = mempool.get_transactions(2).iter().map(|signed_tx| signed_tx.hash()).collect();
+        assert_eq!(order, vec![txs[1].hash(), txs[0].hash()]);
+    }
+
+    #[test]
+    fn fee_rate_rounds_down() {
+        let signed_tx = generate_random_signed_transaction();
+        let size = bincode::serialize(&signed_tx).unwrap().len() as u64;
+        assert_eq!(fee_rate(&signed_tx, Amount::ZERO), 0);
+        assert_eq!(fee_rate(&signed_tx, Amount::new(1)), 1000 / size);
+        assert_eq!(fee_rate(&signed_tx, Amount::new(size)), 1000);
+        assert_eq!(fee_rate(&signed_tx, Amount::new(size + 1)), 1000 + 1000 / size);
+        assert_eq!(fee_rate(&signed_tx, Amount::MAX), u64::MAX / size);
+    }
 }
@@ -1,8 +1,11 @@
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
+use crate::mempool::TransactionMempool;
 use crate::block::*;
-use crate::transaction::{self, SignedTransaction};
+use crate::transaction::{self, Amount, SignedTransaction};
 use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::address::H160;
+use crate::ledger_state::{block_reward, BlockSpends};
 use crate::network::message::Message;
 use log::{debug,info};
 use rand::Rng;
@@ -15,6 +18,9 @@ use std::time;
 use std::thread;
 use std::sync::{Arc, Mutex};
 
+//most transactions taken from the mempool into one block
+const MAX_BLOCK_TXS: usize = 100;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Exit,
@@ -32,7 +38,10 @@ pub struct Context {
     operating_state: OperatingState,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    tx_mempool: Arc<Mutex<TransactionMempool>>,
     num_mined:u8,
+    /// address the coinbases of mined blocks pay to
+    payout_addr: H160,
 }
 
 #[derive(Clone)]
@@ -44,7 +53,9 @@ pub struct Handle {
 
 pub fn new(
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>
+    blockchain: &Arc<Mutex<Blockchain>>,
+    tx_mempool: &Arc<Mutex<TransactionMempool>>,
+    payout_addr: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -53,7 +64,9 @@ pub fn new(
         operating_state: OperatingState::Paused,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
+        tx_mempool: Arc::clone(tx_mempool),
         num_mined:0,
+        payout_addr,
     };
 
     let handle = Handle {
@@ -144,9 +157,24 @@ impl Context {
 
             //Creating Content
             //It will also be used for Merkel Root for the Header
-            let t = transaction::generate_random_signed_transaction();
+            //The coinbase goes first, followed by transactions waiting in the mempool
+            let mut locked_mempool = self.tx_mempool.lock().unwrap();
+            let height = locked_blockchain.heights[&phash] + 1;
+            //only transactions that apply on top of the tip, one after the other, their
+            //fees are claimed by the coinbase
+            let mut spends = BlockSpends::default();
+            let mut fees = Amount::ZERO;
             let mut vect: Vec<SignedTransaction> = vec![];
-            vect.push(t);
+            for signed_tx in locked_mempool.iter() {
+                if vect.len() == MAX_BLOCK_TXS {
+                    break;
+                }
+                if let Ok(fee) = locked_blockchain.ledger.spend(signed_tx, height, &mut spends) {
+                    fees = fees.saturating_add(fee);
+                    vect.push(signed_tx.clone());
+                }
+            }
+            vect.insert(0, transaction::coinbase(self.payout_addr, block_reward(height).saturating_add(fees)));
             let content: Content = Content{data:vect};
 
             let merkle_root = H256::from([0; 32]);
@@ -157,7 +185,8 @@ impl Context {
                 nonce: nonce,
                 difficulty: difficulty,
                 timestamp: timestamp,
-                merkle_root: merkle_root
+                merkle_root: merkle_root,
+                payout_addr: self.payout_addr,
             };
             let new_block = Block{header: header, content: content};
             //Check whether block solved the puzzle
@@ -165,7 +194,9 @@ impl Context {
             if new_block.hash() <= difficulty {
               println!("block with hash:{} generated\n",new_block.hash());
               println!("Number of blocks mined until now:{}\n",self.num_mined+1);
-              locked_blockchain.insert(&new_block);
+              if let Some(reorg) = locked_blockchain.insert(&new_block) {
+                  locked_mempool.apply_reorg(&reorg, &locked_blockchain.ledger);
+              }
               let encodedhead: Vec<u8> = bincode::serialize(&new_block).unwrap();
               debug!("Size of block generated is {} bytes\n",encodedhead.len());
               //print!("Total number of blocks in blockchain:{}\n",locked_blockchain.chain.len());
@@ -182,6 +213,7 @@ impl Context {
                 }
             }
 
+            std::mem::drop(locked_mempool);
             std::mem::drop(locked_blockchain);
         }
     }
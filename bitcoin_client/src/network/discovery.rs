@@ -0,0 +1,92 @@
+use super::server::Handle as ServerHandle;
+use super::worker;
+use crate::blockchain::Blockchain;
+use crate::transaction::ChainId;
+
+use log::{debug, info};
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+// how often the number of outgoing connections is checked
+const DISCOVERY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+// addresses we failed to connect to this many times are not tried again, even when
+// gossip brings them back
+const MAX_CONNECT_FAILURES: u32 = 3;
+
+// Keeps the number of outgoing connections at `target_peers`, using the
+// addresses learnt from GetAddr/Addr gossip
+pub struct Context {
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    target_peers: usize,
+    chain_id: ChainId,
+    failures: HashMap<SocketAddr, u32>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    target_peers: usize,
+    chain_id: ChainId,
+) -> Context {
+    Context {
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        target_peers,
+        chain_id,
+        failures: HashMap::new(),
+    }
+}
+
+impl Context {
+    pub fn start(mut self) {
+        let target_peers = self.target_peers;
+        thread::Builder::new()
+            .name("discovery".to_string())
+            .spawn(move || {
+                self.discovery_loop();
+            })
+            .unwrap();
+        info!("Peer discovery started, targeting {} outgoing peers", target_peers);
+    }
+
+    fn discovery_loop(&mut self) {
+        loop {
+            self.fill_outgoing();
+            thread::sleep(DISCOVERY_INTERVAL);
+        }
+    }
+
+    fn fill_outgoing(&mut self) {
+        let summary = self.server.peer_summary();
+        if summary.num_outgoing >= self.target_peers {
+            return;
+        }
+
+        let mut candidates: Vec<SocketAddr> = self.server.get_addrs()
+            .into_iter()
+            .filter(|addr| !summary.connected.contains(addr))
+            .filter(|addr| self.failures.get(addr).cloned().unwrap_or(0) < MAX_CONNECT_FAILURES)
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+
+        for addr in candidates.into_iter().take(self.target_peers - summary.num_outgoing) {
+            match self.server.connect(addr) {
+                Ok(peer) => {
+                    info!("Connected to discovered peer {}", addr);
+                    self.failures.remove(&addr);
+                    peer.send_version(worker::local_version(&self.blockchain.lock().unwrap(), self.server.listen_addr(), self.chain_id));
+                }
+                Err(e) => {
+                    debug!("Error connecting to discovered peer {}, forgetting it: {}", addr, e);
+                    *self.failures.entry(addr).or_insert(0) += 1;
+                    self.server.remove_addr(addr);
+                }
+            }
+        }
+    }
+}
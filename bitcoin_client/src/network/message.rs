@@ -1,7 +1,23 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
-use crate::block::Block;
-use crate::transaction::SignedTransaction;
+use std::net::SocketAddr;
+use crate::block::{Block, Header};
+use crate::transaction::{ChainId, SignedTransaction};
+
+// bumped whenever the wire format or consensus rules change incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// sent by both sides right after a connection is established
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    // address the sender accepts P2P connections on
+    pub listen_addr: SocketAddr,
+    pub genesis_hash: H256,
+    // network the sender signs and checks transactions for
+    pub chain_id: ChainId,
+    pub best_height: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -13,4 +29,16 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    Version(VersionInfo),
+    VerAck,
+    GetAddr,
+    Addr(Vec<SocketAddr>),
 }
+
+// maximum number of addresses sent in one Addr message
+pub const MAX_ADDR: usize = 1000;
+
+// maximum number of headers sent in one Headers message
+pub const MAX_HEADERS: usize = 2000;
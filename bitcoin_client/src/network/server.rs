@@ -4,23 +4,51 @@ use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use rand::seq::IteratorRandom;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+// upper bound on the number of addresses remembered from gossip
+const MAX_ADDR_BOOK: usize = 4096;
+// how long dialing a peer may take before it counts as unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When a misbehaving peer gets disconnected, and for how long it stays banned.
+#[derive(Clone, Copy)]
+pub struct BanPolicy {
+    pub threshold: u32,
+    pub duration: Duration,
+}
+
+/// An entry of the ban list, as reported through the API.
+#[derive(Serialize, Clone)]
+pub struct BannedPeer {
+    pub ip: std::net::IpAddr,
+    pub remaining_secs: u64,
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    ban_policy: BanPolicy,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        addr,
     };
     let ctx = Context {
         peers: slab::Slab::new(),
         peer_list: vec![],
+        addr_book: HashSet::new(),
+        scores: HashMap::new(),
+        banned: HashMap::new(),
+        ban_policy,
         addr,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
@@ -33,6 +61,13 @@ pub fn new(
 pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
+    // listening addresses of nodes we heard about, connected or not
+    addr_book: HashSet<std::net::SocketAddr>,
+    // accumulated misbehavior score of each connected peer
+    scores: HashMap<std::net::SocketAddr, u32>,
+    // banned IPs, whatever port they connect from, and when their ban expires
+    banned: HashMap<std::net::IpAddr, Instant>,
+    ban_policy: BanPolicy,
     addr: std::net::SocketAddr,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
@@ -97,11 +132,15 @@ impl Context {
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
-        debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+    /// Register a peer we connected to, unless it is banned
+    fn connect(&mut self, addr: &std::net::SocketAddr, stream: std::net::TcpStream) -> std::io::Result<peer::Handle> {
+        if self.is_banned(&addr.ip()) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "peer is banned",
+            ));
+        }
         let mio_stream = net::TcpStream::from_stream(stream)?;
         self.register(mio_stream, peer::Direction::Outgoing)
     }
@@ -112,6 +151,11 @@ impl Context {
         stream: net::TcpStream,
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
+        if self.is_banned(&addr.ip()) {
+            debug!("Refusing incoming connection from banned {}", addr);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return Ok(());
+        }
         debug!("New incoming connection from {}", addr);
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
@@ -128,19 +172,135 @@ impl Context {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
+                let handle = self.connect(&req.addr, req.stream);
                 req.result_chan.send(handle).unwrap();
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    // peers that have not finished the handshake don't get broadcasts
+                    if self.peers[*peer_id].handle.is_ready() {
+                        self.peers[*peer_id].handle.write(msg.clone());
+                    }
+                }
+            }
+            ControlSignal::AddAddrs(addrs) => {
+                trace!("Processing AddAddrs command");
+                for addr in addrs {
+                    if addr == self.addr || addr.ip().is_unspecified() || addr.port() == 0 || self.is_banned(&addr.ip()) {
+                        continue;
+                    }
+                    // a full book makes room by forgetting a random address
+                    if self.addr_book.len() >= MAX_ADDR_BOOK && !self.addr_book.contains(&addr) {
+                        let evicted = self.addr_book.iter().cloned().choose(&mut rand::thread_rng()).unwrap();
+                        self.addr_book.remove(&evicted);
+                    }
+                    self.addr_book.insert(addr);
+                }
+            }
+            ControlSignal::RemoveAddr(addr) => {
+                trace!("Processing RemoveAddr command");
+                self.addr_book.remove(&addr);
+            }
+            ControlSignal::GetAddrs(result_chan) => {
+                trace!("Processing GetAddrs command");
+                result_chan.send(self.addr_book.iter().cloned().collect()).unwrap();
+            }
+            ControlSignal::GetPeerSummary(result_chan) => {
+                trace!("Processing GetPeerSummary command");
+                let mut summary = PeerSummary {
+                    num_outgoing: 0,
+                    connected: HashSet::new(),
+                };
+                for peer_id in &self.peer_list {
+                    let peer = &self.peers[*peer_id];
+                    if let peer::Direction::Outgoing = peer.direction {
+                        summary.num_outgoing += 1;
+                    }
+                    summary.connected.insert(peer.addr);
+                    if let Some(version) = peer.handle.peer_version() {
+                        summary.connected.insert(version.listen_addr);
+                    }
+                }
+                result_chan.send(summary).unwrap();
+            }
+            ControlSignal::Misbehaving(addr, score, reason, result_chan) => {
+                trace!("Processing Misbehaving command");
+                let banned = self.misbehaving(addr, score, &reason);
+                result_chan.send(banned).unwrap();
+            }
+            ControlSignal::GetBanList(result_chan) => {
+                trace!("Processing GetBanList command");
+                let now = Instant::now();
+                self.banned.retain(|_, expiry| *expiry > now);
+                let ban_list = self.banned.iter().map(|(ip, expiry)| BannedPeer {
+                    ip: *ip,
+                    remaining_secs: (*expiry - now).as_secs(),
+                }).collect();
+                result_chan.send(ban_list).unwrap();
+            }
+            ControlSignal::DisconnectPeer(addr) => {
+                trace!("Processing DisconnectPeer command");
+                let peer_id = self.peer_list.iter().cloned().find(|id| self.peers[*id].addr == addr);
+                if let Some(peer_id) = peer_id {
+                    self.disconnect(peer_id);
                 }
             }
         }
         Ok(())
     }
 
+    /// Whether an IP is currently banned, forgetting expired bans.
+    fn is_banned(&mut self, ip: &std::net::IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(expiry) if *expiry > Instant::now() => true,
+            Some(_) => {
+                self.banned.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Add to the misbehavior score of a peer, banning and dropping it at the threshold.
+    /// Returns whether the peer got banned.
+    fn misbehaving(&mut self, addr: std::net::SocketAddr, score: u32, reason: &str) -> bool {
+        let peer_id = match self.peer_list.iter().cloned().find(|id| self.peers[*id].addr == addr) {
+            Some(peer_id) => peer_id,
+            None => return false,
+        };
+        let total = self.scores.entry(addr).or_insert(0);
+        *total += score;
+        warn!("Peer {} misbehaved ({}), score now {}", addr, reason, total);
+        if *total < self.ban_policy.threshold {
+            return false;
+        }
+
+        self.scores.remove(&addr);
+        // only the IP the connection comes from is banned, any address the peer claims,
+        // like the one it listens on, could be someone else's
+        let ip = addr.ip();
+        warn!("Banning {} for {} seconds", ip, self.ban_policy.duration.as_secs());
+        self.addr_book.retain(|book_addr| book_addr.ip() != ip);
+        self.banned.insert(ip, Instant::now() + self.ban_policy.duration);
+        self.disconnect(peer_id);
+        true
+    }
+
+    /// Close the connection to a peer and forget about it.
+    fn disconnect(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        info!("Disconnecting peer {}", peer.addr);
+        self.scores.remove(&peer.addr);
+        if let Err(e) = self.poll.deregister(&peer.stream) {
+            warn!("Error deregistering peer {}: {}", peer.addr, e);
+        }
+        let _ = self.poll.deregister(&peer.writer.queue);
+        let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -164,9 +324,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.disconnect(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -187,9 +345,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.disconnect(peer_id);
                         break;
                     }
                 }
@@ -224,9 +380,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.disconnect(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -353,6 +507,9 @@ impl Context {
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
+                                if !self.peers.contains(peer_id) {
+                                    continue;
+                                }
                                 self.register_write_interest(peer_id)?;
                             }
                             _ => unreachable!(),
@@ -367,13 +524,78 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    addr: std::net::SocketAddr,
+}
+
+/// Snapshot of the current connections, used to maintain outbound peers.
+pub struct PeerSummary {
+    pub num_outgoing: usize,
+    /// remote and advertised listening addresses of every connected peer
+    pub connected: HashSet<std::net::SocketAddr>,
 }
 
 impl Handle {
+    /// The address the P2P server listens on.
+    pub fn listen_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    pub fn add_addrs(&self, addrs: Vec<std::net::SocketAddr>) {
+        self.control_chan
+            .send(ControlSignal::AddAddrs(addrs))
+            .unwrap();
+    }
+
+    /// Forget an address, e.g. one we could not connect to
+    pub fn remove_addr(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::RemoveAddr(addr))
+            .unwrap();
+    }
+
+    pub fn get_addrs(&self) -> Vec<std::net::SocketAddr> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetAddrs(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Report a peer that sent something invalid. `score` is added to its misbehavior score.
+    /// Returns whether the peer got banned, in which case it is already disconnected.
+    pub fn misbehaving(&self, addr: std::net::SocketAddr, score: u32, reason: &str) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::Misbehaving(addr, score, reason.to_string(), sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    pub fn ban_list(&self) -> Vec<BannedPeer> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetBanList(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    pub fn peer_summary(&self) -> PeerSummary {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::GetPeerSummary(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Connect to a peer and register it. The connection is established on the
+    /// calling thread, so that the event loop never waits for an unreachable peer.
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        debug!("Establishing connection to peer {}", addr);
+        let stream = std::net::TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
             addr,
+            stream,
             result_chan: sender,
         };
         self.control_chan
@@ -387,14 +609,81 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::DisconnectPeer(addr))
+            .unwrap();
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    DisconnectPeer(std::net::SocketAddr),
+    AddAddrs(Vec<std::net::SocketAddr>),
+    RemoveAddr(std::net::SocketAddr),
+    GetAddrs(cbchannel::Sender<Vec<std::net::SocketAddr>>),
+    GetPeerSummary(cbchannel::Sender<PeerSummary>),
+    Misbehaving(std::net::SocketAddr, u32, String, cbchannel::Sender<bool>),
+    GetBanList(cbchannel::Sender<Vec<BannedPeer>>),
 }
 
 struct ConnectRequest {
     addr: std::net::SocketAddr,
+    stream: std::net::TcpStream,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+
+    // Connect to `listener`, returning the client side and the accepted side as a mio stream
+    fn incoming(listener: &std::net::TcpListener) -> (std::net::TcpStream, net::TcpStream, std::net::SocketAddr) {
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        (client, net::TcpStream::from_stream(stream).unwrap(), addr)
+    }
+
+    #[test]
+    fn banned_ip_is_refused_from_any_port() {
+        let (msg_sink, _msg_source) = cbchannel::unbounded();
+        let ban_policy = BanPolicy { threshold: 100, duration: Duration::from_secs(60) };
+        let (mut ctx, _handle) = new("127.0.0.1:0".parse().unwrap(), msg_sink, ban_policy).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let (_client, stream, addr) = incoming(&listener);
+        ctx.accept(stream, addr).unwrap();
+        assert_eq!(ctx.peer_list.len(), 1);
+        assert!(!ctx.misbehaving(addr, 60, "invalid block"));
+        assert_eq!(ctx.peer_list.len(), 1);
+        assert!(ctx.misbehaving(addr, 40, "invalid block"));
+        assert!(ctx.peer_list.is_empty());
+        assert_eq!(ctx.banned.keys().cloned().collect::<Vec<_>>(), vec![addr.ip()]);
+
+        // the same host coming back from another port is not registered
+        let (_client, stream, reconnect_addr) = incoming(&listener);
+        assert_ne!(reconnect_addr.port(), addr.port());
+        ctx.accept(stream, reconnect_addr).unwrap();
+        assert!(ctx.peer_list.is_empty());
+    }
+
+    #[test]
+    fn full_addr_book_evicts() {
+        let (msg_sink, _msg_source) = cbchannel::unbounded();
+        let ban_policy = BanPolicy { threshold: 100, duration: Duration::from_secs(60) };
+        let (mut ctx, _handle) = new("127.0.0.1:0".parse().unwrap(), msg_sink, ban_policy).unwrap();
+        let addr = |i: usize| std::net::SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 6000));
+
+        ctx.process_control(ControlSignal::AddAddrs((0..MAX_ADDR_BOOK).map(addr).collect())).unwrap();
+        assert_eq!(ctx.addr_book.len(), MAX_ADDR_BOOK);
+        // new addresses still get in, known ones do not push anything out
+        ctx.process_control(ControlSignal::AddAddrs(vec![addr(0), addr(MAX_ADDR_BOOK)])).unwrap();
+        assert_eq!(ctx.addr_book.len(), MAX_ADDR_BOOK);
+        assert!(ctx.addr_book.contains(&addr(MAX_ADDR_BOOK)));
+
+        ctx.process_control(ControlSignal::RemoveAddr(addr(MAX_ADDR_BOOK))).unwrap();
+        assert!(!ctx.addr_book.contains(&addr(MAX_ADDR_BOOK)));
+    }
+}
@@ -1,9 +1,9 @@
-use super::message::Message;
+use super::message::{Message, VersionInfo, MAX_ADDR, MAX_HEADERS, PROTOCOL_VERSION};
 use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::Blockchain;
 use crate::block::*;
-use crate::transaction::SignedTransaction;
+use crate::transaction::{ChainId, SignedTransaction};
 use crate::transaction_checks;
 use crate::mempool::TransactionMempool;
 use crate::crypto::hash::{H256, Hashable};
@@ -14,6 +14,15 @@ use log::{debug, warn};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+//misbehavior scores added to a peer, it is banned once the total reaches the ban threshold
+//a block failing checks that do not depend on our view of the chain, like proof of work or signatures
+const INVALID_BLOCK_PENALTY: u32 = 50;
+//a block rejected by checks against our chain, which may be behind the peer's or on another branch
+const REJECTED_BLOCK_PENALTY: u32 = 10;
+const INVALID_HEADER_PENALTY: u32 = 50;
+const INVALID_TX_PENALTY: u32 = 10;
+const UNDECODABLE_MESSAGE_PENALTY: u32 = 50;
+
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
@@ -21,6 +30,7 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     tx_mempool: Arc<Mutex<TransactionMempool>>,
+    chain_id: ChainId,
 }
 
 pub fn new(
@@ -28,14 +38,27 @@ pub fn new(
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
-    tx_mempool: &Arc<Mutex<TransactionMempool>>
+    tx_mempool: &Arc<Mutex<TransactionMempool>>,
+    chain_id: ChainId,
 ) -> Context {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        tx_mempool: Arc::clone(tx_mempool)
+        tx_mempool: Arc::clone(tx_mempool),
+        chain_id,
+    }
+}
+
+/// Version describing the chain we are following
+pub fn local_version(blockchain: &Blockchain, listen_addr: std::net::SocketAddr, chain_id: ChainId) -> VersionInfo {
+    VersionInfo {
+        protocol_version: PROTOCOL_VERSION,
+        listen_addr,
+        genesis_hash: blockchain.genesis_hash,
+        chain_id,
+        best_height: blockchain.heights[&blockchain.tiphash],
     }
 }
 
@@ -55,10 +78,54 @@ impl Context {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(error) => {
+                    debug!("Undecodable message from {}: {}", peer.addr(), error);
+                    self.server.misbehaving(peer.addr(), UNDECODABLE_MESSAGE_PENALTY, &error.to_string());
+                    continue;
+                }
+            };
+
+            //nothing but the handshake is processed before we accepted the peer's version
+            match msg {
+                Message::Version(_) | Message::VerAck => {}
+                _ => if !peer.is_accepted() {
+                    warn!("Ignoring message from {} before handshake", peer.addr());
+                    continue;
+                }
+            }
+
             let mut locked_blockchain = self.blockchain.lock().unwrap();
             let mut locked_mempool = self.tx_mempool.lock().unwrap();
             match msg {
+                Message::Version(version) => {
+                    let local = local_version(&locked_blockchain, self.server.listen_addr(), self.chain_id);
+                    if version.protocol_version != PROTOCOL_VERSION || version.genesis_hash != local.genesis_hash
+                        || version.chain_id != local.chain_id {
+                        warn!("Peer {} is incompatible: protocol {} genesis {} chain id {}, dropping it",
+                              peer.addr(), version.protocol_version, version.genesis_hash, version.chain_id);
+                        self.server.disconnect(peer.addr());
+                        continue;
+                    }
+                    //remember where the peer accepts connections, for address gossip
+                    self.server.add_addrs(vec![version.listen_addr]);
+                    //accept the version before answering, so the requests the peer sends after our VerAck
+                    //are not dropped by another worker at the handshake gate
+                    let completes = peer.version_received(version);
+                    //answer an incoming peer with our own version
+                    peer.send_version(local);
+                    peer.write(Message::VerAck);
+                    if completes {
+                        sync_with(&peer, &locked_blockchain);
+                    }
+                }
+                Message::VerAck => {
+                    if peer.verack_received() {
+                        sync_with(&peer, &locked_blockchain);
+                    }
+                }
+
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -121,50 +188,117 @@ impl Context {
                 Message::Blocks(vec_blocks) => {
                     debug!("Received Blocks message");
                     for blck in vec_blocks {
-                      let mut blck_is_valid: bool = true;
-                      for tx in &blck.content.data{
-                          if !transaction_checks::is_tx_valid(tx){
-                             blck_is_valid = false;
-                             debug!("Invalid tx in received block. Ignoring that block");
-                             break;
+                      //proof of work and signatures are wrong whatever chain we are on
+                      let validation = transaction_checks::validate_pow(&blck.header)
+                          .and_then(|_| transaction_checks::validate_transactions(&blck, &self.chain_id));
+                      if let Err(error) = validation {
+                        debug!("Invalid block {}: {}. Ignoring that block", blck.hash(), error);
+                        if self.server.misbehaving(peer.addr(), INVALID_BLOCK_PENALTY, &error.to_string()) {
+                          break;
+                        }
+                        continue;
+                      }
+                      //blocks extending the tip are checked against the ledger right away, the
+                      //others when their branch becomes the longest chain
+                      if blck.header.parenthash == locked_blockchain.ledger.tip {
+                        if let Err(error) = transaction_checks::validate_ledger(&blck, &locked_blockchain.ledger) {
+                          debug!("Block {} does not apply to the ledger: {}. Ignoring that block", blck.hash(), error);
+                          if self.server.misbehaving(peer.addr(), REJECTED_BLOCK_PENALTY, &error.to_string()) {
+                            break;
                           }
+                          continue;
+                        }
                       }
 
-                      if blck_is_valid {
-                        // added difficulty check in insert method
-                        locked_blockchain.insert(&blck);
-                        
-                        //Sending getblocks message if block is orphan
-                        let mut get_block_hash : Vec<H256> = vec![];
-                        get_block_hash.push(blck.header.parenthash);
-                        if !locked_blockchain.chain.contains_key(&blck.header.parenthash){
-                            self.server.broadcast(Message::GetBlocks(get_block_hash));
-                        }
+                      // added difficulty check in insert method
+                      let reorg = locked_blockchain.insert(&blck);
 
-                        //broadcasting NewBlockHashes
-                        let mut new_block_hash : Vec<H256> = vec![];
-                        new_block_hash.push(blck.hash());
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hash));
+                      //Updating mempool, the ledger already follows the longest chain
+                      if let Some(reorg) = reorg {
+                        locked_mempool.apply_reorg(&reorg, &locked_blockchain.ledger);
+                      }
 
-                        //Updating mempool
-                        for signed_tx in &blck.content.data {
-                          let signed_tx_hash = signed_tx.hash();
-                          match locked_mempool.tx_to_process.get(&signed_tx_hash){
-                              Some(_tx_present) => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, false);
-                              },
-                              None => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, false);
-                                  locked_mempool.tx_map.insert(signed_tx_hash, signed_tx.clone());
-                              }
-                          }
+                      //blocks dropped by the chain, e.g. for transactions that do not apply, are not relayed
+                      let hash = blck.hash();
+                      if !locked_blockchain.chain.contains_key(&hash) && !locked_blockchain.buffer.contains_key(&hash) {
+                        debug!("Block {} was rejected by the chain", hash);
+                        if self.server.misbehaving(peer.addr(), REJECTED_BLOCK_PENALTY, "block rejected by the chain") {
+                          break;
                         }
+                        continue;
+                      }
 
-                        //Updating State
-                      
+                      //Sending getblocks message if block is orphan
+                      let mut get_block_hash : Vec<H256> = vec![];
+                      get_block_hash.push(blck.header.parenthash);
+                      if !locked_blockchain.chain.contains_key(&blck.header.parenthash){
+                          self.server.broadcast(Message::GetBlocks(get_block_hash));
                       }
+
+                      //broadcasting NewBlockHashes
+                      let mut new_block_hash : Vec<H256> = vec![];
+                      new_block_hash.push(hash);
+                      self.server.broadcast(Message::NewBlockHashes(new_block_hash));
+                    }
+                }
+                Message::GetHeaders(locator) => {
+                    debug!("Received GetHeaders");
+                    let headers = locked_blockchain.headers_after(&locator, MAX_HEADERS);
+                    if headers.len() != 0 {
+                        debug!("Sending Headers message");
+                        peer.write(Message::Headers(headers));
                     }
                 }
+                Message::Headers(headers) => {
+                    debug!("Received {} headers", headers.len());
+                    let mut required_blocks: Vec<H256> = vec![];
+                    let mut last_hash: Option<H256> = None;
+                    for header in &headers {
+                        //headers have to link up, starting from a block we already know
+                        let linked = match last_hash {
+                            Some(h) => header.parenthash == h,
+                            None => locked_blockchain.chain.contains_key(&header.parenthash),
+                        };
+                        let hash = header.hash();
+                        let validation = if linked {
+                            transaction_checks::validate_pow(header)
+                        } else {
+                            Err(transaction_checks::ValidationError::UnknownParent(header.parenthash))
+                        };
+                        if let Err(error) = validation {
+                            debug!("Invalid header {}: {}, dropping the rest of the batch", hash, error);
+                            self.server.misbehaving(peer.addr(), INVALID_HEADER_PENALTY, &error.to_string());
+                            break;
+                        }
+                        if !locked_blockchain.chain.contains_key(&hash) && !locked_blockchain.buffer.contains_key(&hash) {
+                            required_blocks.push(hash);
+                        }
+                        last_hash = Some(hash);
+                    }
+
+                    if required_blocks.len() != 0 {
+                        debug!("Sending GetBlocks message for {} blocks", required_blocks.len());
+                        peer.write(Message::GetBlocks(required_blocks));
+                    }
+                    //a full batch means the peer has more headers to send
+                    if headers.len() == MAX_HEADERS {
+                        if let Some(h) = last_hash {
+                            peer.write(Message::GetHeaders(vec![h]));
+                        }
+                    }
+                }
+                Message::GetAddr => {
+                    let mut addrs = self.server.get_addrs();
+                    addrs.truncate(MAX_ADDR);
+                    if addrs.len() != 0 {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+                Message::Addr(mut addrs) => {
+                    debug!("Received {} addresses", addrs.len());
+                    addrs.truncate(MAX_ADDR);
+                    self.server.add_addrs(addrs);
+                }
                 Message::NewTransactionHashes(vec_tx_hashes) => {
                     let mut required_txs: Vec<H256> = vec![];
                     debug!("Received NewTransactionHashes");
@@ -202,18 +336,21 @@ impl Context {
                     debug!("Received Transactions");
                     let mut tx_hashes_to_broadcast: Vec<H256> = vec![];
                     for signed_tx in vec_signed_txs {
-                      if transaction_checks::is_tx_valid(&signed_tx){
+                      let validation = transaction_checks::validate_tx(&signed_tx, &self.chain_id);
+                      if validation.is_ok() {
                           let signed_tx_hash = signed_tx.hash();
                           match locked_mempool.tx_to_process.get(&signed_tx_hash){
                               Some(_tx_present) => debug!("tx_hash {} already present. Not adding to mempool", 
                                                          signed_tx_hash),
                               None => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, true);
-                                  locked_mempool.tx_map.insert(signed_tx_hash, signed_tx);
-                                  locked_mempool.tx_hash_queue.push_back(signed_tx_hash);
+                                  let fee = locked_blockchain.ledger.pending_fee(&signed_tx);
+                                  locked_mempool.insert(signed_tx, fee);
                                   tx_hashes_to_broadcast.push(signed_tx_hash);
                               }
                           }
+                      } else if let Err(error) = validation {
+                          debug!("Invalid tx {} received: {}", signed_tx.hash(), error);
+                          self.server.misbehaving(peer.addr(), INVALID_TX_PENALTY, &error.to_string());
                       }
                     }
                     if tx_hashes_to_broadcast.len() != 0{
@@ -224,3 +361,14 @@ impl Context {
         }
     }
 }
+
+/// Once the handshake is done, ask for the peer's known addresses and
+/// download whatever it has beyond our tip
+fn sync_with(peer: &peer::Handle, blockchain: &Blockchain) {
+    peer.write(Message::GetAddr);
+    if let Some(version) = peer.peer_version() {
+        if version.best_height > blockchain.heights[&blockchain.tiphash] {
+            peer.write(Message::GetHeaders(blockchain.block_locator()));
+        }
+    }
+}
@@ -1,55 +1,428 @@
-use crate::transaction::{self, SignedTransaction};
-use crate::block::Block;
-use crate::ledger_state::State;
-use crate::crypto::address;
+use crate::transaction::{Amount, ChainId, SignedTransaction, UtxoInput, COINBASE_IDX};
+use crate::block::{Block, Header};
+use crate::ledger_state::{block_reward, BlockSpends, LedgerState};
+use crate::crypto::hash::{H256, Hashable};
 
 use log::debug;
+use std::fmt;
 
-pub fn is_tx_valid(signed_tx: &SignedTransaction) -> bool {
-   //verify whether the tx is signed properly
-   return transaction::verify(&signed_tx.tx, &signed_tx.signature, &signed_tx.public_key);
-}
-
-pub fn is_blck_valid(block: &Block, parent_state: &State) -> bool {
-    for signed_tx in &block.content.data {
-      debug!("current signed_tx {:?}", signed_tx);
-       if !is_tx_valid(signed_tx){
-          debug!("tx didn't pass signature check!");
-          return false;
-       }
-
-       //Couple of checks
-       //1. Owner match
-       //2. Input/Output total match
-       //3. Double Spend
-       let owner_address = address::address_from_public_key_vec_ref(&signed_tx.public_key);
-       let mut total_input_value = 0;
-       for input in &signed_tx.tx.tx_input {
-           debug!("current tx_input {:?}", input);
-           if !parent_state.state_map.contains_key(&input){
-              debug!("tx is double spend as input is not there in State!");
-              return false;  
-           }
-           let output = &parent_state.state_map[&input];
-           if output.receipient_addr != owner_address {
-              debug!("owner of tx input doesn't match to previous tx output");
-              debug!("input addreess {:?}", owner_address);
-              debug!("output address {:?}", output.receipient_addr);
-              return false;
-           }
-           total_input_value = output.value;
-       }
-       
-       let mut total_output_value = 0;
-       for output in &signed_tx.tx.tx_output {
-            total_output_value += output.value;
-       }
-
-       if total_input_value != total_output_value {
-          debug!("Input sum didn't match to output sum for tx");
-          return false;
-       }
-    }
-
-    true
+/// Outputs of a transaction are indexed below the coinbase input index
+pub const MAX_OUTPUTS: usize = COINBASE_IDX as usize;
+
+/// Why a block, header or transaction was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// a witness signature does not verify against its public key on this network
+    BadSignature,
+    /// input is not in the parent state, either unknown or already spent
+    MissingInput(UtxoInput),
+    /// transaction spends the same output twice
+    DuplicateInput(UtxoInput),
+    /// input spends a coinbase output before `COINBASE_MATURITY` blocks were mined on top of it
+    ImmatureCoinbase(UtxoInput),
+    /// witness of the input does not satisfy the locking script of the output it spends
+    UnsatisfiedScript(UtxoInput),
+    /// transaction needs exactly one witness per input
+    WitnessCount { inputs: usize, witnesses: usize },
+    /// output locking script is malformed or exceeds the script limits
+    BadScript,
+    /// witness exceeds the witness limits
+    BadWitness,
+    /// outputs add up to more than the inputs
+    ValueMismatch { input: Amount, output: Amount },
+    /// input or output values add up beyond `Amount::MAX`
+    ValueOverflow,
+    /// block hash is not below the difficulty
+    BadPow,
+    /// header does not extend the block it should follow
+    UnknownParent(H256),
+    /// transaction creates more than `MAX_OUTPUTS` outputs
+    TooManyOutputs(usize),
+    /// coinbase anywhere but in front of a block
+    UnexpectedCoinbase,
+    /// block does not start with a coinbase
+    MissingCoinbase,
+    /// coinbase does not pay a single output to the payout address of the header
+    BadCoinbase,
+    /// coinbase claims more than the block reward and the fees of the block
+    WrongReward { allowed: Amount, actual: Amount },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::BadSignature => write!(f, "bad transaction signature, or one made for another network"),
+            ValidationError::MissingInput(input) => write!(f, "input {:?} is missing or already spent", input),
+            ValidationError::DuplicateInput(input) => write!(f, "input {:?} is spent twice by the transaction", input),
+            ValidationError::ImmatureCoinbase(input) => write!(f, "input {:?} spends an immature coinbase", input),
+            ValidationError::UnsatisfiedScript(input) => write!(f, "input {:?} does not satisfy its locking script", input),
+            ValidationError::WitnessCount { inputs, witnesses } =>
+                write!(f, "{} witnesses for {} inputs", witnesses, inputs),
+            ValidationError::BadScript => write!(f, "malformed or oversized locking script"),
+            ValidationError::BadWitness => write!(f, "oversized witness"),
+            ValidationError::ValueMismatch { input, output } =>
+                write!(f, "output value {} exceeds input value {}", output, input),
+            ValidationError::ValueOverflow => write!(f, "values add up beyond the largest amount"),
+            ValidationError::BadPow => write!(f, "does not satisfy proof-of-work"),
+            ValidationError::UnknownParent(parent) => write!(f, "unknown parent {}", parent),
+            ValidationError::TooManyOutputs(count) => write!(f, "{} outputs exceed the limit", count),
+            ValidationError::UnexpectedCoinbase => write!(f, "coinbase outside of the first transaction of a block"),
+            ValidationError::MissingCoinbase => write!(f, "block does not start with a coinbase"),
+            ValidationError::BadCoinbase => write!(f, "coinbase does not pay the header payout address"),
+            ValidationError::WrongReward { allowed, actual } =>
+                write!(f, "coinbase claims {} beyond the block reward and fees {}", actual, allowed),
+        }
+    }
+}
+
+/// Checks of a transaction that do not need the chain, signatures are checked for the
+/// network `chain_id`
+pub fn validate_tx(signed_tx: &SignedTransaction, chain_id: &ChainId) -> Result<(), ValidationError> {
+   if signed_tx.tx.tx_output.len() > MAX_OUTPUTS {
+      return Err(ValidationError::TooManyOutputs(signed_tx.tx.tx_output.len()));
+   }
+   if signed_tx.tx.is_coinbase() {
+      return Err(ValidationError::UnexpectedCoinbase);
+   }
+   if signed_tx.tx.output_value().is_none() {
+      return Err(ValidationError::ValueOverflow);
+   }
+   if !signed_tx.tx.tx_output.iter().all(|output| output.script.is_well_formed()) {
+      return Err(ValidationError::BadScript);
+   }
+   if let Some(position) = (1..signed_tx.tx.tx_input.len()).find(|i| signed_tx.tx.tx_input[..*i].contains(&signed_tx.tx.tx_input[*i])) {
+      return Err(ValidationError::DuplicateInput(signed_tx.tx.tx_input[position].clone()));
+   }
+   if signed_tx.witnesses.len() != signed_tx.tx.tx_input.len() {
+      return Err(ValidationError::WitnessCount { inputs: signed_tx.tx.tx_input.len(), witnesses: signed_tx.witnesses.len() });
+   }
+   if !signed_tx.witnesses.iter().all(|witness| witness.is_within_limits()) {
+      return Err(ValidationError::BadWitness);
+   }
+   //verify whether the witnesses are signed properly, whether they unlock the inputs
+   //is up to the locking scripts in the UTXO set
+   if !signed_tx.witnesses.iter().enumerate().all(|(input_index, witness)| witness.verify_signatures(&signed_tx.tx, chain_id, input_index)) {
+      return Err(ValidationError::BadSignature);
+   }
+   Ok(())
+}
+
+pub fn validate_pow(header: &Header) -> Result<(), ValidationError> {
+   if header.hash() > header.difficulty {
+      return Err(ValidationError::BadPow);
+   }
+   Ok(())
+}
+
+/// Checks of the transactions of a block that do not need the chain: a coinbase in
+/// front, followed by properly signed transactions
+pub fn validate_transactions(block: &Block, chain_id: &ChainId) -> Result<(), ValidationError> {
+   match block.content.data.first() {
+      Some(coinbase) if coinbase.tx.is_coinbase() => {}
+      _ => return Err(ValidationError::MissingCoinbase),
+   }
+   block.content.data[1..].iter().try_for_each(|signed_tx| validate_tx(signed_tx, chain_id))
+}
+
+/// The coinbase of a block at `height` pays at most the block reward and the `fees`
+/// of the block, in a single output, to the payout address of the header
+pub fn validate_coinbase(block: &Block, height: u64, fees: Amount) -> Result<(), ValidationError> {
+   let coinbase = match block.content.data.first() {
+      Some(coinbase) if coinbase.tx.is_coinbase() => &coinbase.tx,
+      _ => return Err(ValidationError::MissingCoinbase),
+   };
+   let output = match coinbase.tx_output.as_slice() {
+      [output] if output.script.address() == Some(block.header.payout_addr) => output,
+      _ => return Err(ValidationError::BadCoinbase),
+   };
+   let allowed = block_reward(height).checked_add(fees).ok_or(ValidationError::ValueOverflow)?;
+   if output.value > allowed {
+      return Err(ValidationError::WrongReward { allowed, actual: output.value });
+   }
+   Ok(())
+}
+
+/// Checks of a block on top of the tip of `ledger`. Its transactions are applied in
+/// order, so one may spend the outputs of an earlier one but no output is spent twice.
+pub fn validate_block(block: &Block, ledger: &LedgerState, chain_id: &ChainId) -> Result<(), ValidationError> {
+    validate_pow(&block.header)?;
+    validate_transactions(block, chain_id)?;
+    if block.header.parenthash != ledger.tip {
+       return Err(ValidationError::UnknownParent(block.header.parenthash));
+    }
+    validate_ledger(block, ledger)
+}
+
+/// Checks of a block on top of the tip of `ledger` that need the ledger: the inputs
+/// of its transactions, and a coinbase claiming no more than the reward and their fees
+pub fn validate_ledger(block: &Block, ledger: &LedgerState) -> Result<(), ValidationError> {
+    let fees = validate_spends(block, ledger)?;
+    validate_coinbase(block, ledger.height + 1, fees)
+}
+
+/// Checks the inputs of the transactions of a block on top of the tip of `ledger` and
+/// returns the fees they pay
+pub fn validate_spends(block: &Block, ledger: &LedgerState) -> Result<Amount, ValidationError> {
+    let height = ledger.height + 1;
+    let mut spends = BlockSpends::default();
+    let mut fees = Amount::ZERO;
+    for signed_tx in &block.content.data[1..] {
+       debug!("current signed_tx {:?}", signed_tx);
+       let fee = ledger.spend(signed_tx, height, &mut spends)?;
+       fees = fees.checked_add(fee).ok_or(ValidationError::ValueOverflow)?;
+    }
+    Ok(fees)
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::generate_random_block;
+    use crate::crypto::hash::generate_random_hash;
+    use crate::crypto::address;
+    use crate::crypto::key_pair;
+    use crate::ledger_state::{State, COINBASE_MATURITY, HALVING_INTERVAL};
+    use crate::script::{LockingScript, Witness};
+    use crate::transaction::{self, SigHash, Transaction, UtxoOutput};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// Ledger holding `state` whose tip is the parent of `block`, a block at `height`
+    fn ledger_below(block: &Block, state: &State, height: u64) -> LedgerState {
+        let mut ledger = LedgerState::new(block.header.parenthash);
+        ledger.state = state.clone();
+        ledger.height = height - 1;
+        ledger
+    }
+
+    #[test]
+    fn validation_errors() {
+        let mut block = generate_random_block(&generate_random_hash());
+        let input = block.content.data[1].tx.tx_input[0].clone();
+        assert_eq!(validate_block(&block, &ledger_below(&block, &State::default(), 1), &ChainId::default()), Err(ValidationError::MissingInput(input)));
+
+        //signed for the default network only
+        assert_eq!(validate_tx(&block.content.data[1], &ChainId::default()), Ok(()));
+        assert_eq!(validate_tx(&block.content.data[1], &ChainId::new("devnet", &generate_random_hash())),
+                   Err(ValidationError::BadSignature));
+
+        block.content.data[1].witnesses[0].signatures[0].signature[0] ^= 1;
+        assert_eq!(validate_tx(&block.content.data[1], &ChainId::default()), Err(ValidationError::BadSignature));
+        assert_eq!(validate_tx(&block.content.data[0], &ChainId::default()), Err(ValidationError::UnexpectedCoinbase));
+
+        assert_eq!(validate_coinbase(&block, 1, Amount::ZERO), Ok(()));
+        assert_eq!(validate_coinbase(&block, HALVING_INTERVAL, Amount::ZERO),
+                   Err(ValidationError::WrongReward { allowed: block_reward(HALVING_INTERVAL), actual: block_reward(1) }));
+        //fees make up for the difference, claiming less than allowed is fine
+        let fees = Amount::new(block_reward(1).as_u64() - block_reward(HALVING_INTERVAL).as_u64());
+        assert_eq!(validate_coinbase(&block, HALVING_INTERVAL, fees), Ok(()));
+        assert_eq!(validate_coinbase(&block, 1, Amount::new(1)), Ok(()));
+        block.header.payout_addr = address::generate_random_address();
+        assert_eq!(validate_coinbase(&block, 1, Amount::ZERO), Err(ValidationError::BadCoinbase));
+        block.content.data.remove(0);
+        assert_eq!(validate_transactions(&block, &ChainId::default()), Err(ValidationError::MissingCoinbase));
+
+        block.header.difficulty = H256::default();
+        assert_eq!(validate_block(&block, &ledger_below(&block, &State::default(), 1), &ChainId::default()), Err(ValidationError::BadPow));
+    }
+
+    #[test]
+    fn multi_input_multi_output_values() {
+        let key = key_pair::random();
+        let owner = address::address_from_public_key_ref(key.public_key());
+        let mut state = State::default();
+        let mut fund = |value: u64| {
+            let input = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+            state.state_map.insert(input.clone(), UtxoOutput::to_address(owner, Amount::new(value)));
+            input
+        };
+        let small = fund(10);
+        let large = fund(20);
+        let huge = fund(u64::MAX - 5);
+
+        let spend = |inputs: &[&UtxoInput], outputs: &[u64]| {
+            let tx = Transaction{tx_input: inputs.iter().map(|input| (*input).clone()).collect(),
+                                 tx_output: outputs.iter()
+                                   .map(|value| UtxoOutput::to_address(address::generate_random_address(), Amount::new(*value)))
+                                   .collect()};
+            SignedTransaction::signed_by(tx, &ChainId::default(), &key)
+        };
+        let mut block = generate_random_block(&generate_random_hash());
+        let mut validate = |signed_tx: SignedTransaction| {
+            block.content.data[1] = signed_tx;
+            validate_block(&block, &ledger_below(&block, &state, 1), &ChainId::default())
+        };
+
+        //every input counts, whatever the order
+        assert_eq!(validate(spend(&[&small, &large], &[15, 15])), Ok(()));
+        assert_eq!(validate(spend(&[&large, &small], &[12, 13, 0])), Ok(()));
+        assert_eq!(validate(spend(&[&large, &small], &[16, 15])),
+                   Err(ValidationError::ValueMismatch{input: Amount::new(30), output: Amount::new(31)}));
+        //values right below the limit are fine, past it they are rejected
+        assert_eq!(validate(spend(&[&huge], &[u64::MAX - 10, 5])), Ok(()));
+        assert_eq!(validate(spend(&[&huge, &small], &[1])), Err(ValidationError::ValueOverflow));
+        assert_eq!(validate(spend(&[&small], &[u64::MAX, 1])), Err(ValidationError::ValueOverflow));
+        assert_eq!(validate_tx(&spend(&[&small], &[u64::MAX, 1]), &ChainId::default()), Err(ValidationError::ValueOverflow));
+
+        //the ledger agrees on the fees
+        let mut ledger = LedgerState::new(generate_random_hash());
+        ledger.state = state.clone();
+        assert_eq!(ledger.pending_fee(&spend(&[&large, &small], &[12, 13, 0])), Amount::new(5));
+        assert_eq!(ledger.fee(&spend(&[&large, &small], &[16, 15]), 1), None);
+        assert_eq!(ledger.fee(&spend(&[&huge, &small], &[1]), 1), None);
+    }
+
+    #[test]
+    fn scripted_outputs() {
+        let (buyer, seller, arbiter) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let addr = |key: &Ed25519KeyPair| address::address_from_public_key_ref(key.public_key());
+        let mut state = State::default();
+
+        //an escrow confirmed at height 10 that the seller can also take alone from height 20 on
+        let escrow = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        let script = LockingScript::Any(vec![
+            LockingScript::Multisig{required: 2, addrs: vec![addr(&buyer), addr(&seller), addr(&arbiter)]},
+            LockingScript::All(vec![LockingScript::RelativeTimelock(10), LockingScript::PayToPubkeyHash(addr(&seller))]),
+        ]);
+        state.state_map.insert(escrow.clone(), UtxoOutput{script, value: Amount::new(10)});
+        state.confirmed_heights.insert(escrow.tx_hash, 10);
+
+        let tx = Transaction{tx_input: vec![escrow.clone()],
+                             tx_output: vec![UtxoOutput::to_address(addr(&seller), Amount::new(10))]};
+        let with_keys = |keys: &[&Ed25519KeyPair]| SignedTransaction{witnesses: vec![Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, keys).unwrap()], tx: tx.clone()};
+        let mut block = generate_random_block(&generate_random_hash());
+        let mut validate = |signed_tx: SignedTransaction, height: u64| {
+            block.content.data[1] = signed_tx;
+            validate_block(&block, &ledger_below(&block, &state, height), &ChainId::default())
+        };
+
+        assert_eq!(validate(with_keys(&[&arbiter, &seller]), 11), Ok(()));
+        assert_eq!(validate(with_keys(&[&seller]), 19), Err(ValidationError::UnsatisfiedScript(escrow.clone())));
+        assert_eq!(validate(with_keys(&[&seller]), 20), Ok(()));
+        assert_eq!(validate_tx(&SignedTransaction{witnesses: vec![], tx: tx.clone()}, &ChainId::default()),
+                   Err(ValidationError::WitnessCount{inputs: 1, witnesses: 0}));
+
+        let mut oversized = with_keys(&[&seller]);
+        oversized.witnesses[0].preimages.push(vec![0; crate::script::MAX_PREIMAGE_SIZE + 1]);
+        assert_eq!(validate_tx(&oversized, &ChainId::default()), Err(ValidationError::BadWitness));
+        let mut malformed = with_keys(&[&seller]);
+        malformed.tx.tx_output[0].script = LockingScript::Multisig{required: 2, addrs: vec![addr(&seller)]};
+        assert_eq!(validate_tx(&malformed, &ChainId::default()), Err(ValidationError::BadScript));
+
+        //the ledger applies the same rules and remembers when outputs were confirmed
+        let mut ledger = LedgerState::new(generate_random_hash());
+        ledger.state = state.clone();
+        ledger.height = 18;
+        assert_eq!(ledger.fee(&with_keys(&[&seller]), ledger.height + 1), None);
+        let mut block = generate_random_block(&ledger.tip);
+        block.content.data[1] = with_keys(&[&buyer, &seller]);
+        ledger.connect_block(&block);
+        assert_eq!(ledger.state.confirmed_heights.get(&tx.hash()), Some(&19));
+        assert!(!ledger.state.state_map.contains_key(&escrow));
+        ledger.disconnect_block(&block);
+        assert_eq!(ledger.state.confirmed_heights.get(&tx.hash()), None);
+        assert!(ledger.state.state_map.contains_key(&escrow));
+    }
+
+    #[test]
+    fn inputs_of_different_owners() {
+        let (alice, bob) = (key_pair::random(), key_pair::random());
+        let addr = |key: &Ed25519KeyPair| address::address_from_public_key_ref(key.public_key());
+        let mut state = State::default();
+        let alice_input = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        let bob_input = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        state.state_map.insert(alice_input.clone(), UtxoOutput::to_address(addr(&alice), Amount::new(10)));
+        state.state_map.insert(bob_input.clone(), UtxoOutput::to_address(addr(&bob), Amount::new(6)));
+
+        //alice commits to her input and the payment, bob then adds his input and change
+        let mut tx = Transaction{tx_input: vec![alice_input.clone()],
+                                 tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(10))]};
+        let alice_witness = Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::SINGLE_ANYONE_CAN_PAY, &[&alice]).unwrap();
+        tx.tx_input.push(bob_input);
+        tx.tx_output.push(UtxoOutput::to_address(addr(&bob), Amount::new(5)));
+        let bob_witness = Witness::signed_by(&tx, &ChainId::default(), 1, SigHash::ALL, &[&bob]).unwrap();
+
+        let mut block = generate_random_block(&generate_random_hash());
+        let mut validate = |signed_tx: SignedTransaction| {
+            validate_tx(&signed_tx, &ChainId::default())?;
+            block.content.data[1] = signed_tx;
+            validate_block(&block, &ledger_below(&block, &state, 1), &ChainId::default())
+        };
+        let joint = SignedTransaction{tx: tx.clone(), witnesses: vec![alice_witness.clone(), bob_witness.clone()]};
+        assert_eq!(validate(joint), Ok(()));
+        let swapped = SignedTransaction{tx: tx.clone(), witnesses: vec![bob_witness.clone(), alice_witness]};
+        assert_eq!(validate(swapped), Err(ValidationError::BadSignature));
+        //a valid signature still has to come from the owner of the spent output
+        let stolen = SignedTransaction{witnesses: vec![Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, &[&bob]).unwrap(), bob_witness],
+                                       tx};
+        assert_eq!(validate(stolen), Err(ValidationError::UnsatisfiedScript(alice_input)));
+    }
+
+    #[test]
+    fn spends_within_a_block() {
+        let key = key_pair::random();
+        let owner = address::address_from_public_key_ref(key.public_key());
+        let mut state = State::default();
+        let funds = UtxoInput{tx_hash: generate_random_hash(), idx: 0};
+        state.state_map.insert(funds.clone(), UtxoOutput::to_address(owner, Amount::new(10)));
+        let spend = |inputs: Vec<UtxoInput>, value: u64| {
+            let tx = Transaction{tx_input: inputs, tx_output: vec![UtxoOutput::to_address(owner, Amount::new(value))]};
+            SignedTransaction::signed_by(tx, &ChainId::default(), &key)
+        };
+        let mut block = generate_random_block(&generate_random_hash());
+        let ledger = ledger_below(&block, &state, 1);
+        let mut validate = |txs: Vec<SignedTransaction>| {
+            block.content.data.truncate(1);
+            block.content.data.extend(txs);
+            validate_block(&block, &ledger, &ChainId::default())
+        };
+
+        //an input listed twice is not worth twice its value
+        let duplicated = spend(vec![funds.clone(), funds.clone()], 20);
+        assert_eq!(validate_tx(&duplicated, &ChainId::default()), Err(ValidationError::DuplicateInput(funds.clone())));
+        assert_eq!(validate(vec![duplicated.clone()]), Err(ValidationError::DuplicateInput(funds.clone())));
+        assert_eq!(ledger.fee(&duplicated, 1), None);
+
+        //a transaction may spend the outputs of an earlier one, but no output is spent twice
+        let first = spend(vec![funds.clone()], 10);
+        let chained = spend(vec![UtxoInput{tx_hash: first.tx.hash(), idx: 0}], 8);
+        assert_eq!(validate(vec![first.clone(), chained.clone()]), Ok(()));
+        assert_eq!(validate(vec![chained.clone(), first.clone()]),
+                   Err(ValidationError::MissingInput(UtxoInput{tx_hash: first.tx.hash(), idx: 0})));
+        assert_eq!(validate(vec![first.clone(), spend(vec![funds.clone()], 9)]), Err(ValidationError::MissingInput(funds)));
+        assert_eq!(validate(vec![first.clone(), chained.clone(), chained]),
+                   Err(ValidationError::MissingInput(UtxoInput{tx_hash: first.tx.hash(), idx: 0})));
+    }
+
+    #[test]
+    fn immature_coinbase_spend() {
+        let key = key_pair::random();
+        let owner = address::address_from_public_key_ref(key.public_key());
+        let coinbase = transaction::coinbase(owner, block_reward(1));
+        let reward = UtxoInput{tx_hash: coinbase.tx.hash(), idx: 0};
+        let mut state = State::default();
+        state.state_map.insert(reward.clone(), coinbase.tx.tx_output[0].clone());
+        let tx = Transaction{tx_input: vec![reward.clone()],
+                             tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), block_reward(1))]};
+
+        let mut block = generate_random_block(&generate_random_hash());
+        block.content.data[1] = SignedTransaction::signed_by(tx, &ChainId::default(), &key);
+        let ledger_at = |height: u64| {
+            let mut ledger = ledger_below(&block, &state, height);
+            ledger.coinbase_heights.insert(reward.tx_hash, 1);
+            ledger
+        };
+        assert_eq!(validate_block(&block, &ledger_at(COINBASE_MATURITY), &ChainId::default()),
+                   Err(ValidationError::ImmatureCoinbase(reward.clone())));
+        assert_eq!(validate_block(&block, &ledger_at(1 + COINBASE_MATURITY), &ChainId::default()), Ok(()));
+    }
+
+    #[test]
+    fn block_reward_rounds_down() {
+        assert_eq!(block_reward(HALVING_INTERVAL - 1), Amount::new(5000));
+        assert_eq!(block_reward(4 * HALVING_INTERVAL), Amount::new(312));
+        assert_eq!(block_reward(12 * HALVING_INTERVAL), Amount::new(1));
+        assert_eq!(block_reward(13 * HALVING_INTERVAL), Amount::ZERO);
+        assert_eq!(block_reward(64 * HALVING_INTERVAL), Amount::ZERO);
+        assert_eq!(block_reward(u64::MAX), Amount::ZERO);
+    }
 }
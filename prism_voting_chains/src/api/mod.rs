@@ -3,9 +3,11 @@ use crate::miner::Handle as MinerHandle;
 use crate::tx_generator::Handle as TxGenHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::ledger_manager::Rollback;
 
-use log::debug;
-use std::collections::HashMap;
+use crossbeam::channel::Receiver;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
 use tiny_http::Response;
@@ -13,11 +15,16 @@ use tiny_http::Server as HTTPServer;
 use url::Url;
 use std::time;
 use log::info;
+
+// number of ledger rollbacks kept for `/ledger/rollbacks`
+const ROLLBACK_HISTORY: usize = 100;
+
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
     txgen: TxGenHandle,
+    rollbacks: Arc<Mutex<VecDeque<Rollback>>>,
 }
 
 #[derive(Serialize)]
@@ -39,25 +46,48 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
         txgen: &TxGenHandle,
+        rollback_events: Receiver<Rollback>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
-            txgen: txgen.clone()
+            txgen: txgen.clone(),
+            rollbacks: Arc::new(Mutex::new(VecDeque::new())),
         };
+        // remember the most recent ledger rollbacks
+        let rollbacks = Arc::clone(&server.rollbacks);
+        thread::spawn(move || {
+            for rollback in rollback_events {
+                let mut rollbacks = rollbacks.lock().unwrap();
+                if rollbacks.len() == ROLLBACK_HISTORY {
+                    rollbacks.pop_front();
+                }
+                rollbacks.push_back(rollback);
+            }
+        });
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let txgen = server.txgen.clone(); 
                 let network = server.network.clone();
+                let rollbacks = Arc::clone(&server.rollbacks);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -114,13 +144,20 @@ impl Server {
                             txgen.start(lambda, lambda%3);
                             let interval = time::Duration::from_micros(1000000);
                             thread::sleep(interval);
-                            miner.start(lambda, lambda%3);
+                            miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/banlist" => {
+                            respond_json!(req, network.ban_list());
+                        }
+                        "/ledger/rollbacks" => {
+                            let rollbacks: Vec<Rollback> = rollbacks.lock().unwrap().iter().cloned().collect();
+                            respond_json!(req, rollbacks);
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -3,26 +3,32 @@
 
 use rand::Rng;
 use serde::{Serialize, Deserialize};
+use bigint::uint::U256;
 use crate::crypto::hash::{H256, Hashable};
 use crate::transaction::{self, SignedTransaction};
 use crate::crypto::merkle::MerkleTree;
+use crate::crypto::address::H160;
 
 extern crate chrono;
 use chrono::prelude::*;
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Header {
+    // proposer block the superblock was mined on, its chain decides the difficulty
+    pub parent: H256,
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root:H256,
-    pub miner_id:i32,
+    // address the coinbase of a transaction block mined with this header has to pay
+    pub payout_addr: H160,
 }
 #[derive(Serialize, Deserialize, Debug,Clone)]
 // content depends on type of block, represented by enum
 pub enum Content {
     Proposer(ProposerContent),
     Voter(VoterContent),
+    Transaction(TransactionContent),
 }
 
 impl Hashable for Content {
@@ -30,6 +36,7 @@ impl Hashable for Content {
         match self {
             Content::Proposer(c) => c.hash(),
             Content::Voter(c) => c.hash(),
+            Content::Transaction(c) => c.hash(),
         }
     }
 }
@@ -44,22 +51,22 @@ pub struct Block {
 
 impl Block {
     pub fn new(
-        //parent: H256,
+        parent: H256,
         ts: u128,
         n: u32,
         content_merkle_root: H256,
         sortition_proof: Vec<H256>,
         content: Content,
-        miner_identity:i32,
+        payout_addr: H160,
         diff: H256,
     ) -> Self {
         let header = Header{
-           // parenthash:parent,
+            parent,
             nonce:n,
             difficulty:diff,
             timestamp:ts,
             merkle_root:content_merkle_root,
-            miner_id:miner_identity,
+            payout_addr,
         };
         Self {
             header,
@@ -72,18 +79,19 @@ impl Block {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ProposerContent {
     pub parent_hash:H256,
-    pub transactions: Vec<SignedTransaction>,
+    // transaction blocks whose transactions the proposer orders into the ledger
+    pub transaction_refs: Vec<H256>,
     pub proposer_refs: Vec<H256>,
 }
 
 impl Hashable for ProposerContent {
     fn hash(&self) -> H256 {
-        let txns_merkle_tree = MerkleTree::new(&self.transactions);
+        let tx_refs_merkle_tree = MerkleTree::new(&self.transaction_refs);
         let prop_refs_merkle_tree = MerkleTree::new(&self.proposer_refs);
         let mut byte_array = [0u8; 96];
         byte_array[..32].copy_from_slice(self.parent_hash.as_ref());
         byte_array[32..64].copy_from_slice(prop_refs_merkle_tree.root().as_ref());
-        byte_array[64..96].copy_from_slice(txns_merkle_tree.root().as_ref());
+        byte_array[64..96].copy_from_slice(tx_refs_merkle_tree.root().as_ref());
         ring::digest::digest(&ring::digest::SHA256, &byte_array).into()
     }
 }
@@ -105,12 +113,37 @@ impl Hashable for  VoterContent {
     }
 }
 
+// Transactions are carried by their own blocks, which are not part of any chain and
+// only reach the ledger once a proposer references them
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TransactionContent {
+    pub transactions: Vec<SignedTransaction>,
+}
+
+impl Hashable for TransactionContent {
+    fn hash(&self) -> H256 {
+        MerkleTree::new(&self.transactions).root()
+    }
+}
+
 impl Hashable for Block {
     fn hash(&self) -> H256 {
         self.header.hash()
     }
 }
 
+impl Header {
+    /// Expected number of hashes needed to meet the difficulty, 2^256 / (difficulty + 1)
+    pub fn work(&self) -> U256 {
+        let target = U256::from_big_endian(self.difficulty.as_ref());
+        if target == U256::max_value() {
+            return U256::one();
+        }
+        // same as 2^256 / (target + 1) without leaving 256 bits
+        (!target / (target + U256::one())) + U256::one()
+    }
+}
+
 impl Hashable for Header {
     fn hash(&self) -> H256 {
         let encodedhead: Vec<u8> = bincode::serialize(&self).unwrap();
@@ -156,13 +189,13 @@ pub fn genesis_proposer() -> Block {
 let zero_vec : [u8; 32] = [0; 32];
    let content = ProposerContent {
       parent_hash:zero_vec.into(),
-      transactions:vec![],
+      transaction_refs:vec![],
       proposer_refs:vec![],
    };
    
    let raw: [u8; 32] = [255; 32];
    let default_diff:H256= raw.into();
-   Block::new(0,0,zero_vec.into(),vec![],Content::Proposer(content),0,default_diff,)
+   Block::new(zero_vec.into(),0,0,zero_vec.into(),vec![],Content::Proposer(content),H160::default(),default_diff,)
 }
 
 pub fn genesis_voter(chain_number:u32) -> Block {
@@ -175,6 +208,6 @@ pub fn genesis_voter(chain_number:u32) -> Block {
     let raw: [u8; 32] = [255; 32];
     let default_diff:H256= raw.into();
 
-    Block::new(0,0,zero_vec.into(),vec![],Content::Voter(content),0,default_diff,)
+    Block::new(zero_vec.into(),0,0,zero_vec.into(),vec![],Content::Voter(content),H160::default(),default_diff,)
 }
 
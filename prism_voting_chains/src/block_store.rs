@@ -0,0 +1,155 @@
+use crate::block::Block;
+use crate::blockchain::Metablock;
+
+use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+// One entry of the block store. Records are replayed in the order they were
+// written, so a block always comes after the blocks it references.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StoreRecord {
+    // block attached to the proposer chain or one of the voter chains
+    Chain(Metablock),
+    // transaction block, kept outside of the chains until a proposer references it
+    Transaction(Block),
+    // block received before its references, still waiting in the orphan buffer
+    Orphan(Block),
+}
+
+/// Persistence backend used by `Blockchain`.
+pub trait BlockStore {
+    /// Append a record to the store.
+    fn append(&mut self, record: &StoreRecord) -> io::Result<()>;
+
+    /// Read back every record, in the order they were appended.
+    fn load(&mut self) -> io::Result<Vec<StoreRecord>>;
+}
+
+// Keeps records in memory only, used when no data directory is given
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    records: Vec<StoreRecord>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        MemoryBlockStore {
+            records: Vec::new(),
+        }
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn append(&mut self, record: &StoreRecord) -> io::Result<()> {
+        self.records.push(record.clone());
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<Vec<StoreRecord>> {
+        Ok(self.records.clone())
+    }
+}
+
+// Append-only file of records. Each record is a 4 byte big endian length
+// followed by the bincode encoded `StoreRecord`, same framing as the p2p layer.
+pub struct FileBlockStore {
+    path: PathBuf,
+    file: File,
+}
+
+impl FileBlockStore {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        info!("Opened block store at {}", path.display());
+        Ok(FileBlockStore {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn append(&mut self, record: &StoreRecord) -> io::Result<()> {
+        let encoded = bincode::serialize(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buffer: Vec<u8> = Vec::with_capacity(encoded.len() + 4);
+        buffer.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&encoded);
+        // single write so that a crash leaves at most one torn record at the end
+        self.file.write_all(&buffer)?;
+        self.file.flush()
+    }
+
+    fn load(&mut self) -> io::Result<Vec<StoreRecord>> {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+
+        let mut records: Vec<StoreRecord> = Vec::new();
+        let mut offset: usize = 0;
+        while offset + 4 <= bytes.len() {
+            let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + length > bytes.len() {
+                break;
+            }
+            match bincode::deserialize(&bytes[offset + 4..offset + 4 + length]) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!("Corrupted record in block store {}: {}", self.path.display(), e);
+                    break;
+                }
+            }
+            offset += 4 + length;
+        }
+
+        // drop a torn tail left behind by a crash, so new records stay readable
+        if offset < bytes.len() {
+            warn!("Truncating {} trailing bytes of block store {}", bytes.len() - offset, self.path.display());
+            self.file.set_len(offset as u64)?;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::{genesis_proposer, genesis_voter};
+
+    #[test]
+    fn file_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("block_store_roundtrip_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = FileBlockStore::open(&path).unwrap();
+        store.append(&StoreRecord::Chain(Metablock { block: genesis_proposer(), level: 1 })).unwrap();
+        store.append(&StoreRecord::Orphan(genesis_voter(3))).unwrap();
+        drop(store);
+
+        // simulate a crash in the middle of writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 1, 0, 42]).unwrap();
+        drop(file);
+
+        let mut store = FileBlockStore::open(&path).unwrap();
+        let records = store.load().unwrap();
+        assert_eq!(records.len(), 2);
+        match &records[0] {
+            StoreRecord::Chain(meta) => assert_eq!(meta.level, 1),
+            _ => panic!("expected a chain record"),
+        }
+
+        store.append(&StoreRecord::Orphan(genesis_voter(4))).unwrap();
+        assert_eq!(store.load().unwrap().len(), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -3,15 +3,20 @@ use crate::crypto::hash::{H256,Hashable};
 use log::debug;
 use log::info;
 use std::collections::HashMap;
-use std::collections::VecDeque;
 use crate::mempool::{TransactionMempool};
+use bigint::uint::U256;
+use crate::block_store::{BlockStore, MemoryBlockStore, StoreRecord};
+use crate::miner::{get_difficulty, retarget_difficulty, DIFFICULTY_EPOCH};
+use crate::validation::{check_coinbase, check_difficulty, check_timestamp, check_votes, now_micros, MAX_VOTES, ValidationError, MEDIAN_TIME_SPAN};
 use std::sync::{Arc, Mutex};
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Serialize, Deserialize};
+use log::error;
+use log::warn;
 
 // use crate::utils::{*};
 
 extern crate chrono;
-use chrono::prelude::*;
-use std::cmp;
 
 
 // Implement remove by element from a Vec
@@ -30,9 +35,21 @@ pub fn remove_by_element<T>(list: &mut Vec<T>, element: T) where T: PartialEq {
 pub enum InsertStatus {
     Orphan,
     Valid,
+    // the block breaks a consensus rule
+    Invalid(ValidationError),
 }
 
-#[derive(Debug,Clone)]
+// Published by `insert` for every block that joins the proposer chain or a voter chain
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockchainEvent {
+    NewProposer { hash: H256, level: u32 },
+    // `levels` are the proposer levels whose tally changed: the levels the block votes
+    // for if it extends the main chain, or those voted for by every block leaving and
+    // joining the main chain if it reorganizes the voter chain. Empty for a side branch.
+    NewVoter { hash: H256, chain_num: u32, levels: Vec<u32> },
+}
+
+#[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Metablock {
     pub block: Block,
     pub level: u32,
@@ -47,8 +64,17 @@ pub struct Blockchain {
     pub voter_tips: Vec<H256>,
     pub voter_depths: Vec<u32>,
 
+    // total work of the chain ending at each proposer or voter block, the tips
+    // are the heaviest chains
+    pub chain_work: HashMap<H256, U256>,
+
     // M: list of unreferenced proposer blocks
     pub unref_proposers: Vec<H256>,
+    // transaction blocks, they are not part of any chain and only enter the
+    // ledger through the proposers referencing them
+    pub tx_blocks: HashMap<H256, Block>,
+    // M: list of transaction blocks not yet referenced by a proposer
+    pub unref_tx_blocks: Vec<H256>,
     // M: Hash of first proposer block seen corresponding to each level
     pub level2proposer: HashMap<u32, H256>,
     // LM: level -> proposer hash mapping
@@ -61,10 +87,12 @@ pub struct Blockchain {
     //that proposer
     pub proposer2voterinfo: HashMap<H256, Vec<(u32, H256)>>,
 
-    // Last voted level corresponding to each voter chain
-    // IMP TODO: need changes to handle forking in the voter chain
+    // Last voted level corresponding to the tip of each voter chain
     pub num_voter_chains: u32,
     pub chain2level: HashMap<u32, u32>,
+    // Last proposer level voted for by the voter chain ending at each voter block,
+    // a child has to continue voting right after it
+    pub voter2level: HashMap<H256, u32>,
 
     // orphan buffer stores a mapping between missing reference and block
     // use Vec<Block> as many blocks could wait on a single reference.
@@ -73,14 +101,30 @@ pub struct Blockchain {
     // This is the store of all blocks ever received / mined.
     pub blocksdb: HashMap<H256, Block>,
 
+    // hash of the genesis proposer, identifies the network
+    pub genesis_hash: H256,
+
     // reference to mempool
     mempool:Arc<Mutex<TransactionMempool>>, 
 
     new_proposer: bool,
+
+    // persistent copy of every block, replayed on startup
+    store: Box<dyn BlockStore + Send>,
+    // set while replaying the store so that records are not written twice
+    restoring: bool,
+
+    // receivers of new block notifications
+    subscribers: Vec<Sender<BlockchainEvent>>,
 }
 
 impl Blockchain {
     pub fn new(num_voter_chains: u32, mempool: &Arc<Mutex<TransactionMempool>>) -> Self {
+        Self::with_store(num_voter_chains, mempool, Box::new(MemoryBlockStore::new()))
+    }
+
+    // Create a blockchain backed by `store`, replaying every block it already holds
+    pub fn with_store(num_voter_chains: u32, mempool: &Arc<Mutex<TransactionMempool>>, store: Box<dyn BlockStore + Send>) -> Self {
         // genesis for proposer and voter chains
         let mut blocksdb = HashMap::new();
 
@@ -93,6 +137,8 @@ impl Blockchain {
             block: proposer,
             level: 1,
         };
+        let mut chain_work = HashMap::new();
+        chain_work.insert(proposer_hash, metablock.block.header.work());
         proposer_chain.insert(proposer_hash, metablock);
         let proposer_tip = proposer_hash;
 
@@ -100,6 +146,7 @@ impl Blockchain {
         let mut voter_tips = Vec::new();
         let mut voter_depths = Vec::new();
         let mut chain2level = HashMap::new();
+        let mut voter2level = HashMap::new();
         for chain_num in 1..(num_voter_chains + 1) {
             let mut tmp_chain = HashMap::new();
             let voter = genesis_voter(chain_num);
@@ -110,12 +157,14 @@ impl Blockchain {
                 block: voter,
                 level: 1,
             };
+            chain_work.insert(voter_hash, metablock.block.header.work());
             tmp_chain.insert(voter_hash, metablock);
             voter_chains.push(tmp_chain);
             voter_tips.push(voter_hash);
             voter_depths.push(1);
 
             chain2level.insert(chain_num, 0);
+            voter2level.insert(voter_hash, 0);
         } 
 
         let mut unref_proposers = Vec::new();
@@ -133,7 +182,7 @@ impl Blockchain {
         let mut proposer2voterinfo = HashMap::new();
         proposer2voterinfo.insert(proposer_hash, Vec::new());
 
-        Blockchain {
+        let mut blockchain = Blockchain {
             proposer_chain: proposer_chain,
             proposer_tip: proposer_hash,
             proposer_depth: 1,
@@ -141,8 +190,11 @@ impl Blockchain {
             voter_chains: voter_chains,
             voter_tips: voter_tips,
             voter_depths: voter_depths,
+            chain_work,
 
             unref_proposers: unref_proposers,
+            tx_blocks: HashMap::new(),
+            unref_tx_blocks: Vec::new(),
             level2proposer: level2proposer,
             level2allproposers: level2allproposers,
 
@@ -150,15 +202,86 @@ impl Blockchain {
             proposer2voterinfo: proposer2voterinfo,
             num_voter_chains: num_voter_chains,
             chain2level: chain2level,
+            voter2level: voter2level,
 
             orphan_buffer: HashMap::new(),
             blocksdb: blocksdb,
+            genesis_hash: proposer_hash,
 
             mempool: Arc::clone(mempool),
             new_proposer: true,
+
+            store,
+            restoring: false,
+            subscribers: Vec::new(),
+        };
+        blockchain.restore();
+        blockchain
+    }
+
+    // Rebuild the in-memory chains from the block store. Blocks go through `insert`
+    // again, so levels, tips, depths and vote bookkeeping are recomputed exactly as
+    // they were when the blocks first arrived.
+    fn restore(&mut self) {
+        let records = match self.store.load() {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Unable to read block store, starting from genesis: {}", e);
+                return;
+            }
+        };
+        if records.is_empty() {
+            return;
+        }
+
+        self.restoring = true;
+        for record in &records {
+            match record {
+                StoreRecord::Chain(metablock) => {
+                    let block_hash = metablock.block.hash();
+                    self.insert(&metablock.block);
+                    match self.get_level(&block_hash) {
+                        Some(level) if level == metablock.level => {}
+                        level => error!("Stored block {:?} at level {} restored at {:?}", block_hash, metablock.level, level),
+                    }
+                }
+                StoreRecord::Transaction(block) | StoreRecord::Orphan(block) => {
+                    self.insert(block);
+                }
+            }
+        }
+        self.restoring = false;
+        info!("Restored {} blocks from block store, proposer depth {}", records.len(), self.proposer_depth);
+    }
+
+    // Receive a `BlockchainEvent` for every block inserted from now on
+    pub fn subscribe(&mut self) -> Receiver<BlockchainEvent> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn publish(&mut self, event: BlockchainEvent) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn persist(&mut self, record: StoreRecord) {
+        if self.restoring {
+            return;
+        }
+        if let Err(e) = self.store.append(&record) {
+            error!("Unable to persist block: {}", e);
         }
     }
 
+    // Level of a block in the proposer chain or its voter chain
+    pub fn get_level(&self, block_hash: &H256) -> Option<u32> {
+        if let Some(metablock) = self.proposer_chain.get(block_hash) {
+            return Some(metablock.level);
+        }
+        self.voter_chains.iter().find_map(|chain| chain.get(block_hash)).map(|metablock| metablock.level)
+    }
+
     pub fn is_orphan (&mut self, block: &Block) -> bool {
         // If there are missing references, it will add 
         // (first missing ref -> block) entry to orphan buffer map
@@ -167,14 +290,22 @@ impl Blockchain {
                 if (!self.proposer_chain.contains_key(&content.parent_hash)) {
                     // parent proposer not found, add to orphan buffer
                     self.orphan_buffer.entry(content.parent_hash).or_insert(Vec::new()).push(block.clone());
-                    println!("Adding proposer block with hash {:?} to buffer", block.hash());
+                    debug!("Adding proposer block with hash {:?} to buffer", block.hash());
                     return true;
                 }
 
                 for ref_proposer in content.proposer_refs.clone() {
                     if (!self.proposer_chain.contains_key(&ref_proposer)) {
                         self.orphan_buffer.entry(ref_proposer).or_insert(Vec::new()).push(block.clone());
-                        println!("Adding proposer block with hash {:?} to buffer", block.hash());
+                        debug!("Adding proposer block with hash {:?} to buffer", block.hash());
+                        return true;
+                    }
+                }
+
+                for ref_tx_block in &content.transaction_refs {
+                    if !self.tx_blocks.contains_key(ref_tx_block) {
+                        self.orphan_buffer.entry(*ref_tx_block).or_default().push(block.clone());
+                        debug!("Adding proposer block with hash {:?} to buffer", block.hash());
                         return true;
                     }
                 }
@@ -183,33 +314,69 @@ impl Blockchain {
             Content::Voter(content) => {
                 let chain_num = content.chain_num;
 
+                if !self.proposer_chain.contains_key(&block.header.parent) {
+                    // proposer deciding the difficulty not found, add to orphan buffer
+                    self.orphan_buffer.entry(block.header.parent).or_default().push(block.clone());
+                    debug!("Adding voter block with hash {:?} to buffer", block.hash());
+                    return true;
+                }
+
                 if (!self.voter_chains[(chain_num-1) as usize].contains_key(&content.parent_hash)) {
                     // parent proposer not found, add to orphan buffer
                     self.orphan_buffer.entry(content.parent_hash).or_insert(Vec::new()).push(block.clone());
-                    println!("Adding voter block with hash {:?} to buffer", block.hash());
+                    debug!("Adding voter block with hash {:?} to buffer", block.hash());
                     return true;
                 }
 
                 for vote in content.votes.clone() {
                     if (!self.proposer_chain.contains_key(&vote)) {
                         self.orphan_buffer.entry(vote).or_insert(Vec::new()).push(block.clone());
-                        println!("Adding voter block with hash {:?} to buffer", block.hash());
+                        debug!("Adding voter block with hash {:?} to buffer", block.hash());
                         return true;
                     }
                 }
                 return false;
             }
+            Content::Transaction(_) => {
+                if !self.proposer_chain.contains_key(&block.header.parent) {
+                    // proposer deciding the difficulty not found, add to orphan buffer
+                    self.orphan_buffer.entry(block.header.parent).or_default().push(block.clone());
+                    debug!("Adding transaction block with hash {:?} to buffer", block.hash());
+                    return true;
+                }
+                false
+            }
         }
     }
 
     pub fn insert(&mut self, block: &Block) -> InsertStatus {
         let block_hash = block.hash();
-        self.blocksdb.insert(block_hash, block.clone());
+        if self.get_level(&block_hash).is_some() || self.tx_blocks.contains_key(&block_hash) {
+            // already part of a chain, e.g. replayed from the store
+            return InsertStatus::Valid;
+        }
+        let first_seen = self.blocksdb.insert(block_hash, block.clone()).is_none();
 
         if self.is_orphan(block) {
+            if first_seen {
+                self.persist(StoreRecord::Orphan(block.clone()));
+            }
             return InsertStatus::Orphan;
         }
 
+        let consensus_checks = check_difficulty(block, self)
+            .and_then(|_| check_timestamp(block, self, now_micros()))
+            .and_then(|_| match &block.content {
+                Content::Voter(content) => check_votes(content, self),
+                Content::Transaction(content) => check_coinbase(block, content),
+                Content::Proposer(_) => Ok(()),
+            });
+        if let Err(error) = consensus_checks {
+            warn!("Block {:?} is invalid: {}", block_hash, error);
+            self.blocksdb.remove(&block_hash);
+            return InsertStatus::Invalid(error);
+        }
+
         // All references inside the block are guaranteed to be present
         match &block.content {
             Content::Proposer(content) => {
@@ -220,6 +387,9 @@ impl Blockchain {
                 for ref_proposer in content.proposer_refs.clone() {
                     remove_by_element(&mut self.unref_proposers, ref_proposer);
                 }
+                for ref_tx_block in &content.transaction_refs {
+                    remove_by_element(&mut self.unref_tx_blocks, *ref_tx_block);
+                }
 
                 // Add to `proposer_chain` and update tip
                 let parent_meta = &self.proposer_chain[&content.parent_hash];
@@ -230,24 +400,17 @@ impl Blockchain {
                     level: block_level,
                 };
                 self.proposer_chain.insert(block_hash, metablock.clone());
+                self.persist(StoreRecord::Chain(metablock.clone()));
                 self.new_proposer = true;
                 println!("Added proposer {:?} at level {}", block_hash, block_level);
 
-                if metablock.level > self.proposer_depth {
+                let work = self.chain_work[&content.parent_hash] + block.header.work();
+                self.chain_work.insert(block_hash, work);
+                if work > self.chain_work[&self.proposer_tip] {
                     self.proposer_depth = metablock.level;
                     self.proposer_tip = block_hash;
                 }
 
-                // remove transactions from the mempool
-                // println!("trying to acquire mempool lock");
-                let mut locked_mempool = self.mempool.lock().unwrap();
-                // println!("acquired mempool lock");
-
-                for tx in &content.transactions {
-                    locked_mempool.delete(&tx.hash());
-                }
-                drop(locked_mempool);
-
                 // Add selfhash to unref_proposers
                 self.unref_proposers.push(block_hash);
 
@@ -257,45 +420,48 @@ impl Blockchain {
                 }
                 // Add to `level2allproposers`
                 self.level2allproposers.entry(block_level).or_insert(Vec::new()).push(block_hash);
+
+                self.publish(BlockchainEvent::NewProposer { hash: block_hash, level: block_level });
             }
 
             Content::Voter(content) => {
                 let chain_num = content.chain_num;
 
-                // BEHOLD
-                // The below code is inaccurate: votes aren't counted from every block, 
-                // only the blocks belonging to the longest chain. So this is a major TODO.
-                // Bhavana will work on this 4/25. 
-                
-                // go through all votes, update proposer2votecount and chain2level
-                let mut max_vote_level: u32 = self.chain2level[&chain_num];
-                let voter_info = (chain_num, block_hash);
-                for vote in content.votes.clone() {
-                    // update proposer2votecount
-                    let counter = self.proposer2votecount.entry(vote).or_insert(0);
-                    *counter += 1;
-                    
-                    let voters_info = self.proposer2voterinfo.entry(vote).or_insert(Vec::new());
-                    voters_info.push(voter_info);
-
-                    // update max vote level variable
-                    let block_level = self.proposer_chain[&vote].level;
-                    let max_vote_level = cmp::max(max_vote_level, block_level);
-                }
-                self.chain2level.insert(chain_num, max_vote_level);
+                // votes are contiguous, so the last one is the deepest level voted for
+                let last_voted_level = self.voter2level[&content.parent_hash] + content.votes.len() as u32;
+                self.voter2level.insert(block_hash, last_voted_level);
 
                 // add to voter chain and update tip
-                let mut parent_meta = &self.voter_chains[(chain_num-1) as usize][&content.parent_hash];
+                let parent_meta = &self.voter_chains[(chain_num-1) as usize][&content.parent_hash];
                 let metablock = Metablock {
                     block: block.clone(),
                     level: parent_meta.level + 1
                 };
                 self.voter_chains[(chain_num-1) as usize].insert(block_hash, metablock.clone());
+                self.persist(StoreRecord::Chain(metablock.clone()));
                 // println!("Added voter {:?} #{} at level {}", block_hash, chain_num, metablock.level);
-                if metablock.level > self.voter_depths[(chain_num-1) as usize] {
-                    self.voter_depths[(chain_num-1) as usize] = metablock.level;
-                    self.voter_tips[(chain_num-1) as usize] = block_hash;
+                let work = self.chain_work[&content.parent_hash] + block.header.work();
+                self.chain_work.insert(block_hash, work);
+                // only votes on the main chain of a voter chain are counted
+                let levels = if work > self.chain_work[&self.voter_tips[(chain_num-1) as usize]] {
+                    self.switch_voter_tip(chain_num, block_hash)
+                } else {
+                    Vec::new()
+                };
+                self.publish(BlockchainEvent::NewVoter { hash: block_hash, chain_num, levels });
+            }
+
+            Content::Transaction(content) => {
+                self.tx_blocks.insert(block_hash, block.clone());
+                self.persist(StoreRecord::Transaction(block.clone()));
+                self.unref_tx_blocks.push(block_hash);
+
+                // remove transactions from the mempool
+                let mut locked_mempool = self.mempool.lock().unwrap();
+                for tx in &content.transactions {
+                    locked_mempool.delete(&tx.hash());
                 }
+                drop(locked_mempool);
             }
         }
 
@@ -321,7 +487,7 @@ impl Blockchain {
                             println!("Orphan block {:?} processed", orphan_block.hash());
                             // }
                         }
-                        InsertStatus::Orphan => {},
+                        InsertStatus::Orphan | InsertStatus::Invalid(_) => {},
                     }
                 }
             },
@@ -333,6 +499,77 @@ impl Blockchain {
         InsertStatus::Valid
     }
 
+    // Move the tip of voter chain `chain_num` to `new_tip`. Votes of the blocks
+    // leaving the main chain are taken back and those of the blocks joining it
+    // are counted, so the tallies always reflect the main chains only. Returns the
+    // proposer levels whose tally changed.
+    fn switch_voter_tip(&mut self, chain_num: u32, new_tip: H256) -> Vec<u32> {
+        let chain_idx = (chain_num-1) as usize;
+        let mut old_branch = self.voter_tips[chain_idx];
+        let mut new_branch = new_tip;
+        let mut disconnected: Vec<H256> = Vec::new();
+        let mut connected: Vec<H256> = Vec::new();
+        // walk both branches back to the fork point
+        while old_branch != new_branch {
+            let old_level = self.voter_chains[chain_idx][&old_branch].level;
+            let new_level = self.voter_chains[chain_idx][&new_branch].level;
+            if old_level >= new_level {
+                disconnected.push(old_branch);
+                old_branch = self.voter_parent(chain_num, &old_branch);
+            }
+            if new_level >= old_level {
+                connected.push(new_branch);
+                new_branch = self.voter_parent(chain_num, &new_branch);
+            }
+        }
+        if !disconnected.is_empty() {
+            info!("Voter chain {} reorganized, {} blocks leave the main chain", chain_num, disconnected.len());
+        }
+
+        let mut levels: Vec<u32> = Vec::new();
+        for voter_hash in disconnected {
+            for vote in self.voter_votes(chain_num, &voter_hash) {
+                levels.push(self.proposer_chain[&vote].level);
+                if let Some(counter) = self.proposer2votecount.get_mut(&vote) {
+                    *counter -= 1;
+                }
+                if let Some(voters_info) = self.proposer2voterinfo.get_mut(&vote) {
+                    remove_by_element(voters_info, (chain_num, voter_hash));
+                }
+            }
+        }
+        // count from the fork point up to the new tip
+        for voter_hash in connected.into_iter().rev() {
+            for vote in self.voter_votes(chain_num, &voter_hash) {
+                levels.push(self.proposer_chain[&vote].level);
+                *self.proposer2votecount.entry(vote).or_insert(0) += 1;
+                self.proposer2voterinfo.entry(vote).or_default().push((chain_num, voter_hash));
+            }
+        }
+
+        self.voter_depths[chain_idx] = self.voter_chains[chain_idx][&new_tip].level;
+        self.voter_tips[chain_idx] = new_tip;
+        self.chain2level.insert(chain_num, self.voter2level[&new_tip]);
+
+        levels.sort_unstable();
+        levels.dedup();
+        levels
+    }
+
+    fn voter_parent(&self, chain_num: u32, voter_hash: &H256) -> H256 {
+        match &self.voter_chains[(chain_num-1) as usize][voter_hash].block.content {
+            Content::Voter(content) => content.parent_hash,
+            _ => unreachable!("non-voter block in voter chain {}", chain_num),
+        }
+    }
+
+    fn voter_votes(&self, chain_num: u32, voter_hash: &H256) -> Vec<H256> {
+        match &self.voter_chains[(chain_num-1) as usize][voter_hash].block.content {
+            Content::Voter(content) => content.votes.clone(),
+            _ => unreachable!("non-voter block in voter chain {}", chain_num),
+        }
+    }
+
     pub fn get_proposer_tip(&self) -> H256 {
         self.proposer_tip
     }
@@ -341,15 +578,61 @@ impl Blockchain {
         self.voter_tips[(chain_num-1) as usize]
     }
 
+    // Difficulty of a block mined on top of the proposer `parent`. It stays fixed
+    // for DIFFICULTY_EPOCH proposer levels and is then scaled by how long the
+    // previous epoch took. The first epoch after genesis is not measured, since
+    // the genesis timestamp is not a mining time.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent_meta = &self.proposer_chain[parent];
+        let level = parent_meta.level + 1;
+        if level < 2 + 2 * DIFFICULTY_EPOCH {
+            return get_difficulty(self.num_voter_chains);
+        }
+        if !(level - 2).is_multiple_of(DIFFICULTY_EPOCH) {
+            return parent_meta.block.header.difficulty;
+        }
+
+        // walk back one epoch to the proposer the parent's epoch started from
+        let mut first = parent_meta;
+        for _ in 0..DIFFICULTY_EPOCH {
+            first = &self.proposer_chain[&first.block.header.parent];
+        }
+        let actual_timespan = parent_meta.block.header.timestamp.saturating_sub(first.block.header.timestamp);
+        retarget_difficulty(parent_meta.block.header.difficulty, actual_timespan)
+    }
+
+    // Median timestamp of `parent` and the proposers before it, at most MEDIAN_TIME_SPAN of them
+    pub fn median_time_past(&self, parent: &H256) -> u128 {
+        let mut timestamps: Vec<u128> = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = self.proposer_chain.get(parent);
+        while let Some(metablock) = current {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push(metablock.block.header.timestamp);
+            current = self.proposer_chain.get(&metablock.block.header.parent);
+        }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
     pub fn get_unref_proposers(&self) -> Vec<H256> {
         self.unref_proposers.clone()
     }
 
+    // Transaction blocks no proposer references yet, for the next mined proposer
+    pub fn get_unref_tx_blocks(&self) -> Vec<H256> {
+        self.unref_tx_blocks.clone()
+    }
+
     pub fn get_votes(&self, chain_num: u32) -> Vec<H256> {
         let mut votes: Vec<H256> = Vec::new();
         let last_voted_level = self.chain2level[&chain_num];
         let last_proposer_level = self.proposer_chain[&self.proposer_tip].level;
         for level in (last_voted_level+1)..(last_proposer_level+1) {
+            if votes.len() == MAX_VOTES {
+                break;
+            }
             votes.push(self.level2proposer[&level]);
         }
         votes
@@ -369,6 +652,77 @@ impl Blockchain {
         ret_val
     }
 
+    // Hashes of the main chain ending at the current tip, genesis first.
+    // Chain index 0 is the proposer chain, index i is voter chain i.
+    pub fn main_chain(&self, chain_idx: u32) -> Vec<H256> {
+        let chain = if chain_idx == 0 {
+            &self.proposer_chain
+        } else {
+            &self.voter_chains[(chain_idx-1) as usize]
+        };
+        let mut curr_key = if chain_idx == 0 {
+            self.proposer_tip
+        } else {
+            self.voter_tips[(chain_idx-1) as usize]
+        };
+
+        let mut hashes: Vec<H256> = Vec::new();
+        while let Some(metablock) = chain.get(&curr_key) {
+            hashes.push(curr_key);
+            curr_key = match &metablock.block.content {
+                Content::Proposer(c) => c.parent_hash,
+                Content::Voter(c) => c.parent_hash,
+                Content::Transaction(_) => unreachable!("transaction block in chain {}", chain_idx),
+            };
+        }
+        hashes.reverse();
+        hashes
+    }
+
+    // Block locator for every chain: the last ten hashes of the main chain, then
+    // exponentially sparser ones, always ending with genesis
+    pub fn get_locators(&self) -> Vec<Vec<H256>> {
+        (0..(self.num_voter_chains + 1)).map(|chain_idx| {
+            let main_chain = self.main_chain(chain_idx);
+            let mut locator: Vec<H256> = Vec::new();
+            let mut idx = main_chain.len() - 1;
+            let mut step = 1;
+            loop {
+                locator.push(main_chain[idx]);
+                if idx == 0 {
+                    break;
+                }
+                if locator.len() >= 10 {
+                    step *= 2;
+                }
+                idx = idx.saturating_sub(step);
+            }
+            locator
+        }).collect()
+    }
+
+    // For every chain, the headers of main chain blocks following the first locator
+    // hash that is on our main chain, at most `max_headers` of them. An empty locator
+    // means the peer does not want headers of that chain.
+    pub fn get_headers_after(&self, locators: &[Vec<H256>], max_headers: usize) -> Vec<Vec<Header>> {
+        (0..(self.num_voter_chains + 1)).map(|chain_idx| {
+            let locator = match locators.get(chain_idx as usize) {
+                Some(locator) => locator,
+                None => return Vec::new(),
+            };
+            let main_chain = self.main_chain(chain_idx);
+            let start = locator.iter()
+                .find_map(|hash| main_chain.iter().position(|h| h == hash));
+            match start {
+                Some(pos) => main_chain[(pos + 1)..].iter()
+                    .take(max_headers)
+                    .map(|hash| self.blocksdb[hash].header.clone())
+                    .collect(),
+                None => Vec::new(),
+            }
+        }).collect()
+    }
+
     pub fn print_chains(&self) {
         let mut chain: Vec<Vec<H256>> = Vec::new();
 
@@ -392,6 +746,9 @@ impl Blockchain {
                     Content::Proposer(c) => {
                         println!("wtf is wrong with you? you're a voter");
                     }
+                    Content::Transaction(_) => {
+                        warn!("transaction block in voter chain {}", chain_num);
+                    }
                 }
             }
             voter_chain.reverse();
@@ -406,11 +763,315 @@ impl Blockchain {
 mod tests {
     use super::*;
     // use crate::block::test::generate_random_block;
+    use crate::crypto::address::{self, H160};
     use crate::crypto::hash::Hashable;
+    use crate::transaction::{self, generate_random_signed_transaction, Amount};
+    use crate::validation::block_reward;
+    use crate::miner::TARGET_PROPOSER_INTERVAL;
+    use crate::validation::MAX_FUTURE_DRIFT;
+    use bigint::uint::U256;
 
     #[test]
     fn blockchain_init() {
         // 10 voting chains
-        let mut blockchain = Blockchain::new(10);
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Blockchain::new(10, &mempool);
+        assert_eq!(blockchain.voter_chains.len(), 10);
+    }
+
+    #[test]
+    fn restore_from_store() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(2, &mempool);
+        let genesis = blockchain.get_proposer_tip();
+
+        let difficulty = get_difficulty(2);
+        let proposer = |parent: H256, nonce: u32| {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            Block::new(parent, nonce as u128, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty)
+        };
+        let first = proposer(genesis, 1);
+        let second = proposer(first.hash(), 2);
+
+        // second arrives before its parent and waits in the orphan buffer
+        blockchain.insert(&second);
+        blockchain.insert(&first);
+        let voter_content = VoterContent { votes: vec![genesis, first.hash()], parent_hash: blockchain.get_voter_tip(1), chain_num: 1 };
+        let voter = Block::new(first.hash(), 3, 3, H256::default(), vec![], Content::Voter(voter_content), H160::default(), difficulty);
+        blockchain.insert(&voter);
+
+        let mut store = MemoryBlockStore::new();
+        for record in blockchain.store.load().unwrap() {
+            store.append(&record).unwrap();
+        }
+        let restored = Blockchain::with_store(2, &mempool, Box::new(store));
+        assert_eq!(restored.proposer_tip, second.hash());
+        assert_eq!(restored.proposer_depth, 3);
+        assert_eq!(restored.voter_tips[0], voter.hash());
+        assert_eq!(restored.level2allproposers[&2], vec![first.hash()]);
+        assert_eq!(restored.proposer2voterinfo[&first.hash()], vec![(1, voter.hash())]);
+        assert!(restored.orphan_buffer.is_empty());
+    }
+
+    #[test]
+    fn proposer_waits_for_transaction_block() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let genesis = blockchain.get_proposer_tip();
+        let difficulty = get_difficulty(1);
+
+        let signed_tx = generate_random_signed_transaction();
+        mempool.lock().unwrap().insert(signed_tx.clone(), Amount::ZERO);
+        let payout_addr = address::generate_random_address();
+        let tx_block = |coinbase_addr: H160, nonce: u32| {
+            let transactions = vec![transaction::coinbase(coinbase_addr, block_reward(2)), signed_tx.clone()];
+            Block::new(genesis, nonce as u128, nonce, H256::default(), vec![], Content::Transaction(TransactionContent { transactions }), payout_addr, difficulty)
+        };
+
+        // the coinbase has to pay the payout address of the header
+        let misdirected = tx_block(address::generate_random_address(), 3);
+        match blockchain.insert(&misdirected) {
+            InsertStatus::Invalid(error) => assert_eq!(error, ValidationError::BadCoinbase),
+            _ => panic!("coinbase paying another address accepted"),
+        }
+        assert!(mempool.lock().unwrap().contains(&signed_tx.hash()));
+
+        let tx_block = tx_block(payout_addr, 1);
+        let content = ProposerContent { parent_hash: genesis, transaction_refs: vec![tx_block.hash()], proposer_refs: vec![] };
+        let proposer = Block::new(genesis, 2, 2, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty);
+
+        // the proposer arrives first and waits for the transaction block it references
+        blockchain.insert(&proposer);
+        assert_eq!(blockchain.proposer_depth, 1);
+        blockchain.insert(&tx_block);
+        assert_eq!(blockchain.proposer_tip, proposer.hash());
+        assert!(blockchain.tx_blocks.contains_key(&tx_block.hash()));
+        assert!(blockchain.get_unref_tx_blocks().is_empty());
+        assert!(!mempool.lock().unwrap().contains(&signed_tx.hash()));
+
+        // transaction blocks are not part of any chain, but survive a restart
+        assert_eq!(blockchain.get_level(&tx_block.hash()), None);
+        let mut store = MemoryBlockStore::new();
+        for record in blockchain.store.load().unwrap() {
+            store.append(&record).unwrap();
+        }
+        let restored = Blockchain::with_store(1, &mempool, Box::new(store));
+        assert_eq!(restored.proposer_tip, proposer.hash());
+        assert!(restored.tx_blocks.contains_key(&tx_block.hash()));
+    }
+
+    #[test]
+    fn headers_after_locator() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let genesis = blockchain.get_proposer_tip();
+        let mut parent = genesis;
+        let mut hashes = vec![genesis];
+        for nonce in 0..30 {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            let difficulty = blockchain.next_difficulty(&parent);
+            let block = Block::new(parent, nonce as u128 + 1, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty);
+            parent = block.hash();
+            hashes.push(parent);
+            blockchain.insert(&block);
+        }
+        assert_eq!(blockchain.main_chain(0), hashes);
+
+        let locators = blockchain.get_locators();
+        assert_eq!(locators[0][0], parent);
+        assert_eq!(*locators[0].last().unwrap(), genesis);
+
+        // a peer that only knows the first ten proposers
+        let headers = blockchain.get_headers_after(&[vec![hashes[9], genesis], vec![]], 5);
+        let received: Vec<H256> = headers[0].iter().map(|h| h.hash()).collect();
+        assert_eq!(received, hashes[10..15].to_vec());
+        assert!(headers[1].is_empty());
+    }
+
+    #[test]
+    fn difficulty_retarget() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let initial = get_difficulty(1);
+        let mut parent = blockchain.get_proposer_tip();
+        let mut timestamp: u128 = 1_000_000_000;
+        // proposers arrive twice as fast as targeted
+        let interval = TARGET_PROPOSER_INTERVAL / 2;
+        let mut difficulties = vec![];
+        for nonce in 0..(3 * DIFFICULTY_EPOCH) {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            let difficulty = blockchain.next_difficulty(&parent);
+            let block = Block::new(parent, timestamp, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty);
+            match blockchain.insert(&block) {
+                InsertStatus::Valid => {}
+                _ => panic!("block with the expected difficulty was rejected"),
+            }
+            difficulties.push(difficulty);
+            parent = block.hash();
+            timestamp += interval;
+        }
+        // the first two epochs keep the initial difficulty
+        assert!(difficulties[..(2 * DIFFICULTY_EPOCH) as usize].iter().all(|d| *d == initial));
+        // the third one is twice as hard, i.e. half the target
+        let halved = U256::from_big_endian(initial.as_ref()) / U256::from(2u64);
+        let retargeted = U256::from_big_endian(difficulties[(2 * DIFFICULTY_EPOCH) as usize].as_ref());
+        assert!(retargeted <= halved && retargeted > halved - halved / U256::from(1000u64));
+        assert!(difficulties[(2 * DIFFICULTY_EPOCH) as usize..].iter().all(|d| *d == difficulties[(2 * DIFFICULTY_EPOCH) as usize]));
+
+        // a block that ignores the adjustment is rejected
+        let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+        let block = Block::new(parent, timestamp, 0, H256::default(), vec![], Content::Proposer(content), H160::default(), initial);
+        match blockchain.insert(&block) {
+            InsertStatus::Invalid(ValidationError::WrongDifficulty { actual, .. }) => assert_eq!(actual, initial),
+            _ => panic!("block with a stale difficulty was accepted"),
+        }
+        assert!(!blockchain.has_block(block.hash()));
+    }
+
+    #[test]
+    fn voter_votes_contiguous_levels() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let difficulty = get_difficulty(1);
+        let genesis = blockchain.get_proposer_tip();
+        let mut proposers = vec![genesis];
+        for nonce in 1..4 {
+            let parent = *proposers.last().unwrap();
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            let block = Block::new(parent, nonce as u128, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty);
+            blockchain.insert(&block);
+            proposers.push(block.hash());
+        }
+        let voter = |parent: H256, votes: Vec<H256>, nonce: u32| {
+            let content = VoterContent { votes, parent_hash: parent, chain_num: 1 };
+            Block::new(proposers[3], 10, nonce, H256::default(), vec![], Content::Voter(content), H160::default(), difficulty)
+        };
+        let voter_genesis = blockchain.get_voter_tip(1);
+
+        // skipping level 1
+        match blockchain.insert(&voter(voter_genesis, vec![proposers[1]], 0)) {
+            InsertStatus::Invalid(error) => assert_eq!(error, ValidationError::NonContiguousVote { expected_level: 1, level: 2 }),
+            _ => panic!("vote skipping a level was accepted"),
+        }
+        // two votes for level 1
+        match blockchain.insert(&voter(voter_genesis, vec![genesis, genesis], 1)) {
+            InsertStatus::Invalid(error) => assert_eq!(error, ValidationError::NonContiguousVote { expected_level: 2, level: 1 }),
+            _ => panic!("second vote for a level was accepted"),
+        }
+
+        let first = voter(voter_genesis, vec![genesis, proposers[1]], 2);
+        assert!(matches!(blockchain.insert(&first), InsertStatus::Valid));
+        assert_eq!(blockchain.chain2level[&1], 2);
+        assert_eq!(blockchain.get_votes(1), vec![proposers[2], proposers[3]]);
+        // the child continues right after its parent
+        let second = voter(first.hash(), vec![proposers[2]], 3);
+        assert!(matches!(blockchain.insert(&second), InsertStatus::Valid));
+        assert_eq!(blockchain.get_votes(1), vec![proposers[3]]);
+    }
+
+    #[test]
+    fn voter_reorg_retallies_votes() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let events = blockchain.subscribe();
+        let difficulty = get_difficulty(1);
+        let genesis = blockchain.get_proposer_tip();
+        let mut proposers = vec![genesis];
+        for nonce in 1..3 {
+            let parent = *proposers.last().unwrap();
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            let block = Block::new(parent, nonce as u128, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty);
+            blockchain.insert(&block);
+            proposers.push(block.hash());
+            assert_eq!(events.try_recv(), Ok(BlockchainEvent::NewProposer { hash: block.hash(), level: nonce + 1 }));
+        }
+        let voter = |parent: H256, votes: Vec<H256>, nonce: u32| {
+            let content = VoterContent { votes, parent_hash: parent, chain_num: 1 };
+            Block::new(proposers[2], 10, nonce, H256::default(), vec![], Content::Voter(content), H160::default(), difficulty)
+        };
+        let voter_genesis = blockchain.get_voter_tip(1);
+
+        let a = voter(voter_genesis, vec![genesis, proposers[1]], 0);
+        blockchain.insert(&a);
+        assert_eq!(blockchain.proposer2voterinfo[&proposers[1]], vec![(1, a.hash())]);
+        assert_eq!(events.try_recv(), Ok(BlockchainEvent::NewVoter { hash: a.hash(), chain_num: 1, levels: vec![1, 2] }));
+
+        // a side branch of the same work does not count
+        let b1 = voter(voter_genesis, vec![genesis], 1);
+        blockchain.insert(&b1);
+        assert_eq!(blockchain.get_voter_tip(1), a.hash());
+        assert_eq!(events.try_recv(), Ok(BlockchainEvent::NewVoter { hash: b1.hash(), chain_num: 1, levels: vec![] }));
+        assert_eq!(blockchain.proposer2votecount[&genesis], 1);
+        assert_eq!(blockchain.proposer2voterinfo[&genesis], vec![(1, a.hash())]);
+
+        // once it gets heavier the votes of `a` are replaced by its own
+        let b2 = voter(b1.hash(), vec![proposers[1], proposers[2]], 2);
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.get_voter_tip(1), b2.hash());
+        assert_eq!(events.try_recv(), Ok(BlockchainEvent::NewVoter { hash: b2.hash(), chain_num: 1, levels: vec![1, 2, 3] }));
+        assert_eq!(blockchain.voter_depths[0], 3);
+        assert_eq!(blockchain.chain2level[&1], 3);
+        assert_eq!(blockchain.proposer2votecount[&genesis], 1);
+        assert_eq!(blockchain.proposer2voterinfo[&genesis], vec![(1, b1.hash())]);
+        assert_eq!(blockchain.proposer2votecount[&proposers[1]], 1);
+        assert_eq!(blockchain.proposer2voterinfo[&proposers[1]], vec![(1, b2.hash())]);
+        assert_eq!(blockchain.proposer2voterinfo[&proposers[2]], vec![(1, b2.hash())]);
+    }
+
+    #[test]
+    fn heaviest_proposer_chain() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let difficulty = get_difficulty(1);
+        let genesis = blockchain.get_proposer_tip();
+        let proposer = |parent: H256, nonce: u32| {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            Block::new(parent, nonce as u128 + 1, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty)
+        };
+        let a = proposer(genesis, 1);
+        let b = proposer(genesis, 2);
+        blockchain.insert(&a);
+        blockchain.insert(&b);
+        // equal work, the first one seen stays the tip
+        assert_eq!(blockchain.get_proposer_tip(), a.hash());
+        assert_eq!(blockchain.chain_work[&b.hash()], blockchain.chain_work[&genesis] + b.header.work());
+
+        let b2 = proposer(b.hash(), 3);
+        blockchain.insert(&b2);
+        assert_eq!(blockchain.get_proposer_tip(), b2.hash());
+        assert_eq!(blockchain.proposer_depth, 3);
+    }
+
+    #[test]
+    fn timestamp_rules() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let mut blockchain = Blockchain::new(1, &mempool);
+        let difficulty = get_difficulty(1);
+        let proposer = |parent: H256, timestamp: u128| {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![] };
+            Block::new(parent, timestamp, 0, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty)
+        };
+        let mut parent = blockchain.get_proposer_tip();
+        for timestamp in 1..=MEDIAN_TIME_SPAN as u128 {
+            let block = proposer(parent, timestamp * 10);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        // ancestors are at 0, 10, .., 110, the median of the last eleven is 60
+        assert_eq!(blockchain.median_time_past(&parent), 60);
+
+        match blockchain.insert(&proposer(parent, 60)) {
+            InsertStatus::Invalid(error) => assert_eq!(error, ValidationError::TimestampTooOld { median_time_past: 60, timestamp: 60 }),
+            _ => panic!("block at the median time past was accepted"),
+        }
+        let far_future = now_micros() + 2 * MAX_FUTURE_DRIFT;
+        match blockchain.insert(&proposer(parent, far_future)) {
+            InsertStatus::Invalid(error) => assert_eq!(error, ValidationError::TimestampTooNew(far_future)),
+            _ => panic!("block from the far future was accepted"),
+        }
+        match blockchain.insert(&proposer(parent, 61)) {
+            InsertStatus::Valid => {}
+            _ => panic!("block just past the median time past was rejected"),
+        }
     }
 }
@@ -0,0 +1,89 @@
+use crate::crypto::hash::H256;
+use crate::ledger_manager::LedgerManagerState;
+use crate::utxo::UtxoState;
+
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Snapshot of everything the ledger manager has derived from the blockchain.
+// Both parts are written together so that they always describe the same level.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub utxo_state: UtxoState,
+    pub ledger_state: LedgerManagerState,
+}
+
+fn checksum(payload: &[u8]) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, payload).into()
+}
+
+// File layout: 32 byte SHA256 checksum of the payload, followed by the bincode
+// encoded checkpoint. The file is written next to the target and renamed over it,
+// so a crash while writing never destroys the previous checkpoint.
+pub fn write(path: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    let payload = bincode::serialize(checkpoint)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut bytes: Vec<u8> = Vec::with_capacity(payload.len() + 32);
+    bytes.extend_from_slice(checksum(&payload).as_ref());
+    bytes.extend_from_slice(&payload);
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, &bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+// Returns Ok(None) if no checkpoint has been written yet
+pub fn read(path: &Path) -> io::Result<Option<Checkpoint>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if bytes.len() < 32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint too short"));
+    }
+    let (stored_checksum, payload) = bytes.split_at(32);
+    if checksum(payload).as_ref() != stored_checksum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint checksum mismatch"));
+    }
+    let checkpoint = bincode::deserialize(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Some(checkpoint))
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_random_hash;
+
+    #[test]
+    fn checkpoint_roundtrip() {
+        let path = std::env::temp_dir().join(format!("ledger_checkpoint_{}.ckpt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(read(&path).unwrap().is_none());
+
+        let mut ledger_state = LedgerManagerState::new();
+        ledger_state.last_level_processed = 7;
+        ledger_state.tx_confirmed.insert(generate_random_hash());
+        let checkpoint = Checkpoint {
+            utxo_state: UtxoState::new(),
+            ledger_state,
+        };
+        write(&path, &checkpoint).unwrap();
+
+        let restored = read(&path).unwrap().unwrap();
+        assert_eq!(restored.ledger_state.last_level_processed, 7);
+        assert_eq!(restored.ledger_state.tx_confirmed, checkpoint.ledger_state.tx_confirmed);
+        assert_eq!(restored.utxo_state.state_map.len(), checkpoint.utxo_state.state_map.len());
+
+        // flip one payload bit, the checksum must catch it
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(read(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
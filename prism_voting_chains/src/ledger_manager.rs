@@ -1,53 +1,153 @@
 use crate::crypto::hash::{H256, Hashable};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, BlockchainEvent, Metablock};
 use crate::block::Content;
-use crate::transaction::SignedTransaction;
-use crate::utxo::UtxoState;
-
-use std::collections::{HashMap, HashSet};
+use crate::transaction::{Amount, ChainId, SignedTransaction};
+use crate::utxo::{TxUndo, UtxoState};
+use crate::checkpoint::{self, Checkpoint};
+use crate::validation::{block_reward, check_reward};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::iter;
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::{Arc, Mutex};
 
-use statrs::distribution::{Discrete, Poisson, Univariate};
+use statrs::distribution::{InverseCDF, Normal, Poisson, Univariate};
+
+use serde::{Serialize, Deserialize};
+
+use log::{debug, info, warn, error};
 
-use log::debug;
+// number of newly processed proposer levels after which a checkpoint is written
+const CHECKPOINT_INTERVAL: u32 = 10;
+// number of most recent levels that can be rolled back, older levels are final
+const MAX_UNDO_LEVELS: usize = 1000;
+
+// Parameters of the fast confirmation rule
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfirmationPolicy {
+    // fraction of the mining power assumed to be adversarial, below 1/2
+    pub beta: f64,
+    // probability that a confirmed leader is reverted later on
+    pub epsilon: f64,
+}
+
+// What confirming the leader of a level added to the ledger
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LevelUndo {
+    pub level: u32,
+    pub leader: H256,
+    // proposers of the leader's epoch
+    pub proposers: Vec<H256>,
+    // transactions confirmed with the epoch, in ledger order
+    pub transactions: Vec<TxUndo>,
+}
+
+// Published when leaders lose their confirmation and the ledger is rolled back.
+// Leaders and transactions are listed in the order they had in the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rollback {
+    // first level that was undone
+    pub level: u32,
+    pub leaders: Vec<H256>,
+    pub transactions: Vec<H256>,
+}
 
 //state required by ledger-manager
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LedgerManagerState {
     pub last_level_processed: u32,
     pub leader_sequence: Vec<H256>,
     pub proposer_blocks_processed: HashSet<H256>,
     pub tx_confirmed: HashSet<H256>,
     pub tx_count: usize,
+    // undo data of the most recent levels, oldest first
+    pub undo_log: VecDeque<LevelUndo>,
 }
 
-//ledger-manager will periodically loop and confirm the transactions 
+//ledger-manager reacts to new blocks and confirms the transactions
 pub struct LedgerManager {
     pub ledger_manager_state: LedgerManagerState,
     pub blockchain: Arc<Mutex<Blockchain>>,
     pub utxo_state: Arc<Mutex<UtxoState>>,
-    pub voter_depth_k: u32,
+    pub policy: ConfirmationPolicy,
+    // network whose signatures confirmed transactions carry
+    pub chain_id: ChainId,
+    // where the utxo state and ledger manager state are checkpointed, if anywhere
+    pub checkpoint_path: Option<PathBuf>,
+    pub last_checkpoint_level: u32,
+    // receivers of rollback events
+    subscribers: Vec<Sender<Rollback>>,
+    // blocks inserted into the blockchain
+    events: Receiver<BlockchainEvent>,
 }
 
-impl LedgerManager {
-    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, utxo_state: &Arc<Mutex<UtxoState>>, k: u32) -> Self {
-        let ledger_manager_state = LedgerManagerState{
+impl LedgerManagerState {
+    pub fn new() -> Self {
+        LedgerManagerState{
             last_level_processed: 1,
             proposer_blocks_processed: HashSet::new(),
             leader_sequence: Vec::new(),
             tx_confirmed: HashSet::new(),
             tx_count: 0,
-        };
+            undo_log: VecDeque::new(),
+        }
+    }
+}
 
+impl LedgerManager {
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, utxo_state: &Arc<Mutex<UtxoState>>, policy: ConfirmationPolicy, chain_id: ChainId, checkpoint_path: Option<PathBuf>) -> Self {
+        let mut ledger_manager_state = LedgerManagerState::new();
+
+        // resume from the last checkpoint so that already confirmed levels are not reconfirmed
+        if let Some(path) = &checkpoint_path {
+            match checkpoint::read(path) {
+                Ok(Some(checkpoint)) => {
+                    match check_checkpoint(&checkpoint.ledger_state, &blockchain.lock().unwrap()) {
+                        Ok(()) => {
+                            info!("Resuming ledger from checkpoint at level {}", checkpoint.ledger_state.last_level_processed);
+                            *utxo_state.lock().unwrap() = checkpoint.utxo_state;
+                            ledger_manager_state = checkpoint.ledger_state;
+                        }
+                        Err(reason) => {
+                            warn!("Ignoring ledger checkpoint {} that does not match the blockchain ({}), starting from genesis",
+                                  path.display(), reason);
+                        }
+                    }
+                }
+                Ok(None) => {
+                    info!("No ledger checkpoint found at {}, starting from genesis", path.display());
+                }
+                Err(e) => {
+                    warn!("Ignoring unusable ledger checkpoint {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        let last_checkpoint_level = ledger_manager_state.last_level_processed;
+        let events = blockchain.lock().unwrap().subscribe();
         LedgerManager {
             ledger_manager_state: ledger_manager_state,
             blockchain: Arc::clone(blockchain),
             utxo_state: Arc::clone(utxo_state),
-            voter_depth_k: k,
+            policy,
+            chain_id,
+            checkpoint_path,
+            last_checkpoint_level,
+            subscribers: Vec::new(),
+            events,
         }
     }
 
+    // Receive a `Rollback` every time confirmed levels are undone
+    pub fn subscribe(&mut self) -> Receiver<Rollback> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
         .name("ledger_manager".to_string())
@@ -57,27 +157,126 @@ impl LedgerManager {
         .unwrap();
     }
 
-    //Three Steps
+    fn ledger_manager_loop(&mut self) {
+        // blocks restored from the store were inserted before subscribing, and they
+        // may have changed the leaders of a checkpointed ledger
+        self.update_ledger(Some(0));
+
+        // nothing can be confirmed or rolled back until the blockchain changes
+        while let Ok(event) = self.events.recv() {
+            self.handle_events(event);
+        }
+    }
+
+    // Bring the ledger up to date with `event` and all the other pending events.
+    // Confirmed levels are only re-evaluated if their tally changed.
+    fn handle_events(&mut self, event: BlockchainEvent) {
+        let recheck_from = iter::once(event).chain(self.events.try_iter())
+            .filter_map(|event| match event {
+                BlockchainEvent::NewVoter { levels, .. } => levels.first().copied(),
+                BlockchainEvent::NewProposer { .. } => None,
+            })
+            .min();
+        self.update_ledger(recheck_from);
+
+        if self.ledger_manager_state.last_level_processed >= self.last_checkpoint_level + CHECKPOINT_INTERVAL {
+            self.write_checkpoint();
+        }
+    }
+
+    //Four Steps
+    //0. Roll back levels from `recheck_from` on whose leader is no longer confirmed
     //1. Get the leader sequence
     //2. Get Transaction sequence
     //3. Sanitize Tx and update UTXO state
-    //All 3 steps are done in the loop
+    //Steps 2 and 3 are done level by level, so that each level can be undone
     //
-    fn ledger_manager_loop(&mut self) {
-        loop{
-            //Step 1
-            //let leader_sequence = self.get_leader_sequence();
-            
-            //This one uses the algorithm described in Prism Paper
-            let leader_sequence = self.get_confirmed_leader_sequence();
-            
+    fn update_ledger(&mut self, recheck_from: Option<u32>) {
+        //Step 0
+        if let Some(level) = recheck_from {
+            self.check_confirmed_leaders(level);
+        }
+
+        //Step 1
+        //let leader_sequence = self.get_leader_sequence();
+
+        //This one uses the algorithm described in Prism Paper
+        let level_start = self.ledger_manager_state.last_level_processed + 1;
+        let leader_sequence = self.get_confirmed_leader_sequence();
+
+        for (level, leader) in (level_start..).zip(leader_sequence) {
             //Step 2
-            let tx_sequence = self.get_transaction_sequence(&leader_sequence);
-            
+            let (proposers, tx_blocks) = self.get_transaction_sequence(&leader);
+
             //Step 3
-            self.confirm_transactions(&tx_sequence);
-            
-            thread::sleep(Duration::from_secs(1));
+            let transactions = self.confirm_transactions(&tx_blocks, level);
+
+            let undo_log = &mut self.ledger_manager_state.undo_log;
+            undo_log.push_back(LevelUndo { level, leader, proposers, transactions });
+            if undo_log.len() > MAX_UNDO_LEVELS {
+                undo_log.pop_front();
+            }
+        }
+    }
+
+    // Re-evaluate the levels from `level` on that can still be undone, and roll the
+    // ledger back to the first one whose leader is not confirmed anymore, e.g. after
+    // a voter chain reorganization took votes away from it
+    fn check_confirmed_leaders(&mut self, level: u32) {
+        let leaders: Vec<(u32, H256)> = self.ledger_manager_state.undo_log.iter()
+            .filter(|undo| undo.level >= level)
+            .map(|undo| (undo.level, undo.leader))
+            .collect();
+        for (level, leader) in leaders {
+            if self.confirm_leader(level) != Some(leader) {
+                self.rollback(level);
+                return;
+            }
+        }
+    }
+
+    // Undo every level from `level` on, newest first, and notify the subscribers
+    fn rollback(&mut self, level: u32) {
+        let mut rollback = Rollback { level, leaders: Vec::new(), transactions: Vec::new() };
+        let mut locked_utxostate = self.utxo_state.lock().unwrap();
+        while self.ledger_manager_state.undo_log.back().is_some_and(|undo| undo.level >= level) {
+            let undo = self.ledger_manager_state.undo_log.pop_back().unwrap();
+            for tx_undo in undo.transactions.iter().rev() {
+                locked_utxostate.undo(tx_undo);
+                self.ledger_manager_state.tx_confirmed.remove(&tx_undo.tx_hash);
+                rollback.transactions.push(tx_undo.tx_hash);
+            }
+            for proposer in &undo.proposers {
+                self.ledger_manager_state.proposer_blocks_processed.remove(proposer);
+            }
+            rollback.leaders.push(undo.leader);
+        }
+        drop(locked_utxostate);
+        rollback.leaders.reverse();
+        rollback.transactions.reverse();
+
+        self.ledger_manager_state.last_level_processed = level - 1;
+        self.last_checkpoint_level = self.last_checkpoint_level.min(level - 1);
+        warn!("Leader at level {} lost its confirmation, rolled back {} levels and {} transactions",
+              level, rollback.leaders.len(), rollback.transactions.len());
+        self.subscribers.retain(|subscriber| subscriber.send(rollback.clone()).is_ok());
+    }
+
+    fn write_checkpoint(&mut self) {
+        let path = match &self.checkpoint_path {
+            Some(path) => path,
+            None => return,
+        };
+        let checkpoint = Checkpoint {
+            utxo_state: self.utxo_state.lock().unwrap().clone(),
+            ledger_state: self.ledger_manager_state.clone(),
+        };
+        match checkpoint::write(path, &checkpoint) {
+            Ok(()) => {
+                debug!("Ledger checkpoint written at level {}", self.ledger_manager_state.last_level_processed);
+                self.last_checkpoint_level = self.ledger_manager_state.last_level_processed;
+            }
+            Err(e) => error!("Unable to write ledger checkpoint {}: {}", path.display(), e),
         }
     }
 
@@ -92,7 +291,10 @@ impl LedgerManager {
         let level_start = self.ledger_manager_state.last_level_processed + 1;
         let level_end = locked_blockchain.proposer_depth + 1;
         for level in level_start..level_end {
-            let proposers = &locked_blockchain.level2allproposers[&level];
+            let proposers = match locked_blockchain.level2allproposers.get(&level) {
+                Some(proposers) => proposers,
+                None => break,
+            };
             
             let mut max_vote_count = 0;
             let mut leader: H256 = [0; 32].into();
@@ -113,10 +315,10 @@ impl LedgerManager {
                 break;
             }
 
-            println!("Adding leader at level {}, leader hash: {:?}, max votes: {}", level, leader, max_vote_count);
+            debug!("Adding leader at level {}, leader hash: {:?}, max votes: {}", level, leader, max_vote_count);
             leader_sequence.push(leader);
             self.ledger_manager_state.leader_sequence.push(leader);
-            println!("Leader sequence: {:?}", self.ledger_manager_state.leader_sequence);
+            debug!("Leader sequence: {:?}", self.ledger_manager_state.leader_sequence);
             self.ledger_manager_state.last_level_processed = level;
         }
 
@@ -140,7 +342,7 @@ impl LedgerManager {
 
             match leader {
                 Some(leader_hash) => {  
-                    println!("Adding leader at level {}, leader hash: {:?}", level, leader_hash);
+                    info!("Adding leader at level {}, leader hash: {:?}", level, leader_hash);
                     leader_sequence.push(leader_hash);
                     // self.ledger_manager_state.leader_sequence.push(leader_hash);
                     // println!("Leader sequence: {:?}", self.ledger_manager_state.leader_sequence);
@@ -148,8 +350,7 @@ impl LedgerManager {
                 }
 
                 None => {
-                    println!("Unable to confirm leader at level {}", level);
-                    println!("Returning from get_confirmed_leader_sequence func");
+                    debug!("Unable to confirm leader at level {} yet", level);
                     break; // TODO: Will this break out of loop??
                 }
             }
@@ -164,137 +365,597 @@ impl LedgerManager {
     fn confirm_leader(&mut self, level: u32) -> Option<H256> {
 
         let locked_blockchain = self.blockchain.lock().unwrap();
-        
-        let proposer_blocks = &locked_blockchain.level2allproposers[&level];
-        let mut new_leader: Option<H256> = None;
 
+        let proposer_blocks = locked_blockchain.level2allproposers.get(&level)?;
         let num_voter_chains: u32 = locked_blockchain.num_voter_chains;
 
-        // for each proposer count the number of confirmed votes i.e. votes that are k-deep (2-deep).
-        let mut num_confirmed_votes: HashMap<H256, u32> = HashMap::new(); 
-
+        // depth of every vote for each proposer at this level, a vote in a voter
+        // chain tip is 1-deep. Only votes on the voter main chains are tallied.
+        let mut vote_depths: HashMap<H256, Vec<u32>> = HashMap::new();
         for block in proposer_blocks {
-            if locked_blockchain.proposer2voterinfo.contains_key(block) {
-                
-                //TODO: We might also need number of voter blocks at a particular level of a voter chain
-                //This is not urgent as we can **assume**, there is one block at each level
-                let voters_info = &locked_blockchain.proposer2voterinfo[block];
-                if voters_info.len() < (num_voter_chains as usize / 2) {
-                    println!("number of votes for {:?} is {}", block, voters_info.len());
-                    continue;
-                }
-
-                let mut total_k_deep_votes: u32 = 0;
+            let depths = vote_depths.entry(*block).or_default();
+            if let Some(voters_info) = locked_blockchain.proposer2voterinfo.get(block) {
                 for (voter_chain, voter_block) in voters_info {
-
                     let voter_block_level = locked_blockchain.voter_chains[(*voter_chain-1) as usize][voter_block].level;
                     let voter_chain_level = locked_blockchain.voter_depths[(*voter_chain-1) as usize];
-                    
-                    let this_vote_depth = voter_chain_level - voter_block_level;
-                    if this_vote_depth >= self.voter_depth_k {
-                        total_k_deep_votes += 1;
-                    }
+                    depths.push(voter_chain_level - voter_block_level + 1);
                 }
-                num_confirmed_votes.insert(*block, total_k_deep_votes);
             }
         }
 
-        for (proposer, votes) in  num_confirmed_votes.iter() {
-            println!("proposer {:?}  votes {}", proposer, *votes);
-            if *votes > (num_voter_chains / 2) {
-                new_leader = Some(*proposer);
-                break;
-            }
-        }
-
-        new_leader
+        select_leader(&vote_depths, num_voter_chains, &self.policy)
     }
 
-    // needs to process parent as well
-    fn get_transaction_sequence(&mut self, leader_sequence: &Vec<H256>) -> Vec<SignedTransaction> {        
+    // Proposers of the leader's epoch, i.e. the leader and every proposer reachable
+    // from it that has not made it into the ledger yet, and the transaction blocks they
+    // reference, in reference order. Each block comes with the reward its coinbase may
+    // claim besides the fees, and its transactions.
+    fn get_transaction_sequence(&mut self, leader: &H256) -> (Vec<H256>, Vec<(Amount, Vec<SignedTransaction>)>) {
         let locked_blockchain = self.blockchain.lock().unwrap();
 
-        let mut tx_sequence: Vec<SignedTransaction> = Vec::new();
-
-        //TODO: Should we do it recusrively? Like should we also see references to
-        //proposer references of leader?
-        //TODO: Also we should refactor it later
-        for leader in leader_sequence {
-            let leader_block = &locked_blockchain.proposer_chain[leader].block;
-
-            //processing parent and proposer refs
-            let mut proposer_refs_to_process: Vec<H256> = Vec::new();
-            let mut leader_txs: Vec<SignedTransaction> = Vec::new();
-            match &leader_block.content {
-                Content::Proposer(content) => {
-                    // parent and proposer_refs of leader
-                    let parent = &content.parent_hash;
-                    let proposer_refs = &content.proposer_refs;
-                    
-                    if !self.ledger_manager_state.proposer_blocks_processed.contains(parent) {
-                        proposer_refs_to_process.push(*parent);
-                    }
+        let mut tx_blocks: Vec<(Amount, Vec<SignedTransaction>)> = Vec::new();
+        // concurrent proposers may reference the same transaction block
+        let mut tx_blocks_seen: HashSet<H256> = HashSet::new();
 
-                    for proposer_ref in proposer_refs {
-                        if !self.ledger_manager_state.proposer_blocks_processed.contains(proposer_ref) {
-                            proposer_refs_to_process.push(*proposer_ref);
-                        }
+        let epoch = epoch_proposers(leader, &locked_blockchain.proposer_chain, &self.ledger_manager_state.proposer_blocks_processed);
+        for proposer in &epoch {
+            if let Content::Proposer(content) = &locked_blockchain.proposer_chain[proposer].block.content {
+                for tx_block_hash in &content.transaction_refs {
+                    if !tx_blocks_seen.insert(*tx_block_hash) {
+                        continue;
+                    }
+                    let tx_block = &locked_blockchain.tx_blocks[tx_block_hash];
+                    if let Content::Transaction(tx_content) = &tx_block.content {
+                        // transaction blocks are only inserted on top of a known proposer
+                        let reward = locked_blockchain.proposer_chain.get(&tx_block.header.parent)
+                            .map_or(Amount::ZERO, |parent| block_reward(parent.level + 1));
+                        tx_blocks.push((reward, tx_content.transactions.clone()));
                     }
+                }
+            }
+            self.ledger_manager_state.proposer_blocks_processed.insert(*proposer);
+        }
 
-                    //txs of leader
-                    leader_txs = content.transactions.clone(); 
+        (epoch, tx_blocks)
+    }
+
+    // Apply the valid transactions to the utxo state at ledger level `level`, returns
+    // how to undo them. A coinbase is confirmed after the transactions of its block, if it
+    // claims no more than the block reward and the fees of those that made it into the
+    // ledger, but it keeps its place in front of them in the ledger order.
+    fn confirm_transactions(&mut self, tx_blocks: &[(Amount, Vec<SignedTransaction>)], level: u32) -> Vec<TxUndo> {
+        let mut undo: Vec<TxUndo> = Vec::new();
+        self.ledger_manager_state.tx_count += tx_blocks.iter().map(|(_, transactions)| transactions.len()).sum::<usize>();
+        // println!("Number of transactions considered yet {}", self.ledger_manager_state.tx_count);
+        let mut locked_utxostate = self.utxo_state.lock().unwrap();
+        for (reward, transactions) in tx_blocks {
+            let coinbase_position = undo.len();
+            let mut fees = Amount::ZERO;
+            for tx in transactions.iter().filter(|tx| !tx.tx.is_coinbase()) {
+                //if already processed continue
+                if self.ledger_manager_state.tx_confirmed.contains(&tx.tx.hash()) {
+                    debug!("Skipping trans hash {}: already confirmed", tx.hash());
+                    continue;
                 }
-                _ => {
 
+                //check for validity
+                //if valid, update utxo_state and add to confirmed transactions
+                match locked_utxostate.validate_tx(tx, level, &self.chain_id) {
+                    Ok(fee) => {
+                        undo.push(locked_utxostate.update_state(tx, level));
+                        fees = fees.saturating_add(fee);
+                        self.ledger_manager_state.tx_confirmed.insert(tx.tx.hash());
+                        info!("Confirmed trans hash {} at {}", tx.hash(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros());
+                        // Print UTXO state
+                        // locked_utxostate.print();
+                    }
+                    Err(error) => debug!("Skipping trans hash {}: {}", tx.hash(), error),
                 }
             }
 
-            //TODO: Do we have to do match in this and previous loop as we know it will always
-            //match to Proposer(content). Can we unwrap??
-            for proposer_ref in &proposer_refs_to_process {
-                let proposer_block = &locked_blockchain.proposer_chain[proposer_ref].block;
-                match &proposer_block.content {
-                    Content::Proposer(content) => {
-                        tx_sequence.append(&mut content.transactions.clone()); 
-                    }
-                    _ => {
+            // its outputs cannot be spent by the transactions of its own block, so undoing
+            // it before them is the same as undoing it after them
+            let coinbase = match transactions.first() {
+                Some(coinbase) if coinbase.tx.is_coinbase() => coinbase,
+                _ => continue,
+            };
+            if self.ledger_manager_state.tx_confirmed.contains(&coinbase.tx.hash()) {
+                debug!("Skipping coinbase {}: already confirmed", coinbase.hash());
+                continue;
+            }
+            match check_reward(coinbase, *reward, fees) {
+                Ok(()) => {
+                    undo.insert(coinbase_position, locked_utxostate.apply_coinbase(coinbase, level));
+                    self.ledger_manager_state.tx_confirmed.insert(coinbase.tx.hash());
+                }
+                Err(error) => debug!("Skipping coinbase {}: {}", coinbase.hash(), error),
+            }
+        }
+        drop(locked_utxostate);
+        undo
+    }
+}
 
-                    }
-                }             
-                
-                self.ledger_manager_state.proposer_blocks_processed.insert(*proposer_ref);
+// Whether a checkpointed ledger can be resumed on top of `blockchain`: the levels it
+// processed, the leaders of its undo log and the proposers it recorded must all be there
+fn check_checkpoint(state: &LedgerManagerState, blockchain: &Blockchain) -> Result<(), String> {
+    let last_level = state.last_level_processed;
+    if last_level > blockchain.proposer_depth {
+        return Err(format!("level {} was processed but the proposer chain is {} levels deep", last_level, blockchain.proposer_depth));
+    }
+    // the undo log holds one entry per level, up to the last processed one
+    let levels: Vec<u32> = state.undo_log.iter().map(|undo| undo.level).collect();
+    if levels.windows(2).any(|w| w[1] != w[0] + 1) || levels.last().is_some_and(|&level| level != last_level) {
+        return Err(format!("the undo log does not end at level {}", last_level));
+    }
+    for undo in &state.undo_log {
+        let is_proposer = blockchain.level2allproposers.get(&undo.level).is_some_and(|proposers| proposers.contains(&undo.leader));
+        if !is_proposer {
+            return Err(format!("leader {:?} is not a proposer at level {}", undo.leader, undo.level));
+        }
+    }
+    let unknown = state.leader_sequence.iter().chain(state.proposer_blocks_processed.iter())
+        .find(|hash| !blockchain.proposer_chain.contains_key(hash));
+    if let Some(hash) = unknown {
+        return Err(format!("proposer {:?} is unknown", hash));
+    }
+    Ok(())
+}
+
+// Proposers that enter the ledger with `leader`: all the proposers reachable from it
+// through parent and reference links, except the `processed` ones. The order is a
+// depth-first post-order, so every block comes after its parent and its references,
+// which are visited in the order they are listed, and the leader comes last.
+pub fn epoch_proposers(leader: &H256, proposer_chain: &HashMap<H256, Metablock>, processed: &HashSet<H256>) -> Vec<H256> {
+    let mut epoch: Vec<H256> = Vec::new();
+    let mut visited: HashSet<H256> = HashSet::new();
+    // (block, whether its parent and references are already on the stack)
+    let mut stack: Vec<(H256, bool)> = vec![(*leader, false)];
+    while let Some((hash, expanded)) = stack.pop() {
+        if expanded {
+            epoch.push(hash);
+            continue;
+        }
+        if processed.contains(&hash) || !visited.insert(hash) {
+            continue;
+        }
+        let content = match proposer_chain.get(&hash).map(|metablock| &metablock.block.content) {
+            Some(Content::Proposer(content)) => content,
+            // the parent of the genesis proposer
+            _ => continue,
+        };
+        stack.push((hash, true));
+        for proposer_ref in content.proposer_refs.iter().rev() {
+            stack.push((*proposer_ref, false));
+        }
+        stack.push((content.parent_hash, false));
+    }
+    epoch
+}
+
+// Probability that an adversary with a `beta` fraction of the mining power mines a
+// private voter branch overtaking a vote that is `depth` blocks deep. While the
+// honest miners add `depth` blocks the adversary mines a Poisson number of blocks
+// with mean depth * beta / (1 - beta).
+pub fn reversal_probability(depth: u32, beta: f64) -> f64 {
+    if depth == 0 {
+        return 1.0;
+    }
+    let poisson = Poisson::new(depth as f64 * beta / (1.0 - beta)).unwrap();
+    1.0 - poisson.cdf(depth as f64 - 1.0)
+}
+
+// Leader of a proposer level under the fast confirmation rule, given the depth of
+// the votes of every proposer at the level. Each vote stays with its proposer with
+// probability 1 - reversal_probability, so a proposer keeps at least
+// mean - z * std_dev of its votes except with probability epsilon. Any other
+// proposer, including one the adversary has not released yet, gets at most its own
+// votes, the votes not cast yet and the votes that may be reversed. A proposer is
+// confirmed once its lower bound beats the upper bound of every other candidate.
+pub fn select_leader(vote_depths: &HashMap<H256, Vec<u32>>, num_voter_chains: u32, policy: &ConfirmationPolicy) -> Option<H256> {
+    let quantile = Normal::new(0.0, 1.0).unwrap().inverse_cdf(1.0 - policy.epsilon);
+
+    // Candidates by decreasing number of votes, then by hash. Every node walks them,
+    // and adds up their floating point bounds, in this order regardless of the order
+    // in which blocks and votes arrived, so all nodes pick the same leader.
+    let mut candidates: Vec<(H256, Vec<u32>)> = vote_depths.iter()
+        .map(|(proposer, depths)| {
+            let mut depths = depths.clone();
+            depths.sort_unstable();
+            (*proposer, depths)
+        })
+        .collect();
+    candidates.sort_by(|(a, a_depths), (b, b_depths)| b_depths.len().cmp(&a_depths.len()).then(a.cmp(b)));
+
+    // expected number of reversed votes and its variance, per candidate
+    let reversals: Vec<(f64, f64)> = candidates.iter()
+        .map(|(_, depths)| depths.iter()
+            .map(|depth| reversal_probability(*depth, policy.beta))
+            .fold((0.0, 0.0), |(mean, variance), p| (mean + p, variance + p * (1.0 - p))))
+        .collect();
+    let votes_cast: u32 = candidates.iter().map(|(_, depths)| depths.len() as u32).sum();
+    let votes_not_cast = num_voter_chains.saturating_sub(votes_cast) as f64;
+    let (total_mean, total_variance) = reversals.iter()
+        .fold((0.0, 0.0), |(mean, variance), (m, v)| (mean + m, variance + v));
+
+    // a proposer the adversary kept private can only win reversed and new votes
+    let hidden_ucb = votes_not_cast + total_mean + quantile * total_variance.sqrt();
+
+    // the first candidate with the highest lower bound
+    let mut leader: Option<(usize, f64)> = None;
+    for (idx, (_, depths)) in candidates.iter().enumerate() {
+        let (mean, variance) = reversals[idx];
+        let lcb = depths.len() as f64 - mean - quantile * variance.sqrt();
+        if leader.is_none_or(|(_, max_lcb)| lcb > max_lcb) {
+            leader = Some((idx, lcb));
+        }
+    }
+    let (leader, lcb) = leader?;
+    if lcb <= hidden_ucb {
+        return None;
+    }
+    for (idx, (_, depths)) in candidates.iter().enumerate() {
+        if idx == leader {
+            continue;
+        }
+        let (mean, variance) = (total_mean - reversals[idx].0, total_variance - reversals[idx].1);
+        let ucb = depths.len() as f64 + votes_not_cast + mean + quantile * variance.max(0.0).sqrt();
+        if lcb <= ucb {
+            return None;
+        }
+    }
+    Some(candidates[leader].0)
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::block::{genesis_proposer, genesis_voter, Block, ProposerContent, TransactionContent, VoterContent};
+    use crate::crypto::address::{self, H160};
+    use crate::crypto::hash::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::mempool::TransactionMempool;
+    use crate::miner::get_difficulty;
+    use crate::validation::block_reward;
+    use crate::transaction::{self, generate_random_signed_transaction, Amount, Transaction, UtxoInput, UtxoOutput};
+    use ring::signature::KeyPair;
+    use rand::Rng;
+
+    const POLICY: ConfirmationPolicy = ConfirmationPolicy { beta: 0.25, epsilon: 1e-3 };
+
+    #[test]
+    fn deeper_votes_are_safer() {
+        let probabilities: Vec<f64> = (1..=12).map(|depth| reversal_probability(depth, POLICY.beta)).collect();
+        assert!(probabilities.windows(2).all(|w| w[1] < w[0]));
+        // mining at a third of the honest rate, the adversary rarely overtakes 12 blocks
+        assert!(probabilities[11] < 1e-3);
+        assert!(reversal_probability(5, 0.1) < reversal_probability(5, 0.4));
+    }
+
+    #[test]
+    fn leader_confirmed_by_deep_votes() {
+        let (a, b) = (generate_random_hash(), generate_random_hash());
+
+        // 10 deep votes out of 10 voter chains
+        let mut vote_depths = HashMap::new();
+        vote_depths.insert(a, vec![20; 10]);
+        vote_depths.insert(b, vec![]);
+        assert_eq!(select_leader(&vote_depths, 10, &POLICY), Some(a));
+
+        // the votes are too shallow to rule out a reversal
+        vote_depths.insert(a, vec![1; 10]);
+        assert_eq!(select_leader(&vote_depths, 10, &POLICY), None);
+
+        // a close race is decided only once the votes are deep enough
+        vote_depths.insert(a, vec![5; 6]);
+        vote_depths.insert(b, vec![5; 4]);
+        assert_eq!(select_leader(&vote_depths, 10, &POLICY), None);
+        vote_depths.insert(a, vec![20; 6]);
+        vote_depths.insert(b, vec![20; 4]);
+        assert_eq!(select_leader(&vote_depths, 10, &POLICY), Some(a));
+
+        // neither is a majority while many voter chains have not voted yet
+        vote_depths.insert(a, vec![20; 4]);
+        vote_depths.insert(b, vec![]);
+        assert_eq!(select_leader(&vote_depths, 10, &POLICY), None);
+    }
+
+    // Proposer DAG on top of genesis where every block references a few random earlier blocks
+    fn random_dag(size: usize) -> (HashMap<H256, Metablock>, Vec<H256>) {
+        let mut rng = rand::thread_rng();
+        let genesis = genesis_proposer();
+        let mut hashes = vec![genesis.hash()];
+        let mut proposer_chain = HashMap::new();
+        proposer_chain.insert(genesis.hash(), Metablock { block: genesis, level: 1 });
+        for nonce in 0..size {
+            let parent_hash = hashes[rng.gen_range(0, hashes.len())];
+            let proposer_refs = (0..rng.gen_range(0, 4)).map(|_| hashes[rng.gen_range(0, hashes.len())]).collect();
+            let content = ProposerContent { parent_hash, transaction_refs: vec![], proposer_refs };
+            let block = Block::new(parent_hash, 0, nonce as u32, H256::default(), vec![], Content::Proposer(content), H160::default(), H256::default());
+            let level = proposer_chain[&parent_hash].level + 1;
+            hashes.push(block.hash());
+            proposer_chain.insert(block.hash(), Metablock { block, level });
+        }
+        (proposer_chain, hashes)
+    }
+
+    // every block of `epoch` shows up once, after its parent and references
+    fn assert_topological(epoch: &[H256], proposer_chain: &HashMap<H256, Metablock>, processed: &HashSet<H256>) {
+        let mut seen = processed.clone();
+        for hash in epoch {
+            if let Content::Proposer(content) = &proposer_chain[hash].block.content {
+                for link in content.proposer_refs.iter().chain(std::iter::once(&content.parent_hash)) {
+                    assert!(seen.contains(link) || !proposer_chain.contains_key(link), "{:?} comes before {:?}", hash, link);
+                }
             }
+            assert!(seen.insert(*hash), "{:?} included twice", hash);
+        }
+    }
 
-            //appending leader txs finally
-            //adding leader to proposer_blocks_processed
-            tx_sequence.append(&mut leader_txs);
-            self.ledger_manager_state.proposer_blocks_processed.insert(*leader);
+    #[test]
+    fn epoch_includes_indirect_references() {
+        let (mut proposer_chain, hashes) = random_dag(2000);
+        let mut processed = HashSet::new();
+        // confirm a few leaders one after the other, the last one references everything
+        let tip = {
+            let content = ProposerContent { parent_hash: hashes[hashes.len() - 1], transaction_refs: vec![], proposer_refs: hashes.clone() };
+            Block::new(hashes[0], 0, u32::MAX, H256::default(), vec![], Content::Proposer(content), H160::default(), H256::default())
+        };
+        proposer_chain.insert(tip.hash(), Metablock { block: tip.clone(), level: 0 });
+        let mut included = 0;
+        for leader in [hashes[500], hashes[1000], hashes[1500], tip.hash()].iter() {
+            let epoch = epoch_proposers(leader, &proposer_chain, &processed);
+            assert_eq!(epoch.last(), Some(leader));
+            assert_topological(&epoch, &proposer_chain, &processed);
+            included += epoch.len();
+            processed.extend(epoch);
         }
+        assert_eq!(included, proposer_chain.len());
+        // walking the same leader twice gives the same epoch
+        let (proposer_chain, hashes) = random_dag(300);
+        let leader = hashes[hashes.len() - 1];
+        assert_eq!(epoch_proposers(&leader, &proposer_chain, &HashSet::new()), epoch_proposers(&leader, &proposer_chain, &HashSet::new()));
+    }
 
-        tx_sequence
+    #[test]
+    fn epoch_of_a_long_chain() {
+        // a reference chain deep enough to overflow a recursive walk
+        let genesis = genesis_proposer();
+        let mut proposer_chain = HashMap::new();
+        let mut parent = genesis.hash();
+        proposer_chain.insert(parent, Metablock { block: genesis, level: 1 });
+        for nonce in 0..100_000u32 {
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![], proposer_refs: vec![parent] };
+            let block = Block::new(parent, 0, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), H256::default());
+            parent = block.hash();
+            proposer_chain.insert(parent, Metablock { block, level: nonce + 2 });
+        }
+        let epoch = epoch_proposers(&parent, &proposer_chain, &HashSet::new());
+        assert_eq!(epoch.len(), proposer_chain.len());
+        assert_topological(&epoch, &proposer_chain, &HashSet::new());
     }
 
-    fn confirm_transactions(&mut self, tx_sequence: &Vec<SignedTransaction>) {
-        self.ledger_manager_state.tx_count += tx_sequence.len();
-        // println!("Number of transactions considered yet {}", self.ledger_manager_state.tx_count);
-        let mut locked_utxostate = self.utxo_state.lock().unwrap();
-        for tx in tx_sequence {
-            //if already processed continue
-            if self.ledger_manager_state.tx_confirmed.contains(&tx.hash()) {
-                println!("DUPLICATE TXS! Already confirmed");
-                continue;
+    #[test]
+    fn ledger_independent_of_arrival_order() {
+        let num_voter_chains = 3;
+        let difficulty = get_difficulty(num_voter_chains);
+        let mut nonce = 0;
+        // every proposer references a transaction block of its own
+        let genesis = genesis_proposer().hash();
+        let level = |parent: H256| if parent == genesis { 2 } else { 3 };
+        let mut proposer = |parent: H256, proposer_refs: Vec<H256>| {
+            let mut transactions = vec![transaction::coinbase(H160::default(), block_reward(level(parent)))];
+            transactions.extend((0..3).map(|_| generate_random_signed_transaction()));
+            nonce += 1;
+            let tx_block = Block::new(parent, nonce as u128, nonce, H256::default(), vec![], Content::Transaction(TransactionContent { transactions }), H160::default(), difficulty);
+            let content = ProposerContent { parent_hash: parent, transaction_refs: vec![tx_block.hash()], proposer_refs };
+            nonce += 1;
+            (tx_block, Block::new(parent, nonce as u128, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty))
+        };
+        // two competing proposers at level 2, the one at level 3 refers to the loser
+        let (tx_a, a) = proposer(genesis, vec![]);
+        let (tx_b, b) = proposer(genesis, vec![]);
+        let (tx_c, c) = proposer(a.hash(), vec![b.hash()]);
+        let mut blocks = vec![tx_a.clone(), a.clone(), tx_b.clone(), b.clone(), tx_c.clone(), c.clone()];
+
+        // two voter chains vote for `a`, one for `b`, then all of them for `c`
+        for chain_num in 1..=num_voter_chains {
+            let level2 = if chain_num == 3 { b.hash() } else { a.hash() };
+            let mut parent = genesis_voter(chain_num).hash();
+            // followed by empty voters that make the votes deep
+            let mut ballots = vec![vec![genesis, level2], vec![c.hash()]];
+            ballots.resize(22, vec![]);
+            for (idx, votes) in ballots.into_iter().enumerate() {
+                let content = VoterContent { votes, parent_hash: parent, chain_num };
+                let nonce = chain_num * 100 + idx as u32;
+                let voter = Block::new(c.hash(), 100, nonce, H256::default(), vec![], Content::Voter(content), H160::default(), difficulty);
+                parent = voter.hash();
+                blocks.push(voter);
+            }
+        }
+
+        let ledger = |blocks: &[Block]| {
+            let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+            let blockchain = Arc::new(Mutex::new(Blockchain::new(num_voter_chains, &mempool)));
+            for block in blocks {
+                blockchain.lock().unwrap().insert(block);
             }
+            let utxo_state = Arc::new(Mutex::new(UtxoState::new()));
+            let mut ledger_manager = LedgerManager::new(&blockchain, &utxo_state, POLICY, ChainId::default(), None);
+            let leader_sequence = ledger_manager.get_confirmed_leader_sequence();
+            let tx_sequence: Vec<H256> = leader_sequence.iter()
+                .flat_map(|leader| ledger_manager.get_transaction_sequence(leader).1)
+                .flat_map(|(_, transactions)| transactions)
+                .map(|tx| tx.hash())
+                .collect();
+            (leader_sequence, tx_sequence)
+        };
+
+        let (leader_sequence, tx_sequence) = ledger(&blocks);
+        assert_eq!(leader_sequence, vec![a.hash(), c.hash()]);
+        let expected: Vec<H256> = [&tx_a, &tx_b, &tx_c].iter()
+            .flat_map(|block| match &block.content {
+                Content::Transaction(content) => content.transactions.iter().map(|tx| tx.hash()).collect(),
+                _ => vec![],
+            })
+            .collect();
+        assert_eq!(tx_sequence, expected);
+
+        // the other node gets every block in reverse, and in shuffled order
+        blocks.reverse();
+        assert_eq!(ledger(&blocks), (leader_sequence.clone(), tx_sequence.clone()));
+        let mut rng = rand::thread_rng();
+        for _ in 0..5 {
+            rand::seq::SliceRandom::shuffle(&mut blocks[..], &mut rng);
+            assert_eq!(ledger(&blocks), (leader_sequence.clone(), tx_sequence.clone()));
+        }
+    }
 
-            //check for validity
-            //if valid, update utxo_state and add to confirmed transactions
-            if locked_utxostate.is_tx_valid(tx){
-                locked_utxostate.update_state(tx);
-                self.ledger_manager_state.tx_confirmed.insert(tx.hash());
-                println!("Confirmed trans hash {} at {}", tx.hash(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros());
-                // Print UTXO state
-                // locked_utxostate.print();
+    #[test]
+    fn rollback_when_leader_loses_confirmation() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(1, &mempool)));
+        let difficulty = get_difficulty(1);
+
+        // `a` spends an output of `owner` with a fee of 1, `b` competes with it at level 2
+        let owner = key_pair::random();
+        let input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let mut utxo = UtxoState::default();
+        utxo.state_map.insert(input.clone(), UtxoOutput::to_address(address::address_from_public_key_ref(owner.public_key()), Amount::new(10)));
+        let utxo_state = Arc::new(Mutex::new(utxo));
+        let tx = Transaction { tx_input: vec![input.clone()], tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(9))] };
+        let signed_tx = SignedTransaction::signed_by(tx, &ChainId::default(), &owner);
+
+        let genesis = genesis_proposer().hash();
+        let coinbase = transaction::coinbase(H160::default(), block_reward(2).checked_add(Amount::new(1)).unwrap());
+        let transactions = vec![coinbase.clone(), signed_tx.clone()];
+        let tx_block = Block::new(genesis, 3, 3, H256::default(), vec![], Content::Transaction(TransactionContent { transactions }), H160::default(), difficulty);
+        let proposer = |transaction_refs: Vec<H256>, nonce: u32| {
+            let content = ProposerContent { parent_hash: genesis, transaction_refs, proposer_refs: vec![] };
+            Block::new(genesis, nonce as u128, nonce, H256::default(), vec![], Content::Proposer(content), H160::default(), difficulty)
+        };
+        let a = proposer(vec![tx_block.hash()], 1);
+        let b = proposer(vec![], 2);
+        // a branch of the voter chain voting for `vote`, made deep by empty voters
+        let voter_branch = |vote: H256, length: u32, nonce: u32| {
+            let mut parent = genesis_voter(1).hash();
+            (0..length).map(|idx| {
+                let votes = if idx == 0 { vec![genesis, vote] } else { vec![] };
+                let content = VoterContent { votes, parent_hash: parent, chain_num: 1 };
+                let voter = Block::new(a.hash(), 100, nonce + idx, H256::default(), vec![], Content::Voter(content), H160::default(), difficulty);
+                parent = voter.hash();
+                voter
+            }).collect::<Vec<Block>>()
+        };
+
+        let mut ledger_manager = LedgerManager::new(&blockchain, &utxo_state, POLICY, ChainId::default(), None);
+        let rollbacks = ledger_manager.subscribe();
+        {
+            let mut blockchain = blockchain.lock().unwrap();
+            for block in [tx_block.clone(), a.clone(), b.clone()].iter().chain(voter_branch(a.hash(), 20, 100).iter()) {
+                blockchain.insert(block);
             }
         }
-        drop(locked_utxostate);
+        let event = ledger_manager.events.try_recv().unwrap();
+        ledger_manager.handle_events(event);
+        assert_eq!(ledger_manager.ledger_manager_state.last_level_processed, 2);
+        assert!(ledger_manager.ledger_manager_state.tx_confirmed.contains(&signed_tx.tx.hash()));
+        assert!(!utxo_state.lock().unwrap().state_map.contains_key(&input));
+        let reward = UtxoInput { tx_hash: coinbase.tx.hash(), idx: 0 };
+        assert_eq!(Some(utxo_state.lock().unwrap().state_map[&reward].value), block_reward(2).checked_add(Amount::new(1)));
+
+        // a longer voter branch moves the vote to `b`
+        {
+            let mut blockchain = blockchain.lock().unwrap();
+            for block in voter_branch(b.hash(), 21, 200) {
+                blockchain.insert(&block);
+            }
+        }
+        let event = ledger_manager.events.try_recv().unwrap();
+        ledger_manager.handle_events(event);
+        assert_eq!(rollbacks.try_recv(), Ok(Rollback { level: 2, leaders: vec![a.hash()], transactions: vec![coinbase.tx.hash(), signed_tx.tx.hash()] }));
+        assert!(!ledger_manager.ledger_manager_state.tx_confirmed.contains(&signed_tx.tx.hash()));
+        let state_map = &utxo_state.lock().unwrap().state_map;
+        assert_eq!(state_map.len(), 1);
+        assert!(state_map.contains_key(&input));
+        // and the level is confirmed again, with its new leader
+        assert_eq!(ledger_manager.ledger_manager_state.undo_log.back().unwrap().leader, b.hash());
+        assert_eq!(ledger_manager.ledger_manager_state.last_level_processed, 2);
+    }
+
+    #[test]
+    fn checkpoint_of_another_chain_is_ignored() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(1, &mempool)));
+        let path = std::env::temp_dir().join(format!("ledger_resume_{}.ckpt", std::process::id()));
+        let resume = |ledger_state: LedgerManagerState| {
+            // an output only the checkpoint has
+            let marker = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+            let mut utxo = UtxoState::new();
+            utxo.state_map.insert(marker.clone(), UtxoOutput::to_address(address::generate_random_address(), Amount::new(1)));
+            checkpoint::write(&path, &Checkpoint { utxo_state: utxo, ledger_state }).unwrap();
+            let utxo_state = Arc::new(Mutex::new(UtxoState::new()));
+            let ledger_manager = LedgerManager::new(&blockchain, &utxo_state, POLICY, ChainId::default(), Some(path.clone()));
+            let restored = utxo_state.lock().unwrap().state_map.contains_key(&marker);
+            (ledger_manager.ledger_manager_state, restored)
+        };
+
+        // nothing beyond genesis processed yet, it fits any chain
+        let mut ledger_state = LedgerManagerState::new();
+        ledger_state.proposer_blocks_processed.insert(genesis_proposer().hash());
+        let (state, restored) = resume(ledger_state.clone());
+        assert!(restored);
+        assert_eq!(state.proposer_blocks_processed, ledger_state.proposer_blocks_processed);
+
+        // a confirmed level the blockchain does not have
+        let leader = generate_random_hash();
+        ledger_state.last_level_processed = 2;
+        ledger_state.undo_log.push_back(LevelUndo { level: 2, leader, proposers: vec![leader], transactions: vec![] });
+        let (state, restored) = resume(ledger_state.clone());
+        assert!(!restored);
+        assert_eq!(state.last_level_processed, 1);
+        assert!(state.undo_log.is_empty());
+
+        // a leader that is not a proposer of its level
+        ledger_state.last_level_processed = 1;
+        ledger_state.undo_log = iter::once(LevelUndo { level: 1, leader, proposers: vec![], transactions: vec![] }).collect();
+        let (state, restored) = resume(ledger_state);
+        assert!(!restored);
+        assert!(state.proposer_blocks_processed.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn coinbase_claims_reward_and_fees() {
+        let mempool = Arc::new(Mutex::new(TransactionMempool::new()));
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(1, &mempool)));
+        let owner = key_pair::random();
+        let owner_addr = address::address_from_public_key_ref(owner.public_key());
+        let inputs: Vec<UtxoInput> = (0..2).map(|_| UtxoInput { tx_hash: generate_random_hash(), idx: 0 }).collect();
+        let mut utxo = UtxoState::default();
+        for input in &inputs {
+            utxo.state_map.insert(input.clone(), UtxoOutput::to_address(owner_addr, Amount::new(10)));
+        }
+        let utxo_state = Arc::new(Mutex::new(utxo));
+        // each pays a fee of 2
+        let spend = |input: &UtxoInput| {
+            let tx = Transaction { tx_input: vec![input.clone()], tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(8))] };
+            SignedTransaction::signed_by(tx, &ChainId::default(), &owner)
+        };
+        let (first, second) = (spend(&inputs[0]), spend(&inputs[1]));
+        let reward = block_reward(2);
+        let greedy = transaction::coinbase(address::generate_random_address(), reward.checked_add(Amount::new(3)).unwrap());
+        let fair = transaction::coinbase(address::generate_random_address(), reward.checked_add(Amount::new(2)).unwrap());
+
+        let mut ledger_manager = LedgerManager::new(&blockchain, &utxo_state, POLICY, ChainId::default(), None);
+        let tx_blocks = vec![(reward, vec![greedy.clone(), first.clone()]), (reward, vec![fair.clone(), second.clone()])];
+        let undo = ledger_manager.confirm_transactions(&tx_blocks, 2);
+
+        // the greedy coinbase is left out, the fair one keeps its place in front of its block
+        let confirmed: Vec<H256> = undo.iter().map(|undo| undo.tx_hash).collect();
+        assert_eq!(confirmed, vec![first.tx.hash(), fair.tx.hash(), second.tx.hash()]);
+        let state_map = &utxo_state.lock().unwrap().state_map;
+        assert!(!state_map.contains_key(&UtxoInput { tx_hash: greedy.tx.hash(), idx: 0 }));
+        assert_eq!(Some(state_map[&UtxoInput { tx_hash: fair.tx.hash(), idx: 0 }].value), reward.checked_add(Amount::new(2)));
     }
 }
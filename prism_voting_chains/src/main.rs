@@ -5,9 +5,12 @@ extern crate hex_literal;
 pub mod api;
 pub mod block;
 pub mod blockchain;
+pub mod block_store;
+pub mod checkpoint;
 pub mod crypto;
 pub mod miner;
 pub mod network;
+pub mod script;
 pub mod transaction;
 pub mod mempool;
 pub mod tx_generator;
@@ -17,17 +20,22 @@ pub mod utxo;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, debug,info};
+use log::{error, debug,info, warn};
 use api::Server as ApiServer;
-use network::{server, worker};
+use network::{discovery, server, worker};
+use std::fs;
 use std::net;
+use std::path;
 use std::process;
 use std::thread;
 use std::time;
 use std::sync::{Arc, Mutex};
 use crate::crypto::hash::{self, H256, Hashable};
+use crate::crypto::address::{self, H160};
 use crate::block::{*};
+use crate::transaction::ChainId;
 use crate::utxo::{UtxoState};
+use crate::block_store::{BlockStore, FileBlockStore, MemoryBlockStore};
 use std::collections::HashSet;
 
 
@@ -41,9 +49,16 @@ fn main() {
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg target_peers: --("target-peers") [INT] default_value("8") "Sets the number of outgoing connections kept open to discovered peers")
+     (@arg ban_threshold: --("ban-threshold") [INT] default_value("100") "Sets the misbehavior score at which a peer is disconnected and banned")
+     (@arg ban_duration: --("ban-duration") [SECS] default_value("3600") "Sets how long a misbehaving peer stays banned")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
      (@arg voter_chains: --("voter-chains") [INT] default_value("40") "Sets the number of voter chains")
-     (@arg voter_depth_k: --("voter-depth-k") [INT] default_value("2") "Depth of votes before ledger manager can confirm")
+     (@arg beta: --beta [FRACTION] default_value("0.25") "Sets the fraction of mining power the confirmation rule assumes to be adversarial")
+     (@arg epsilon: --epsilon [PROB] default_value("0.001") "Sets the probability that a confirmed leader is reverted")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where blocks and ledger checkpoints are persisted across restarts")
+     (@arg payout_addr: --("payout-addr") [ADDR] "Sets the address, in hex, that block rewards of mined blocks are paid to")
+     (@arg network: --network [NAME] default_value("testnet") "Sets the name of the network, transactions signed for other networks are rejected")
     )
     .get_matches();
 
@@ -74,8 +89,30 @@ fn main() {
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
+    // parse the ban policy for misbehaving peers
+    let ban_threshold = matches
+        .value_of("ban_threshold")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban threshold: {}", e);
+            process::exit(1);
+        });
+    let ban_duration = matches
+        .value_of("ban_duration")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban duration: {}", e);
+            process::exit(1);
+        });
+    let ban_policy = server::BanPolicy {
+        threshold: ban_threshold,
+        duration: time::Duration::from_secs(ban_duration),
+    };
+
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, ban_policy).unwrap();
     server_ctx.start().unwrap();
 
     //INTMOD
@@ -90,29 +127,66 @@ fn main() {
 
     info!("voter chains {}", num_chains);
 
-    let voter_depth_k = matches
-    .value_of("voter_depth_k")
+    let beta = matches
+    .value_of("beta")
     .unwrap()
-    .parse::<u32>()
-    .unwrap_or_else(|e| {
-        error!("Error parsing voter depth k: {}", e);
+    .parse::<f64>()
+    .ok()
+    .filter(|beta| *beta > 0.0 && *beta < 0.5)
+    .unwrap_or_else(|| {
+        error!("Error parsing beta: expected a fraction between 0 and 0.5");
+        process::exit(1);
+    });
+
+    let epsilon = matches
+    .value_of("epsilon")
+    .unwrap()
+    .parse::<f64>()
+    .ok()
+    .filter(|epsilon| *epsilon > 0.0 && *epsilon < 1.0)
+    .unwrap_or_else(|| {
+        error!("Error parsing epsilon: expected a probability between 0 and 1");
         process::exit(1);
     });
+    let confirmation_policy = ledger_manager::ConfirmationPolicy { beta, epsilon };
 
     let utxo_state = Arc::new(Mutex::new(UtxoState::new()));
 
     // create mempool
     let mempool = Arc::new(Mutex::new(mempool::TransactionMempool::new()));
 
-    // create blockchain
-    let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::new(num_chains, &mempool)));
+    // create blockchain, replaying the block store if a data directory is given
+    let data_dir = matches.value_of("data_dir").map(path::PathBuf::from);
+    let block_store: Box<dyn BlockStore + Send> = match &data_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir).unwrap_or_else(|e| {
+                error!("Error creating data directory {}: {}", dir.display(), e);
+                process::exit(1);
+            });
+            let store = FileBlockStore::open(&dir.join("blocks.db")).unwrap_or_else(|e| {
+                error!("Error opening block store: {}", e);
+                process::exit(1);
+            });
+            Box::new(store)
+        }
+        None => Box::new(MemoryBlockStore::new()),
+    };
+    let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::with_store(num_chains, &mempool, block_store)));
+
+    // transactions are signed for this network, identified by its name and genesis
+    let network = matches.value_of("network").unwrap();
+    let chain_id = ChainId::new(network, &blockchain.lock().unwrap().genesis_hash);
+    info!("network {} chain id {}", network, chain_id);
 
     //create ledger_manager
-    let ledger_manager = ledger_manager::LedgerManager::new(
+    let mut ledger_manager = ledger_manager::LedgerManager::new(
         &blockchain,
         &utxo_state,
-        voter_depth_k,
+        confirmation_policy,
+        chain_id,
+        data_dir.as_ref().map(|dir| dir.join("ledger.ckpt")),
     );
+    let rollbacks = ledger_manager.subscribe();
     ledger_manager.start();
 
     // start the transaction generator
@@ -121,15 +195,28 @@ fn main() {
         &server,
         &mempool,
         &utxo_state,
+        chain_id,
     );
     txgen_ctx.start(); 
 
   
      // start the miner
+    let payout_addr = match matches.value_of("payout_addr") {
+        Some(addr) => addr.parse::<H160>().unwrap_or_else(|e| {
+            error!("Error parsing payout address: {}", e);
+            process::exit(1);
+        }),
+        None => {
+            let addr = address::generate_random_address();
+            warn!("No payout address given, block rewards go to the unspendable address {:?}", addr);
+            addr
+        }
+    };
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
         &mempool,
+        payout_addr,
     );
     miner_ctx.start();
 
@@ -148,6 +235,8 @@ fn main() {
         &server,
         &blockchain,
         &mempool,
+        &utxo_state,
+        chain_id,
     );
     worker_ctx.start();
 
@@ -159,6 +248,7 @@ fn main() {
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
+        let blockchain = Arc::clone(&blockchain);
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -169,9 +259,12 @@ fn main() {
                             break;
                         }
                     };
+                    server.add_addrs(vec![addr]);
                     match server.connect(addr) {
-                        Ok(_) => {
+                        Ok(peer) => {
                             info!("Connected to outgoing peer {}", &addr);
+                            // headers are requested once the peer accepted our version
+                            peer.send_version(worker::local_version(&blockchain, server.listen_addr(), chain_id));
                             break;
                         }
                         Err(e) => {
@@ -189,12 +282,25 @@ fn main() {
     }
 
 
+    // keep outgoing connections to peers learnt through address gossip
+    let target_peers = matches
+        .value_of("target_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing target peers: {}", e);
+            process::exit(1);
+        });
+    let discovery_ctx = discovery::new(&server, &blockchain, target_peers, chain_id);
+    discovery_ctx.start();
+
     // start the API server
     ApiServer::start(
         api_addr,
         &miner,
         &server,
         &txgen,
+        rollbacks,
     );
 
     loop {
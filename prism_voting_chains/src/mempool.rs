@@ -1,13 +1,13 @@
 use crate::crypto::hash::H256;
-use crate::transaction::{SignedTransaction,UtxoInput};
+use crate::transaction::{Amount, SignedTransaction, UtxoInput};
 use crate::crypto::hash::Hashable;
-use std::collections::VecDeque;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::cmp::Reverse;
 
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::cmp;
   
 #[derive(Debug)]
@@ -22,8 +22,8 @@ pub struct TransactionMempool{
     // to speed up duplicate removal
     // if a -> b, trans hash b is consuming utxoinput a
     // input_to_hash:HashMap<UtxoInput,H256>,
-    // storage_index to txhash, used for maintaining FIFO order
-    index_to_hash: BTreeMap<u32, H256>,
+    // priority to txhash, highest fee rate first and FIFO among equal fee rates
+    priority_to_hash: BTreeMap<Priority, H256>,
     utxoinputs: HashSet<H256>
 }
 
@@ -31,22 +31,35 @@ pub struct TransactionMempool{
 pub struct TxStore{  //used for storing a tx and its btree index
 
     pub signed_tx: SignedTransaction,
-    
-    //storage index for btree
-    index: u32,
+
+    //fee the tx pays, as far as the utxo state knew its inputs when it arrived
+    pub fee: Amount,
+
+    //key for btree
+    priority: Priority,
 
 }
+
+// Fee per kilobyte of serialized tx, then storage index
+type Priority = (Reverse<u64>, u32);
+
+// rounded down, saturating for fees beyond u64::MAX / 1000
+fn fee_rate(tx: &SignedTransaction, fee: Amount) -> u64 {
+    let size = bincode::serialize(tx).unwrap().len() as u64;
+    fee.as_u64().saturating_mul(1000) / size.max(1)
+}
   
 impl TransactionMempool{
     pub fn new() -> Self{
         TransactionMempool{ counter: 0,
             hash_to_txstore: HashMap::new(),
-            index_to_hash: BTreeMap::new(), 
+            priority_to_hash: BTreeMap::new(),
             utxoinputs: HashSet::new(),
         }
     }
 
-    pub fn insert(&mut self, tx: SignedTransaction) {
+    // `fee` orders the tx for mining, 0 if its inputs are not confirmed yet
+    pub fn insert(&mut self, tx: SignedTransaction, fee: Amount) {
             // println!("Size of mempool: {}", self.hash_to_txstore.len());
             println!("Received trans hash {} at {}", tx.hash(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros());
 
@@ -59,12 +72,13 @@ impl TransactionMempool{
             }
             
             let txstore = TxStore{
+                priority: (Reverse(fee_rate(&tx, fee)), self.counter),
                 signed_tx: tx,
-                index: self.counter,
+                fee,
             };
             self.counter += 1;
-            
-            self.index_to_hash.insert(txstore.index, hash);
+
+            self.priority_to_hash.insert(txstore.priority, hash);
             self.hash_to_txstore.insert(hash, txstore);
     }
 
@@ -88,7 +102,7 @@ impl TransactionMempool{
         let txstore = self.hash_to_txstore.remove(hash);
         match txstore {
             Some(txstore) => {
-                self.index_to_hash.remove(&txstore.index);
+                self.priority_to_hash.remove(&txstore.priority);
                 true
             }
             None => {
@@ -98,14 +112,57 @@ impl TransactionMempool{
         }
     }
 
+    // Up to `n` txs to mine, by decreasing fee rate
     pub fn get_transactions(&self, n: u32) -> Vec<SignedTransaction> {
         let count = cmp::min(n, self.len().try_into().unwrap());
-        self.index_to_hash.values().take(count as usize).map(|hash| self.get(hash).unwrap().signed_tx.clone()).collect()
+        self.priority_to_hash.values().take(count as usize).map(|hash| self.get(hash).unwrap().signed_tx.clone()).collect()
     }
     
+    // Fees recorded for `txs`, which a coinbase mining them may claim besides the block reward
+    pub fn fees(&self, txs: &[SignedTransaction]) -> Amount {
+        txs.iter()
+            .filter_map(|tx| self.get(&tx.hash()))
+            .fold(Amount::ZERO, |fees, txstore| fees.saturating_add(txstore.fee))
+    }
+
     pub fn len(&self) -> usize {
         self.hash_to_txstore.len()
     }
 
 }
   
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::transaction::generate_random_signed_transaction;
+
+    #[test]
+    fn highest_fee_rate_first() {
+        let mut mempool = TransactionMempool::new();
+        let txs: Vec<SignedTransaction> = (0..4).map(|_| generate_random_signed_transaction()).collect();
+        mempool.insert(txs[0].clone(), Amount::new(0));
+        mempool.insert(txs[1].clone(), Amount::new(5));
+        mempool.insert(txs[2].clone(), Amount::new(0));
+        mempool.insert(txs[3].clone(), Amount::new(50));
+
+        let hashes = |txs: Vec<SignedTransaction>| txs.iter().map(|tx| tx.hash()).collect::<Vec<H256>>();
+        // equal fee rates keep their arrival order
+        assert_eq!(hashes(mempool.get_transactions(4)), hashes(vec![txs[3].clone(), txs[1].clone(), txs[0].clone(), txs[2].clone()]));
+
+        assert!(mempool.delete(&txs[3].hash()));
+        assert_eq!(hashes(mempool.get_transactions(2)), hashes(vec![txs[1].clone(), txs[0].clone()]));
+    }
+
+    #[test]
+    fn fee_rate_rounds_down() {
+        let tx = generate_random_signed_transaction();
+        let size = bincode::serialize(&tx).unwrap().len() as u64;
+        assert!(size > 1, "transaction too small for the test");
+        assert_eq!(fee_rate(&tx, Amount::ZERO), 0);
+        assert_eq!(fee_rate(&tx, Amount::new(1)), 1000 / size);
+        assert_eq!(fee_rate(&tx, Amount::new(size)), 1000);
+        assert_eq!(fee_rate(&tx, Amount::new(size + 1)), 1000 + 1000 / size);
+        assert_eq!(fee_rate(&tx, Amount::MAX), u64::MAX / size);
+    }
+}
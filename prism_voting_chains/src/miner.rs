@@ -5,21 +5,30 @@ use crate::crypto::hash::{H256, Hashable};
 use crate::mempool::{TransactionMempool};
 use crate::crypto::merkle::MerkleTree;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::network::message::{Message};
-use log::info;
+use log::{debug, info};
 use bigint::uint::U256;
 use rand::Rng;
-use crate::transaction::{self, SignedTransaction};
+use crate::transaction::{self, Amount, SignedTransaction};
+use crate::crypto::address::H160;
+use crate::validation::block_reward;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
 use std::thread;
+use std::cmp;
 
 const TOTAL_SORTITION_WIDTH: u64 = std::u64::MAX;
 pub const PROPOSER_INDEX: u32 = 0;
 pub const FIRST_VOTER_IDX: u32 = 1;
+// number of proposer levels between two difficulty adjustments
+pub const DIFFICULTY_EPOCH: u32 = 20;
+// expected time between two proposer blocks, in microseconds
+pub const TARGET_PROPOSER_INTERVAL: u128 = 1_000_000;
+// an adjustment never changes the difficulty by more than this factor
+const MAX_ADJUSTMENT_FACTOR: u128 = 4;
 
 pub struct Superblock {
     pub header: Header,
@@ -32,28 +41,59 @@ impl Hashable for Superblock {
     }
 }
 
+// Transaction blocks take the superblock slot after the last voter chain
+pub fn transaction_index(num_voter_chains: u32) -> u32 {
+    FIRST_VOTER_IDX + num_voter_chains
+}
+
+// Difficulty of the first epochs, before any adjustment happened. Every slot of
+// the superblock, proposer, voters and transactions, is mined at the base rate.
 pub fn get_difficulty(num_voter_chains: u32) -> H256 {
     let base_difficulty: H256 = (hex!("0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
     let difficulty = U256::from_big_endian(base_difficulty.as_ref());
-    let adjusted_difficulty = difficulty * (num_voter_chains + 1).into();
+    let adjusted_difficulty = difficulty * (num_voter_chains + 2).into();
     let mut buffer: [u8; 32] = [0; 32];
     adjusted_difficulty.to_big_endian(&mut buffer);
     buffer.into()    
 }
 
+// Scale `difficulty` by how long the last epoch took compared to its target.
+// `actual_timespan` is the time, in microseconds, between the first and the last
+// proposer of an epoch of DIFFICULTY_EPOCH proposer intervals.
+pub fn retarget_difficulty(difficulty: H256, actual_timespan: u128) -> H256 {
+    let expected_timespan = TARGET_PROPOSER_INTERVAL * DIFFICULTY_EPOCH as u128;
+    let actual_timespan = cmp::min(
+        cmp::max(actual_timespan, expected_timespan / MAX_ADJUSTMENT_FACTOR),
+        expected_timespan * MAX_ADJUSTMENT_FACTOR,
+    );
+    // higher target means easier, so a slow epoch raises the target
+    let difficulty = U256::from_big_endian(difficulty.as_ref());
+    let (adjusted, overflow) = (difficulty / U256::from(expected_timespan as u64))
+        .overflowing_mul(U256::from(actual_timespan as u64));
+    let adjusted = if overflow { U256::max_value() } else { adjusted };
+    let mut buffer: [u8; 32] = [0; 32];
+    adjusted.to_big_endian(&mut buffer);
+    buffer.into()
+}
+
 
+// Slot of the superblock a hash below the difficulty mines: proposer and
+// transaction blocks get one slot width each, voter chains share the rest
 pub fn sortition_hash(hash: H256, difficulty: H256, num_voter_chains: u32) -> Option<u32> {
     let hash = U256::from_big_endian(hash.as_ref());
     let difficulty = U256::from_big_endian(difficulty.as_ref());
     let multiplier = difficulty / TOTAL_SORTITION_WIDTH.into();
     
-    let precise: f32 = (1.0 / (num_voter_chains + 1) as f32) * TOTAL_SORTITION_WIDTH as f32;
-    let proposer_sortition_width: u64 = precise.ceil() as u64;
-    let proposer_width = multiplier * proposer_sortition_width.into();
+    let precise: f32 = (1.0 / (num_voter_chains + 2) as f32) * TOTAL_SORTITION_WIDTH as f32;
+    let slot_sortition_width: u64 = precise.ceil() as u64;
+    let proposer_width = multiplier * slot_sortition_width.into();
+    let transaction_width = proposer_width + proposer_width;
     if hash < proposer_width {
         Some(PROPOSER_INDEX)
+    } else if hash < transaction_width {
+        Some(transaction_index(num_voter_chains))
     } else if hash < difficulty {
-        let voter_idx = (hash - proposer_width) % num_voter_chains.into();
+        let voter_idx = (hash - transaction_width) % num_voter_chains.into();
         Some(FIRST_VOTER_IDX + voter_idx.as_u32())
     } else {
         println!("Why you sortitioning something that is not less than difficulty?");
@@ -62,13 +102,13 @@ pub fn sortition_hash(hash: H256, difficulty: H256, num_voter_chains: u32) -> Op
 }
 
 enum ControlSignal {
-    Start(u64,u64), // the number controls the lambda of interval between block generation
+    Start(u64), // the number controls the lambda of interval between block generation
     Exit,
 }
 
 enum OperatingState {
     Paused,
-    Run(u64,u64),
+    Run(u64),
     ShutDown,
 }
 
@@ -79,6 +119,8 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool:Arc<Mutex<TransactionMempool>>,
+    // address the coinbases of mined transaction blocks pay to
+    payout_addr: H160,
 }
 
 #[derive(Clone)]
@@ -91,6 +133,7 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<TransactionMempool>>,  
+    payout_addr: H160,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -99,7 +142,8 @@ pub fn new(
         operating_state: OperatingState::Paused,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool)
+        mempool: Arc::clone(mempool),
+        payout_addr,
     };
 
     let handle = Handle {
@@ -114,9 +158,9 @@ impl Handle {
         self.control_chan.send(ControlSignal::Exit).unwrap();
     }
 
-    pub fn start(&self, lambda: u64,index: u64) {
+    pub fn start(&self, lambda: u64) {
         self.control_chan
-            .send(ControlSignal::Start(lambda,index))
+            .send(ControlSignal::Start(lambda))
             .unwrap();
     }
 
@@ -139,9 +183,9 @@ impl Context {
                 info!("Miner shutting down");
                 self.operating_state = OperatingState::ShutDown;
             }
-            ControlSignal::Start(i,j) => {
+            ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
-                self.operating_state = OperatingState::Run(i,j);
+                self.operating_state = OperatingState::Run(i);
             }
         }
     }
@@ -153,7 +197,6 @@ impl Context {
         // let mut num_proposer_blocks = 0;
 
         loop {
-            let mut index:u64 = 0;
             let mut time_i:u64 = 0;
 
             // check and react to control signals
@@ -178,8 +221,7 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
-            if let OperatingState::Run(i, j) = self.operating_state {
-                index = j;
+            if let OperatingState::Run(i) = self.operating_state {
                 time_i = i; 
                 
                 if time_i != 0 {
@@ -188,6 +230,7 @@ impl Context {
                 }
 
                 let mut txs: Vec<SignedTransaction> = Vec::new();
+                let mut fees = Amount::ZERO;
 
                 let locked_mempool = self.mempool.lock().unwrap();
                 // println!("miner: acquired mempool lock");
@@ -199,6 +242,7 @@ impl Context {
                     thread::sleep(interval);
                 } else {
                     txs = locked_mempool.get_transactions(5);
+                    fees = locked_mempool.fees(&txs);
                     // println!("length of txs in miner {}", txs.len());
                     drop(locked_mempool);
                     // println!("miner: dropped mempool lock");
@@ -230,6 +274,7 @@ impl Context {
                             thread::sleep(interval);
                         } else {
                             txs = locked_mempool.get_transactions(5);
+                            fees = locked_mempool.fees(&txs);
                             // println!("length of txs in miner {}", txs.len());
                             drop(locked_mempool);
                             // println!("miner: dropped mempool lock");
@@ -245,9 +290,11 @@ impl Context {
                     let mut contents: Vec<Content> = Vec::new();
     
                     //proposer
+                    let parent = locked_blockchain.get_proposer_tip();
+                    let difficulty = locked_blockchain.next_difficulty(&parent);
                     let proposer_content = ProposerContent {
-                        parent_hash: locked_blockchain.get_proposer_tip(),
-                        transactions: txs.clone(),
+                        parent_hash: parent,
+                        transaction_refs: locked_blockchain.get_unref_tx_blocks(),
                         proposer_refs: locked_blockchain.get_unref_proposers(),
                     };
                     contents.push(block::Content::Proposer(proposer_content));
@@ -262,6 +309,13 @@ impl Context {
                         };
                         contents.push(block::Content::Voter(tmp));
                     }
+
+                    // Transactions, after the coinbase claiming the reward of the level mined at
+                    // and their fees. It is forfeited if not all of the fees make it into the ledger.
+                    let reward = block_reward(locked_blockchain.proposer_chain[&parent].level + 1);
+                    let mut transactions = vec![transaction::coinbase(self.payout_addr, reward.saturating_add(fees))];
+                    transactions.extend(txs.iter().cloned());
+                    contents.push(block::Content::Transaction(TransactionContent { transactions }));
     
                     //drop(locked_blockchain);
     
@@ -269,11 +323,12 @@ impl Context {
     
                     let mut rng = rand::thread_rng();
                     let header = Header {
+                        parent,
                         nonce: rng.gen::<u32>(),
-                        difficulty: get_difficulty(num_voter_chains),
+                        difficulty,
                         timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros(),
                         merkle_root: content_mkl_tree.root(),
-                        miner_id: index as i32,   
+                        payout_addr: self.payout_addr,
                     };
     
                     let superblock = Superblock {
@@ -282,8 +337,6 @@ impl Context {
                     };
     
                     let block_hash = superblock.hash();
-                    // NOTE: Below works only for static difficulty
-                    let difficulty = get_difficulty(num_voter_chains);
     
                     if block_hash < difficulty {
                         
@@ -297,6 +350,9 @@ impl Context {
                             Content::Voter(content) => {
                                 println!("Mined a voter with hash {:?} at index: {} and height {}",block_hash,block_idx,locked_blockchain.voter_chains[(block_idx-1) as usize][&content.parent_hash].level+1);
                             }
+                            Content::Transaction(content) => {
+                                debug!("Mined a transaction block with hash {:?} at index: {} and {} transactions",block_hash,block_idx,content.transactions.len());
+                            }
                         }    
     
                         // Add header, relevant content and sortition proof
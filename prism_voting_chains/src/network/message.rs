@@ -1,7 +1,25 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
-use crate::block::Block;
-use crate::transaction::SignedTransaction;
+use std::net::SocketAddr;
+use crate::block::{Block, Header};
+use crate::transaction::{ChainId, SignedTransaction};
+
+// bumped whenever the wire format or consensus rules change incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// sent by both sides right after a connection is established
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    // address the sender accepts P2P connections on
+    pub listen_addr: SocketAddr,
+    pub genesis_hash: H256,
+    // network the sender signs and checks transactions for
+    pub chain_id: ChainId,
+    pub num_voter_chains: u32,
+    // level of the proposer tip followed by the level of every voter tip
+    pub best_heights: Vec<u32>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -13,4 +31,18 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    // one block locator per chain: proposer chain first, then voter chains 1..m
+    GetHeaders(Vec<Vec<H256>>),
+    // main chain headers per chain, in the same chain order as GetHeaders
+    Headers(Vec<Vec<Header>>),
+    Version(VersionInfo),
+    VerAck,
+    GetAddr,
+    Addr(Vec<SocketAddr>),
 }
+
+// maximum number of addresses sent in one Addr message
+pub const MAX_ADDR: usize = 1000;
+
+// maximum number of headers sent per chain in one Headers message
+pub const MAX_HEADERS: usize = 500;
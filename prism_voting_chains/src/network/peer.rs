@@ -4,7 +4,7 @@ use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 
 enum DecodeState {
     Length,
@@ -184,6 +184,7 @@ pub fn new(
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        handshake: Arc::new(Mutex::new(HandshakeState::default())),
     };
     let ctx = Context {
         addr,
@@ -211,13 +212,68 @@ pub struct Context {
     pub direction: Direction,
 }
 
+// Progress of the Version/VerAck handshake, shared by all handles of a peer
+#[derive(Default)]
+struct HandshakeState {
+    version_sent: bool,
+    // the Version the peer sent us, once we accepted it
+    peer_version: Option<message::VersionInfo>,
+    verack_received: bool,
+}
+
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
+    handshake: Arc<Mutex<HandshakeState>>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Send our Version to the peer, unless it has already been sent.
+    pub fn send_version(&self, version: message::VersionInfo) {
+        let mut handshake = self.handshake.lock().unwrap();
+        if !handshake.version_sent {
+            handshake.version_sent = true;
+            drop(handshake);
+            self.write(message::Message::Version(version));
+        }
+    }
+
+    /// Record the accepted Version of the peer. Returns true if this completes the handshake.
+    pub fn version_received(&self, version: message::VersionInfo) -> bool {
+        let mut handshake = self.handshake.lock().unwrap();
+        let completes = handshake.peer_version.is_none() && handshake.verack_received;
+        handshake.peer_version = Some(version);
+        completes
+    }
+
+    /// Record the VerAck of the peer. Returns true if this completes the handshake.
+    pub fn verack_received(&self) -> bool {
+        let mut handshake = self.handshake.lock().unwrap();
+        let completes = handshake.peer_version.is_some() && !handshake.verack_received;
+        handshake.verack_received = true;
+        completes
+    }
+
+    /// Whether we accepted the Version of the peer, i.e. may process its other messages.
+    pub fn is_accepted(&self) -> bool {
+        self.handshake.lock().unwrap().peer_version.is_some()
+    }
+
+    /// Whether both sides accepted each other, i.e. the peer may receive broadcasts.
+    pub fn is_ready(&self) -> bool {
+        let handshake = self.handshake.lock().unwrap();
+        handshake.peer_version.is_some() && handshake.verack_received
+    }
+
+    pub fn peer_version(&self) -> Option<message::VersionInfo> {
+        self.handshake.lock().unwrap().peer_version.clone()
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
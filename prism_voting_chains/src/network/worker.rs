@@ -4,18 +4,29 @@ use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::{Blockchain, InsertStatus};
 use crate::block::*;
-use crate::transaction::SignedTransaction;
+use crate::transaction::{ChainId, SignedTransaction};
 use crate::mempool::TransactionMempool;
+use crate::utxo::UtxoState;
 use crate::crypto::hash::{H256, Hashable};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 // use crate::validation::{BlockResult};
 use crossbeam::channel;
-use log::{info,debug, warn};
-use crate::validation::{BlockResult, check_pow_sortition_id, check_sortition_proof};
+use log::{debug, warn};
+use crate::validation::{check_content, check_tx_structure, check_pow_sortition_id, check_sortition_proof, check_header, ValidationError};
+use super::message::{MAX_ADDR, MAX_HEADERS, PROTOCOL_VERSION, VersionInfo};
 
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+// misbehavior scores added to a peer, it is banned once the total reaches the ban threshold
+// a block failing checks that do not depend on our view of the chain, like proof of work or signatures
+const INVALID_BLOCK_PENALTY: u32 = 50;
+// a block rejected by checks against our chain, which may be behind the peer's or on another branch
+const REJECTED_BLOCK_PENALTY: u32 = 10;
+const INVALID_HEADER_PENALTY: u32 = 50;
+const INVALID_TX_PENALTY: u32 = 10;
+const UNDECODABLE_MESSAGE_PENALTY: u32 = 50;
+
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
@@ -23,6 +34,8 @@ pub struct Context {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<TransactionMempool>>,
+    utxo_state: Arc<Mutex<UtxoState>>,
+    chain_id: ChainId,
 }
 
 pub fn new(
@@ -31,6 +44,8 @@ pub fn new(
     server: &ServerHandle,
     blockchain: &Arc<Mutex<Blockchain>>,
     mempool: &Arc<Mutex<TransactionMempool>>,
+    utxo_state: &Arc<Mutex<UtxoState>>,
+    chain_id: ChainId,
 ) -> Context {
     Context {
         msg_chan: msg_src,
@@ -38,6 +53,23 @@ pub fn new(
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
+        utxo_state: Arc::clone(utxo_state),
+        chain_id,
+    }
+}
+
+// Version describing the chains we are following
+pub fn local_version(blockchain: &Arc<Mutex<Blockchain>>, listen_addr: std::net::SocketAddr, chain_id: ChainId) -> VersionInfo {
+    let locked_blockchain = blockchain.lock().unwrap();
+    let mut best_heights = vec![locked_blockchain.proposer_depth];
+    best_heights.extend_from_slice(&locked_blockchain.voter_depths);
+    VersionInfo {
+        protocol_version: PROTOCOL_VERSION,
+        listen_addr,
+        genesis_hash: locked_blockchain.genesis_hash,
+        chain_id,
+        num_voter_chains: locked_blockchain.num_voter_chains,
+        best_heights,
     }
 }
 
@@ -53,13 +85,75 @@ impl Context {
         }
     }
 
+    // Once the handshake is done, ask for the peer's known addresses and
+    // download whatever it has beyond our tips
+    fn sync_with(&self, peer: &peer::Handle) {
+        peer.write(Message::GetAddr);
+
+        let peer_heights = match peer.peer_version() {
+            Some(version) => version.best_heights,
+            None => return,
+        };
+        let local_heights = local_version(&self.blockchain, self.server.listen_addr(), self.chain_id).best_heights;
+        let peer_is_ahead = peer_heights.iter().zip(local_heights.iter()).any(|(theirs, ours)| theirs > ours);
+        if peer_is_ahead {
+            let locators = self.blockchain.lock().unwrap().get_locators();
+            peer.write(Message::GetHeaders(locators));
+        }
+    }
+
     fn worker_loop(&self) {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(error) => {
+                    debug!("Undecodable message from {}: {}", peer.addr(), error);
+                    self.server.misbehaving(peer.addr(), UNDECODABLE_MESSAGE_PENALTY, &error.to_string());
+                    continue;
+                }
+            };
+
+            // nothing but the handshake is processed before we accepted the peer's version
+            match msg {
+                Message::Version(_) | Message::VerAck => {}
+                _ => if !peer.is_accepted() {
+                    warn!("Ignoring message from {} before handshake", peer.addr());
+                    continue;
+                }
+            }
             
             match msg {
+                Message::Version(version) => {
+                    let local = local_version(&self.blockchain, self.server.listen_addr(), self.chain_id);
+                    if version.protocol_version != PROTOCOL_VERSION
+                        || version.genesis_hash != local.genesis_hash
+                        || version.chain_id != local.chain_id
+                        || version.num_voter_chains != local.num_voter_chains {
+                        warn!("Peer {} is incompatible: protocol {} genesis {:?} chain id {} voter chains {}, dropping it",
+                              peer.addr(), version.protocol_version, version.genesis_hash, version.chain_id, version.num_voter_chains);
+                        self.server.disconnect(peer.addr());
+                        continue;
+                    }
+                    // remember where the peer accepts connections, for address gossip
+                    self.server.add_addrs(vec![version.listen_addr]);
+                    // accept the version before answering, so the requests the peer sends after our VerAck
+                    // are not dropped by another worker at the handshake gate
+                    let completes = peer.version_received(version);
+                    // answer an incoming peer with our own version
+                    peer.send_version(local);
+                    peer.write(Message::VerAck);
+                    if completes {
+                        self.sync_with(&peer);
+                    }
+                }
+                Message::VerAck => {
+                    if peer.verack_received() {
+                        self.sync_with(&peer);
+                    }
+                }
+
                 Message::Ping(nonce) => {
                     println!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -107,37 +201,47 @@ impl Context {
                     let mut locked_blockchain = self.blockchain.lock().unwrap();
                     let num_voter_chains = locked_blockchain.num_voter_chains;
                     let mut valid_block_hashes: Vec<H256> = Vec::new();
+                    // transaction blocks are not covered by header sync, they are fetched
+                    // once a proposer referencing them arrives
+                    let mut transaction_refs: Vec<H256> = Vec::new();
                     for block in vec_blocks {
                         let block_hash = block.hash();
                         if (!locked_blockchain.has_block(block_hash)) {
-                            // perform validation checks -- hash < difficulty, sortition id, sortition proof
-                            let result = check_pow_sortition_id(&block, num_voter_chains);
-                            match result {
-                                BlockResult::Fail => {
-                                    println!("Invalid block {:?} pow/sortition failed", block_hash);
-                                    continue;
+                            // perform validation checks -- hash < difficulty, sortition id, sortition proof, content
+                            let result = check_pow_sortition_id(&block, num_voter_chains)
+                                .and_then(|_| check_sortition_proof(&block, num_voter_chains))
+                                .and_then(|_| check_content(&block, &self.chain_id));
+                            if let Err(error) = result {
+                                debug!("Invalid block {:?}: {}", block_hash, error);
+                                if self.server.misbehaving(peer.addr(), INVALID_BLOCK_PENALTY, &error.to_string()) {
+                                    break;
                                 }
-                                BlockResult::Pass => {
-                                    // println!("pow/sortition passed {:?}", block_hash);
-                                    let result2 = check_sortition_proof(&block, num_voter_chains);
-                                    match result2 {
-                                        BlockResult::Fail => {
-                                            println!("Invalid block {:?} sortition proof failed", block_hash);
-                                            continue;
-                                        }
-                                        BlockResult::Pass => {
-                                            // println!("both checks passed {:?}", block_hash);
-                                        }
+                                continue;
+                            }
+                            if let Content::Proposer(content) = &block.content {
+                                transaction_refs.extend(content.transaction_refs.iter().cloned());
+                            }
+                            match locked_blockchain.insert(&block) {
+                                InsertStatus::Valid => valid_block_hashes.push(block_hash),
+                                InsertStatus::Orphan => {}
+                                // a block from the future may just be a clock ahead of ours
+                                InsertStatus::Invalid(ValidationError::TimestampTooNew(_)) => {}
+                                InsertStatus::Invalid(error) => {
+                                    if self.server.misbehaving(peer.addr(), REJECTED_BLOCK_PENALTY, &error.to_string()) {
+                                        break;
                                     }
                                 }
                             }
-                            let result = locked_blockchain.insert(&block);
-                            if let result = InsertStatus::Valid {
-                                valid_block_hashes.push(block_hash);
-                            }
                         }
                     } 
+                    let mut requested: HashSet<H256> = HashSet::new();
+                    let req_blocks: Vec<H256> = transaction_refs.into_iter()
+                        .filter(|hash| !locked_blockchain.has_block(*hash) && requested.insert(*hash))
+                        .collect();
                     drop(locked_blockchain);
+                    if !req_blocks.is_empty() {
+                        peer.write(Message::GetBlocks(req_blocks));
+                    }
                     if valid_block_hashes.len() > 0 {
                         self.server.broadcast(Message::NewBlockHashes(valid_block_hashes));
                     }
@@ -175,16 +279,88 @@ impl Context {
                     }
                 }
 
+                Message::GetHeaders(locators) => {
+                    let locked_blockchain = self.blockchain.lock().unwrap();
+                    let headers = locked_blockchain.get_headers_after(&locators, MAX_HEADERS);
+                    drop(locked_blockchain);
+                    if headers.iter().any(|chain_headers| chain_headers.len() > 0) {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+
+                Message::Headers(headers) => {
+                    let locked_blockchain = self.blockchain.lock().unwrap();
+                    let num_voter_chains = locked_blockchain.num_voter_chains;
+                    // proposer blocks are requested before voter blocks, since votes refer to them
+                    let mut req_blocks: Vec<H256> = Vec::new();
+                    let mut next_locators: Vec<Vec<H256>> = Vec::new();
+                    let mut more_headers = false;
+                    for (chain_idx, chain_headers) in headers.iter().enumerate() {
+                        let mut next_locator: Vec<H256> = Vec::new();
+                        if chain_idx as u32 > num_voter_chains {
+                            break;
+                        }
+                        for header in chain_headers {
+                            if let Err(error) = check_header(header, chain_idx as u32, num_voter_chains) {
+                                debug!("Invalid header {:?} for chain {}: {}", header.hash(), chain_idx, error);
+                                self.server.misbehaving(peer.addr(), INVALID_HEADER_PENALTY, &error.to_string());
+                                break;
+                            }
+                            let block_hash = header.hash();
+                            if !locked_blockchain.has_block(block_hash) {
+                                req_blocks.push(block_hash);
+                            }
+                            next_locator = vec![block_hash];
+                        }
+                        // a full batch means the peer has more headers for this chain
+                        if chain_headers.len() < MAX_HEADERS {
+                            next_locator.clear();
+                        } else if next_locator.len() > 0 {
+                            more_headers = true;
+                        }
+                        next_locators.push(next_locator);
+                    }
+                    drop(locked_blockchain);
+
+                    if req_blocks.len() > 0 {
+                        peer.write(Message::GetBlocks(req_blocks));
+                    }
+                    if more_headers {
+                        peer.write(Message::GetHeaders(next_locators));
+                    }
+                }
+
+                Message::GetAddr => {
+                    let mut addrs = self.server.get_addrs();
+                    addrs.truncate(MAX_ADDR);
+                    if addrs.len() > 0 {
+                        peer.write(Message::Addr(addrs));
+                    }
+                }
+
+                Message::Addr(mut addrs) => {
+                    addrs.truncate(MAX_ADDR);
+                    self.server.add_addrs(addrs);
+                }
+
                 Message::Transactions(vec_txs) => {
                     let mut locked_mempool = self.mempool.lock().unwrap();
+                    let locked_utxostate = self.utxo_state.lock().unwrap();
                     let mut new_tx_hashes: Vec<H256> = Vec::new();
                     for tx in vec_txs {
                         let tx_hash = tx.hash();
                         if (!locked_mempool.contains(&tx_hash)) {
-                            locked_mempool.insert(tx);
+                            if let Err(error) = check_tx_structure(&tx, &self.chain_id) {
+                                debug!("Invalid transaction {:?}: {}", tx_hash, error);
+                                self.server.misbehaving(peer.addr(), INVALID_TX_PENALTY, &error.to_string());
+                                continue;
+                            }
+                            let fee = locked_utxostate.fee(&tx);
+                            locked_mempool.insert(tx, fee);
                             new_tx_hashes.push(tx_hash);
                         }
                     }
+                    drop(locked_utxostate);
                     drop(locked_mempool);
                     if new_tx_hashes.len() > 0{
                         self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
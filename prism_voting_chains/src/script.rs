@@ -0,0 +1,215 @@
+use crate::crypto::address::{self, H160};
+use crate::crypto::hash::H256;
+use crate::transaction::{self, ChainId, SigHash, Transaction};
+
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+
+// limits that keep scripts and witnesses cheap to check
+pub const MAX_SCRIPT_DEPTH: usize = 4;
+pub const MAX_BRANCHES: usize = 16;
+pub const MAX_MULTISIG_KEYS: usize = 16;
+pub const MAX_WITNESS_SIGNATURES: usize = MAX_MULTISIG_KEYS;
+pub const MAX_PREIMAGES: usize = 4;
+pub const MAX_PREIMAGE_SIZE: usize = 64;
+
+// Condition an output puts on the input spending it. Scripts are plain data without
+// loops or jumps, every condition is checked once against what the input proves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockingScript {
+    // signed by the key with this address
+    PayToPubkeyHash(H160),
+    // signed by at least `required` of the keys with these addresses
+    Multisig { required: u32, addrs: Vec<H160> },
+    // spent at this ledger level or later
+    AbsoluteTimelock(u64),
+    // spent at least this many levels after the output was confirmed
+    RelativeTimelock(u64),
+    // the input reveals a preimage with this SHA256 hash
+    HashLock(H256),
+    // every condition holds
+    All(Vec<LockingScript>),
+    // at least one condition holds
+    Any(Vec<LockingScript>),
+}
+
+// What an input presents to satisfy the locking script of the output it spends
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Witness {
+    pub signatures: Vec<KeySignature>,
+    pub preimages: Vec<Vec<u8>>,
+}
+
+// Signature of the spending input, with the key that made it and the parts of the
+// transaction it covers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeySignature {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub sighash: SigHash,
+}
+
+// What an input proves, for a spend at `level` of an output confirmed at `confirmed_at`.
+// The signatures of the witness have to be verified beforehand.
+pub struct Spend {
+    signers: HashSet<H160>,
+    preimage_hashes: HashSet<H256>,
+    pub level: u64,
+    pub confirmed_at: u64,
+}
+
+impl LockingScript {
+    // The address of a pay-to-pubkey-hash script
+    pub fn address(&self) -> Option<H160> {
+        match self {
+            LockingScript::PayToPubkeyHash(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // Within the size limits, with multisig thresholds that can be met
+    pub fn is_well_formed(&self) -> bool {
+        self.is_well_formed_below(MAX_SCRIPT_DEPTH)
+    }
+
+    fn is_well_formed_below(&self, depth: usize) -> bool {
+        match self {
+            LockingScript::Multisig { required, addrs } =>
+                *required >= 1 && *required as usize <= addrs.len() && addrs.len() <= MAX_MULTISIG_KEYS,
+            LockingScript::All(scripts) | LockingScript::Any(scripts) =>
+                depth > 1 && !scripts.is_empty() && scripts.len() <= MAX_BRANCHES
+                    && scripts.iter().all(|script| script.is_well_formed_below(depth - 1)),
+            _ => true,
+        }
+    }
+
+    pub fn is_satisfied(&self, spend: &Spend) -> bool {
+        match self {
+            LockingScript::PayToPubkeyHash(addr) => spend.signers.contains(addr),
+            LockingScript::Multisig { required, addrs } =>
+                addrs.iter().collect::<HashSet<_>>().iter().filter(|addr| spend.signers.contains(addr)).count() >= *required as usize,
+            LockingScript::AbsoluteTimelock(level) => spend.level >= *level,
+            LockingScript::RelativeTimelock(delay) => spend.level >= spend.confirmed_at.saturating_add(*delay),
+            LockingScript::HashLock(hash) => spend.preimage_hashes.contains(hash),
+            LockingScript::All(scripts) => scripts.iter().all(|script| script.is_satisfied(spend)),
+            LockingScript::Any(scripts) => scripts.iter().any(|script| script.is_satisfied(spend)),
+        }
+    }
+}
+
+impl Witness {
+    // Witness for input `input_index` of `tx` signed with each of `keys` for the
+    // network `chain_id`, None if `sighash` does not apply to that input
+    pub fn signed_by(tx: &Transaction, chain_id: &ChainId, input_index: usize, sighash: SigHash, keys: &[&Ed25519KeyPair]) -> Option<Self> {
+        let signatures = keys.iter()
+            .map(|key| Some(KeySignature {
+                public_key: key.public_key().as_ref().to_vec(),
+                signature: transaction::sign(tx, chain_id, input_index, sighash, key)?.as_ref().to_vec(),
+                sighash,
+            }))
+            .collect::<Option<_>>()?;
+        Some(Witness { signatures, preimages: vec![] })
+    }
+
+    pub fn is_within_limits(&self) -> bool {
+        self.signatures.len() <= MAX_WITNESS_SIGNATURES
+            && self.preimages.len() <= MAX_PREIMAGES
+            && self.preimages.iter().all(|preimage| preimage.len() <= MAX_PREIMAGE_SIZE)
+    }
+
+    pub fn verify_signatures(&self, tx: &Transaction, chain_id: &ChainId, input_index: usize) -> bool {
+        self.signatures.iter().all(|key_signature| transaction::verify(
+            tx, chain_id, input_index, key_signature.sighash, &key_signature.signature, &key_signature.public_key))
+    }
+}
+
+impl Spend {
+    pub fn new(witness: &Witness, level: u64, confirmed_at: u64) -> Self {
+        Spend {
+            signers: witness.signatures.iter().map(|key_signature| address::address_from_public_key_vec_ref(&key_signature.public_key)).collect(),
+            preimage_hashes: witness.preimages.iter().map(|preimage| preimage_hash(preimage)).collect(),
+            level,
+            confirmed_at,
+        }
+    }
+}
+
+pub fn preimage_hash(preimage: &[u8]) -> H256 {
+    ring::digest::digest(&ring::digest::SHA256, preimage).into()
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+    use crate::transaction::generate_random_transaction;
+
+    fn addr(key: &Ed25519KeyPair) -> H160 {
+        address::address_from_public_key_ref(key.public_key())
+    }
+
+    #[test]
+    fn multisig_escrow() {
+        let (buyer, seller, arbiter) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let escrow = LockingScript::Multisig { required: 2, addrs: vec![addr(&buyer), addr(&seller), addr(&arbiter)] };
+        assert!(escrow.is_well_formed());
+        let tx = generate_random_transaction();
+        let spend = |keys: &[&Ed25519KeyPair]| Spend::new(&Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, keys).unwrap(), 1, 0);
+
+        assert!(escrow.is_satisfied(&spend(&[&buyer, &seller])));
+        assert!(escrow.is_satisfied(&spend(&[&arbiter, &seller])));
+        assert!(!escrow.is_satisfied(&spend(&[&seller])));
+        // the same key twice counts once
+        assert!(!escrow.is_satisfied(&spend(&[&seller, &seller])));
+        assert!(!escrow.is_satisfied(&spend(&[&seller, &key_pair::random()])));
+
+        assert!(!LockingScript::Multisig { required: 0, addrs: vec![addr(&buyer)] }.is_well_formed());
+        assert!(!LockingScript::Multisig { required: 2, addrs: vec![addr(&buyer)] }.is_well_formed());
+    }
+
+    #[test]
+    fn hash_time_locked_channel() {
+        // `payee` takes the funds by revealing the secret, `payer` gets them back 10 levels later
+        let (payer, payee) = (key_pair::random(), key_pair::random());
+        let secret = b"channel secret".to_vec();
+        let htlc = LockingScript::Any(vec![
+            LockingScript::All(vec![LockingScript::HashLock(preimage_hash(&secret)), LockingScript::PayToPubkeyHash(addr(&payee))]),
+            LockingScript::All(vec![LockingScript::RelativeTimelock(10), LockingScript::PayToPubkeyHash(addr(&payer))]),
+        ]);
+        assert!(htlc.is_well_formed());
+        let tx = generate_random_transaction();
+
+        let mut claim = Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, &[&payee]).unwrap();
+        assert!(!htlc.is_satisfied(&Spend::new(&claim, 5, 5)));
+        claim.preimages.push(secret);
+        assert!(htlc.is_satisfied(&Spend::new(&claim, 5, 5)));
+
+        let refund = Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, &[&payer]).unwrap();
+        assert!(!htlc.is_satisfied(&Spend::new(&refund, 14, 5)));
+        assert!(htlc.is_satisfied(&Spend::new(&refund, 15, 5)));
+
+        let absolute = LockingScript::All(vec![LockingScript::AbsoluteTimelock(15), LockingScript::PayToPubkeyHash(addr(&payer))]);
+        assert!(!absolute.is_satisfied(&Spend::new(&refund, 14, 0)));
+        assert!(absolute.is_satisfied(&Spend::new(&refund, 15, 0)));
+    }
+
+    #[test]
+    fn limits() {
+        let mut script = LockingScript::HashLock(H256::default());
+        for _ in 1..MAX_SCRIPT_DEPTH {
+            script = LockingScript::All(vec![script]);
+        }
+        assert!(script.is_well_formed());
+        assert!(!LockingScript::Any(vec![script]).is_well_formed());
+        assert!(!LockingScript::All(vec![]).is_well_formed());
+
+        let tx = generate_random_transaction();
+        let mut witness = Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, &[&key_pair::random()]).unwrap();
+        assert!(witness.is_within_limits() && witness.verify_signatures(&tx, &ChainId::default(), 0));
+        witness.signatures[0].signature[0] ^= 1;
+        assert!(!witness.verify_signatures(&tx, &ChainId::default(), 0));
+        witness.preimages.push(vec![0; MAX_PREIMAGE_SIZE + 1]);
+        assert!(!witness.is_within_limits());
+    }
+}
@@ -2,23 +2,66 @@ extern crate bincode;
 extern crate serde;
 
 use serde::{Serialize,Deserialize};
-use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair};
+use std::fmt;
+use ring::signature::{self,Ed25519KeyPair, Signature};
 
 use crate::crypto::key_pair;
 use crate::crypto::hash::{self, H256, Hashable};
 use crate::crypto::address::{self, H160};
+use crate::script::{LockingScript, Witness};
 
 
 #[derive(Serialize, Deserialize, Debug, Default,Clone, Eq, PartialEq, Hash)]
 pub struct UtxoInput{
   pub tx_hash: H256,
-  pub idx: u8,  
+  pub idx: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default,Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UtxoOutput{
-  pub receipient_addr: H160,
-  pub value: u32,
+  pub script: LockingScript,
+  pub value: Amount,
+}
+
+/// Value held by an output. Arithmetic on amounts is checked, a sum that does not
+/// fit in 64 bits is an error instead of wrapping around.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn new(value: u64) -> Self {
+        Amount(value)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    /// Sum of `amounts`, None if it overflows
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default,Clone)]
@@ -30,8 +73,101 @@ pub struct Transaction {
 #[derive(Serialize, Deserialize, Debug, Default,Clone)]
 pub struct SignedTransaction {
   pub tx: Transaction,
-  pub signature: Vec<u8>, 
-  pub public_key: Vec<u8>,
+  // one per input, in input order
+  pub witnesses: Vec<Witness>,
+}
+
+// Index of the single input of a coinbase transaction. The input spends nothing,
+// its hash is an extra nonce that keeps the hashes of coinbases unique.
+pub const COINBASE_IDX: u32 = u32::MAX;
+
+impl Transaction {
+    /// Whether this is a coinbase, minting the block reward instead of spending outputs
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_input.len() == 1 && self.tx_input[0].idx == COINBASE_IDX
+    }
+
+    /// Total value of the outputs, None if it overflows
+    pub fn output_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.tx_output.iter().map(|output| output.value))
+    }
+}
+
+impl UtxoOutput {
+    /// Output paying `value` to `addr`
+    pub fn to_address(addr: H160, value: Amount) -> Self {
+        UtxoOutput{script: LockingScript::PayToPubkeyHash(addr), value}
+    }
+}
+
+impl SignedTransaction {
+    /// Transaction whose inputs all spend pay-to-pubkey-hash outputs of `key`, each
+    /// signed with `SigHash::ALL` for the network `chain_id`
+    pub fn signed_by(tx: Transaction, chain_id: &ChainId, key: &Ed25519KeyPair) -> Self {
+        let witnesses = (0..tx.tx_input.len())
+            .map(|input_index| Witness::signed_by(&tx, chain_id, input_index, SigHash::ALL, &[key]).unwrap())
+            .collect();
+        SignedTransaction{tx, witnesses}
+    }
+}
+
+/// Network a transaction is signed for. Every signature commits to it, so that a
+/// transaction signed for one network is rejected by all others, even where the same
+/// keys hold coins.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChainId(H256);
+
+impl ChainId {
+    /// Chain id of the network named `network` that starts from `genesis_hash`
+    pub fn new(network: &str, genesis_hash: &H256) -> Self {
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        context.update(network.as_bytes());
+        context.update(genesis_hash.as_ref());
+        ChainId(context.finish().into())
+    }
+}
+
+impl fmt::Display for ChainId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Outputs a signature commits to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignedOutputs {
+    /// every output
+    All,
+    /// only the output at the index of the signed input
+    Single,
+}
+
+/// Parts of a transaction a signature commits to. A signature always commits to the
+/// input it signs, with `anyone_can_pay` it leaves the other inputs open so that
+/// others can add theirs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigHash {
+    pub outputs: SignedOutputs,
+    pub anyone_can_pay: bool,
+}
+
+impl SigHash {
+    pub const ALL: SigHash = SigHash{outputs: SignedOutputs::All, anyone_can_pay: false};
+    pub const SINGLE: SigHash = SigHash{outputs: SignedOutputs::Single, anyone_can_pay: false};
+    pub const ALL_ANYONE_CAN_PAY: SigHash = SigHash{outputs: SignedOutputs::All, anyone_can_pay: true};
+    pub const SINGLE_ANYONE_CAN_PAY: SigHash = SigHash{outputs: SignedOutputs::Single, anyone_can_pay: true};
+}
+
+/// What the signature of one input covers, see `signing_digest`
+#[derive(Serialize)]
+struct SigningData<'a> {
+    chain_id: ChainId,
+    sighash: SigHash,
+    /// position of the signed input, left out with `anyone_can_pay` since the
+    /// inputs added by others move it
+    input_index: Option<u32>,
+    inputs: &'a [UtxoInput],
+    outputs: &'a [UtxoOutput],
 }
 
 impl Hashable for Transaction {
@@ -57,13 +193,30 @@ impl Hashable for UtxoInput {
 }
 
 
-/// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    //let mut mess = [&t.input[..],&t.output[..]].concat();
-    let encoded: Vec<u8> = bincode::serialize(&t).unwrap();
-    //let merged : Vec<_> =mess.iter().flat_map(|s.as_mut()| s.iter()).collect();
-    let sig = key.sign(&encoded[..]);
-    sig
+/// Digest the signature of input `input_index` signs under `sighash`: the network,
+/// the sighash itself, the signed input or without `anyone_can_pay` every input, and
+/// the outputs picked by `sighash.outputs`. None if there is no such input, or no
+/// output at the same index for `SignedOutputs::Single`.
+pub fn signing_digest(t: &Transaction, chain_id: &ChainId, input_index: usize, sighash: SigHash) -> Option<H256> {
+    let signed_input = t.tx_input.get(input_index..=input_index)?;
+    let data = SigningData {
+        chain_id: *chain_id,
+        sighash,
+        input_index: if sighash.anyone_can_pay { None } else { Some(input_index as u32) },
+        inputs: if sighash.anyone_can_pay { signed_input } else { &t.tx_input[..] },
+        outputs: match sighash.outputs {
+            SignedOutputs::All => &t.tx_output[..],
+            SignedOutputs::Single => t.tx_output.get(input_index..=input_index)?,
+        },
+    };
+    let encoded: Vec<u8> = bincode::serialize(&data).unwrap();
+    Some(ring::digest::digest(&ring::digest::SHA256, &encoded[..]).into())
+}
+
+/// Create digital signature of input `input_index` of a transaction for the network `chain_id`
+pub fn sign(t: &Transaction, chain_id: &ChainId, input_index: usize, sighash: SigHash, key: &Ed25519KeyPair) -> Option<Signature> {
+    let digest = signing_digest(t, chain_id, input_index, sighash)?;
+    Some(key.sign(digest.as_ref()))
 }
 
 /*
@@ -76,23 +229,36 @@ pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicK
     peer_public_key.verify(&encoded[..],signature.as_ref()).is_ok()
 }*/
 
-pub fn verify(t: &Transaction, signature_bytes: &Vec<u8>, public_key_bytes: &Vec<u8>) -> bool {
-    //let mut mess = [&t.input[..],&t.output[..]].concat();
-    let encoded: Vec<u8> = bincode::serialize(&t).unwrap();
+/// Verify the signature of input `input_index` on the network `chain_id`, whether its
+/// key owns the output the input spends is up to the locking script of that output
+pub fn verify(t: &Transaction, chain_id: &ChainId, input_index: usize, sighash: SigHash, signature_bytes: &[u8], public_key_bytes: &[u8]) -> bool {
+    let digest = match signing_digest(t, chain_id, input_index, sighash) {
+        Some(digest) => digest,
+        None => return false,
+    };
     let peer_public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key_bytes);
-    peer_public_key.verify(&encoded[..],signature_bytes).is_ok()
+    peer_public_key.verify(digest.as_ref(),signature_bytes).is_ok()
+}
+
+/// Coinbase paying `value` to the miner's payout address. It carries no signature,
+/// the block it is mined in authorizes it.
+pub fn coinbase(payout_addr: H160, value: Amount) -> SignedTransaction {
+    let input = vec![UtxoInput{tx_hash: hash::generate_random_hash(), idx: COINBASE_IDX}];
+    let output = vec![UtxoOutput::to_address(payout_addr, value)];
+    SignedTransaction{tx: Transaction{tx_input: input, tx_output: output},
+                      witnesses: vec![Witness::default()]}
 }
 
 pub fn generate_random_transaction() -> Transaction {
     let input = vec![UtxoInput{tx_hash: hash::generate_random_hash(), idx: 0}];
-    let output = vec![UtxoOutput{receipient_addr: address::generate_random_address(), value: 0}];
+    let output = vec![UtxoOutput::to_address(address::generate_random_address(), Amount::ZERO)];
     
     Transaction{tx_input: input, tx_output: output}
 }
 
 pub fn generate_genesis_transaction() -> Transaction {
     let input = vec![UtxoInput{tx_hash: H256::from([0;32]), idx: 0}];
-    let output = vec![UtxoOutput{receipient_addr: H160::from([0;20]), value: 0}];
+    let output = vec![UtxoOutput::to_address(H160::from([0;20]), Amount::ZERO)];
     
     Transaction{tx_input: input, tx_output: output}
 }
@@ -100,9 +266,7 @@ pub fn generate_genesis_transaction() -> Transaction {
 pub fn generate_random_signed_transaction() -> SignedTransaction {
     let t = generate_random_transaction();
     let key = key_pair::random();
-    let sig = sign(&t, &key);
-    let signed_tx = SignedTransaction{tx:t,signature:sig.as_ref().to_vec(),public_key:key.public_key().as_ref().to_vec()};
-    signed_tx
+    SignedTransaction::signed_by(t, &ChainId::default(), &key)
 }
 
 
@@ -111,21 +275,100 @@ pub fn generate_genesis_signed_transaction() -> SignedTransaction {
 
     let t = generate_genesis_transaction();
     let key = Ed25519KeyPair::from_pkcs8([48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 187, 131, 74, 161, 134, 11, 240, 6, 188, 109, 18, 108, 124, 219, 167, 164, 215, 125, 168, 79, 204, 194, 232, 91, 58, 186, 181, 230, 212, 78, 163, 28, 161, 35, 3, 33, 0, 233, 72, 146, 218, 220, 235, 17, 123, 202, 112, 119, 63, 134, 105, 134, 71, 34, 185, 71, 193, 59, 66, 43, 137, 50, 194, 120, 234, 97, 132, 235, 159].as_ref().into()).unwrap();
-    let sig = sign(&t, &key);
-    let signed_tx = SignedTransaction{tx:t,signature:sig.as_ref().to_vec(),public_key:key.public_key().as_ref().to_vec()};
-    signed_tx
+    SignedTransaction::signed_by(t, &ChainId::default(), &key)
 }
 
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use super::*;
     use crate::crypto::key_pair;
+    use ring::signature::KeyPair;
    
     #[test]
     fn sign_verify() {
         let t = generate_random_transaction();
         let key = key_pair::random();
-        let signature = sign(&t, &key);
-        assert!(verify(&t, &signature.as_ref().to_vec(), &key.public_key().as_ref().to_vec()));
+        let chain_id = ChainId::default();
+        let signature = sign(&t, &chain_id, 0, SigHash::ALL, &key).unwrap();
+        assert!(verify(&t, &chain_id, 0, SigHash::ALL, signature.as_ref(), key.public_key().as_ref()));
+    }
+
+    #[test]
+    fn sighash_modes() {
+        let key = key_pair::random();
+        let output = |value: u64| UtxoOutput::to_address(address::generate_random_address(), Amount::new(value));
+        let input = || UtxoInput{tx_hash: hash::generate_random_hash(), idx: 0};
+        let tx = Transaction{tx_input: vec![input(), input()], tx_output: vec![output(1), output(2)]};
+        let chain_id = ChainId::default();
+        let signs = |t: &Transaction, input_index: usize, sighash: SigHash| {
+            let signature = sign(&tx, &chain_id, 1, sighash, &key).unwrap();
+            verify(t, &chain_id, input_index, sighash, signature.as_ref(), key.public_key().as_ref())
+        };
+
+        //ALL commits to everything, including the position of the input
+        assert!(signs(&tx, 1, SigHash::ALL));
+        assert!(!signs(&tx, 0, SigHash::ALL));
+        let mut more_outputs = tx.clone();
+        more_outputs.tx_output.push(output(3));
+        assert!(!signs(&more_outputs, 1, SigHash::ALL));
+
+        //SINGLE only commits to the output at the same index
+        assert!(signs(&more_outputs, 1, SigHash::SINGLE));
+        let mut other_output = tx.clone();
+        other_output.tx_output[1] = output(3);
+        assert!(!signs(&other_output, 1, SigHash::SINGLE));
+        let mut first_output_changed = tx.clone();
+        first_output_changed.tx_output[0] = output(3);
+        assert!(signs(&first_output_changed, 1, SigHash::SINGLE));
+        assert!(sign(&tx, &chain_id, 2, SigHash::SINGLE, &key).is_none());
+        let mut missing_output = tx.clone();
+        missing_output.tx_output.pop();
+        assert!(!signs(&missing_output, 1, SigHash::SINGLE));
+
+        //ANYONE_CAN_PAY lets others add and reorder inputs, but not change the signed one
+        let mut crowdfunded = tx.clone();
+        crowdfunded.tx_input.swap(0, 1);
+        crowdfunded.tx_input.push(input());
+        assert!(signs(&crowdfunded, 0, SigHash::ALL_ANYONE_CAN_PAY));
+        assert!(!signs(&crowdfunded, 0, SigHash::ALL));
+        crowdfunded.tx_input[0].idx = 1;
+        assert!(!signs(&crowdfunded, 0, SigHash::ALL_ANYONE_CAN_PAY));
+        assert!(signs(&tx, 1, SigHash::SINGLE_ANYONE_CAN_PAY));
+    }
+
+    #[test]
+    fn replay_across_networks() {
+        let genesis_hash = hash::generate_random_hash();
+        let testnet = ChainId::new("testnet", &genesis_hash);
+        let devnet = ChainId::new("devnet", &genesis_hash);
+        assert_ne!(testnet, devnet);
+        assert_ne!(testnet, ChainId::new("testnet", &hash::generate_random_hash()));
+        assert_eq!(testnet, ChainId::new("testnet", &genesis_hash));
+
+        let t = generate_random_transaction();
+        let key = key_pair::random();
+        for sighash in [SigHash::ALL, SigHash::SINGLE, SigHash::ALL_ANYONE_CAN_PAY, SigHash::SINGLE_ANYONE_CAN_PAY].iter() {
+            let signature = sign(&t, &testnet, 0, *sighash, &key).unwrap();
+            assert!(verify(&t, &testnet, 0, *sighash, signature.as_ref(), key.public_key().as_ref()));
+            assert!(!verify(&t, &devnet, 0, *sighash, signature.as_ref(), key.public_key().as_ref()));
+        }
+    }
+
+    #[test]
+    fn amount_arithmetic() {
+        assert_eq!(Amount::new(3).checked_add(Amount::new(4)), Some(Amount::new(7)));
+        assert_eq!(Amount::MAX.checked_add(Amount::ZERO), Some(Amount::MAX));
+        assert_eq!(Amount::MAX.checked_add(Amount::new(1)), None);
+        assert_eq!(Amount::new(3).checked_sub(Amount::new(3)), Some(Amount::ZERO));
+        assert_eq!(Amount::new(3).checked_sub(Amount::new(4)), None);
+        assert_eq!(Amount::MAX.saturating_add(Amount::new(1)), Amount::MAX);
+
+        assert_eq!(Amount::checked_sum(vec![]), Some(Amount::ZERO));
+        assert_eq!(Amount::checked_sum(vec![Amount::new(u64::MAX - 1), Amount::new(1)]), Some(Amount::MAX));
+        assert_eq!(Amount::checked_sum(vec![Amount::MAX, Amount::new(1), Amount::ZERO]), None);
+
+        let output = |value: u64| UtxoOutput::to_address(H160::default(), Amount::new(value));
+        let tx = Transaction { tx_input: vec![], tx_output: vec![output(1 << 63), output(1 << 63)] };
+        assert_eq!(tx.output_value(), None);
     }
 }
@@ -9,13 +9,14 @@ use crate::network::message::Message;
 use log::{debug,info};
 use rand::Rng;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair};
+use ring::signature::{self, KeyPair};
 use crate::mempool::TransactionMempool;
 use crate::crypto::key_pair;
 use crate::crypto::address::{self,*};
 use std::borrow::Borrow;
 use std::collections::{HashSet, HashMap};
 use crate::utxo::{UtxoState};
+use crate::script::LockingScript;
 
 
 use rand::seq::SliceRandom;
@@ -45,6 +46,7 @@ pub struct Context {
     server: ServerHandle,
     mempool: Arc<Mutex<TransactionMempool>>,
     utxo_state: Arc<Mutex<UtxoState>>,
+    chain_id: ChainId,
 }
 
 #[derive(Clone)]
@@ -57,6 +59,7 @@ pub fn new(
     server: &ServerHandle,
     mempool: &Arc<Mutex<TransactionMempool>>,
     utxo_state: &Arc<Mutex<UtxoState>>,
+    chain_id: ChainId,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
 
@@ -66,6 +69,7 @@ pub fn new(
         server: server.clone(),
         mempool: Arc::clone(mempool),
         utxo_state: Arc::clone(utxo_state),
+        chain_id,
     };
 
     let handle = Handle {
@@ -205,9 +209,11 @@ impl Context {
                     continue;
                 }
 
-                if !responsible_addresses.iter().any(|&i| i==output.receipient_addr) {
-                    continue;
-                }
+                // only pay-to-pubkey-hash outputs of our keys can be spent
+                let old_receipient = match output.script.address() {
+                    Some(addr) if responsible_addresses.contains(&addr) => addr,
+                    _ => continue,
+                };
 
                 let mut vec_input:Vec<UtxoInput> = vec![]; 
                 let mut vec_output:Vec<UtxoOutput> = vec![];
@@ -216,7 +222,7 @@ impl Context {
 
                 let mut new_output = output.clone();
                 let new_receipient = *address_vec.choose(&mut rand::thread_rng()).unwrap();
-                new_output.receipient_addr = new_receipient;
+                new_output.script = LockingScript::PayToPubkeyHash(new_receipient);
 
                 vec_output.push(new_output);
 
@@ -227,43 +233,31 @@ impl Context {
 
                 // assign dummy values for now, this is changed inside match scope
                 // Rust does not allow changing the data type of a variable
-                let mut signature = sign(&raw_tx, &key1).as_ref().to_vec();
-                let mut public_key = key1.public_key().as_ref().to_vec();
+                let mut key = &key1;
 
-                let old_receipient = output.receipient_addr;
                 if (old_receipient == address1) {
-                    signature = sign(&raw_tx, &key1).as_ref().to_vec();
-                    public_key = key1.public_key().as_ref().to_vec();
+                    key = &key1;
                     // println!("Sending address1's coin");
                 } else if (old_receipient == address2) {
-                    signature = sign(&raw_tx, &key2).as_ref().to_vec();
-                    public_key = key2.public_key().as_ref().to_vec();
+                    key = &key2;
                     // println!("Sending address2's coin");
                 } else if (old_receipient == address3) {
-                    signature = sign(&raw_tx, &key3).as_ref().to_vec();
-                    public_key = key3.public_key().as_ref().to_vec();
+                    key = &key3;
                     // println!("Sending address3's coin");
                 } else if (old_receipient == address4) {
-                    signature = sign(&raw_tx, &key4).as_ref().to_vec();
-                    public_key = key4.public_key().as_ref().to_vec();
+                    key = &key4;
                     // println!("Sending address4's coin");
                 } else if (old_receipient == address5) {
-                    signature = sign(&raw_tx, &key5).as_ref().to_vec();
-                    public_key = key5.public_key().as_ref().to_vec();
+                    key = &key5;
                     // println!("Sending address5's coin");
                 } else if (old_receipient == address6) {
-                    signature = sign(&raw_tx, &key6).as_ref().to_vec();
-                    public_key = key6.public_key().as_ref().to_vec();
+                    key = &key6;
                     // println!("Sending address6's coin");
                 } else {
                     println!("I am only aware of six addresses, I don't know you!!!");
                 }
 
-                let signed_tx = SignedTransaction {
-                    tx: raw_tx,
-                    signature: signature,
-                    public_key: public_key,
-                };
+                let signed_tx = SignedTransaction::signed_by(raw_tx, &self.chain_id, key);
 
                 if locked_mempool.contains(&signed_tx.hash()){
                     continue;
@@ -272,7 +266,8 @@ impl Context {
                     if tx_buffer.len() > 5 {
                         break;
                     }
-                    locked_mempool.insert(signed_tx);
+                    let fee = locked_utxostate.fee(&signed_tx);
+                    locked_mempool.insert(signed_tx, fee);
                 }
                 
             }
@@ -1,15 +1,28 @@
-use crate::transaction::{self, UtxoInput, UtxoOutput, SignedTransaction};
+use crate::transaction::{Amount, ChainId, UtxoInput, UtxoOutput, SignedTransaction};
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::address::{self, H160};
-use ring::signature::{self,Ed25519KeyPair, Signature, KeyPair};
+use crate::script::Spend;
+use crate::validation::{check_signature, ValidationError, COINBASE_MATURITY};
+use ring::signature::{self, KeyPair};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use serde::{Serialize, Deserialize};
 
-use log::debug;
-
-#[derive(Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct UtxoState{
     pub state_map: HashMap<UtxoInput, UtxoOutput>,  
+    // ledger level each confirmed coinbase was confirmed at, for its maturity
+    pub coinbase_levels: HashMap<H256, u32>,
+    // ledger level each confirmed transaction was confirmed at, for relative timelocks
+    pub confirmed_levels: HashMap<H256, u32>,
+}
+
+// Outputs a confirmed transaction spent and created, enough to take it back
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxUndo {
+    pub tx_hash: H256,
+    pub spent: Vec<(UtxoInput, UtxoOutput)>,
+    pub created: Vec<UtxoInput>,
 }
 
 pub fn perform_ico() -> HashMap<UtxoInput, UtxoOutput> {
@@ -45,14 +58,14 @@ pub fn perform_ico() -> HashMap<UtxoInput, UtxoOutput> {
     let mut state_map: HashMap<UtxoInput, UtxoOutput> = HashMap::new();
 
     let mut sam = hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920");
-    let val = 100;
+    let val = Amount::new(100);
     for (i, address) in  address_vec.iter().enumerate() {
         for j in 0..5 {
             sam[0] = i as u8;
             sam[1] = j as u8;
-            let mut initial_tx_hash: H256 = sam.into() ;
+            let initial_tx_hash: H256 = sam.into() ;
             let input = UtxoInput{tx_hash: initial_tx_hash, idx: 0};
-            let output = UtxoOutput{receipient_addr: *address, value: val};
+            let output = UtxoOutput::to_address(*address, val);
             state_map.insert(input, output);
         } 
     }
@@ -64,77 +77,332 @@ impl  UtxoState {
         UtxoState{
             // perform ICO 
             state_map: perform_ico(),
+            coinbase_levels: HashMap::new(),
+            confirmed_levels: HashMap::new(),
         }
     }
 
     pub fn print(&self) {
         println!("Balances {}", self.state_map.len());
-        let mut balance_map: HashMap<H160, u32> = HashMap::new();
+        let mut balance_map: HashMap<H160, Amount> = HashMap::new();
+        let mut locked = Amount::ZERO;
         for (input, output) in self.state_map.iter() {
-            let balance = balance_map.entry(output.receipient_addr).or_insert(0);
-            *balance += output.value;
+            match output.script.address() {
+                Some(addr) => {
+                    let balance = balance_map.entry(addr).or_insert(Amount::ZERO);
+                    *balance = balance.saturating_add(output.value);
+                }
+                None => locked = locked.saturating_add(output.value),
+            }
         }
 
         for (addr, amount) in balance_map.iter() {
             println!("addr: {:?} balance: {}", addr, amount);
         }
+        println!("held by other scripts: {}", locked);
     }
     
     //TODO: Should take Vec<SignedTransaction> for more general purpose
     //As we will be giving only one tx at a time, for now it is fine
-    pub fn update_state(&mut self, signed_tx: &SignedTransaction) {
+    // Outputs are keyed by the hash of the transaction without its witnesses, which
+    // signers commit to and anyone could pad
+    pub fn update_state(&mut self, signed_tx: &SignedTransaction, level: u32) -> TxUndo {
+        let tx_hash = signed_tx.tx.hash();
+        self.confirmed_levels.insert(tx_hash, level);
+        let mut undo = TxUndo { tx_hash, spent: Vec::new(), created: Vec::new() };
         for tx_input in &signed_tx.tx.tx_input {
-            self.state_map.remove(tx_input);
+            if let Some(output) = self.state_map.remove(tx_input) {
+                undo.spent.push((tx_input.clone(), output));
+            }
         }
         
         for (i, tx_output) in (&signed_tx.tx.tx_output).iter().enumerate() {
-            let tx_input = UtxoInput{tx_hash: signed_tx.hash(), idx: i as u8};
-            self.state_map.insert(tx_input, tx_output.clone());
+            let tx_input = UtxoInput{tx_hash, idx: i as u32};
+            self.state_map.insert(tx_input.clone(), tx_output.clone());
+            undo.created.push(tx_input);
         }
+        undo
     }
 
-    //Should it be a "function" rather than "method" of UtxoState??
-    //1. Signature check
-    //2. Owner match
-    //3. Double Spend
-    //4. Input/Output total match
-    pub fn is_tx_valid(&self, signed_tx: &SignedTransaction) -> bool {
-        // println!("current signed_tx {:?}", signed_tx);
+    // Mint the outputs of a coinbase confirmed at ledger level `level`
+    pub fn apply_coinbase(&mut self, coinbase: &SignedTransaction, level: u32) -> TxUndo {
+        let undo = self.update_state(coinbase, level);
+        self.coinbase_levels.insert(undo.tx_hash, level);
+        undo
+    }
 
-        if !transaction::verify(&signed_tx.tx, &signed_tx.signature, &signed_tx.public_key){
-            println!("tx didn't pass signature check!");
-            return false;
-        }
-        
-        let owner_address = address::address_from_public_key_vec_ref(&signed_tx.public_key);
-        let mut total_input_value = 0;
+    // What the inputs of a transaction hold beyond its outputs, 0 if an input is not
+    // in the state (yet) or the outputs exceed the inputs
+    pub fn fee(&self, signed_tx: &SignedTransaction) -> Amount {
+        self.input_value(signed_tx).ok()
+            .zip(signed_tx.tx.output_value())
+            .and_then(|(input, output)| input.checked_sub(output))
+            .unwrap_or(Amount::ZERO)
+    }
+
+    // Total value of the outputs a transaction spends, each of them counts once
+    fn input_value(&self, signed_tx: &SignedTransaction) -> Result<Amount, ValidationError> {
+        let mut seen: HashSet<&UtxoInput> = HashSet::new();
+        let mut total_input_value = Amount::ZERO;
         for input in &signed_tx.tx.tx_input {
-            if !self.state_map.contains_key(&input) {
-               println!("Input is {:?}",input);
-               println!("tx is double spend as input is not there in State!");
-               return false;  
+            if !seen.insert(input) {
+                return Err(ValidationError::DuplicateInput(input.clone()));
             }
+            let output = self.state_map.get(input).ok_or_else(|| ValidationError::MissingInput(input.clone()))?;
+            total_input_value = total_input_value.checked_add(output.value).ok_or(ValidationError::ValueOverflow)?;
+        }
+        Ok(total_input_value)
+    }
 
-            let output = &self.state_map[&input];
-            if output.receipient_addr != owner_address {
-               println!("owner of tx input doesn't match to previous tx output");
-               println!("input addreess {:?}", owner_address);
-               println!("output address {:?}", output.receipient_addr);
-               return false;
-            }
-            total_input_value = output.value;
+    // Revert `update_state`, transactions have to be undone newest first
+    pub fn undo(&mut self, undo: &TxUndo) {
+        self.coinbase_levels.remove(&undo.tx_hash);
+        self.confirmed_levels.remove(&undo.tx_hash);
+        for tx_input in &undo.created {
+            self.state_map.remove(tx_input);
+        }
+        for (tx_input, output) in &undo.spent {
+            self.state_map.insert(tx_input.clone(), output.clone());
         }
+    }
+
+    //Should it be a "function" rather than "method" of UtxoState??
+    //1. Signature check, for the network `chain_id`
+    //2. Witnesses satisfy the locking scripts of the outputs spent
+    //3. Double Spend
+    //4. Inputs cover the outputs, the rest is the fee returned
+    //5. Coinbase outputs are mature at ledger level `level`
+    pub fn validate_tx(&self, signed_tx: &SignedTransaction, level: u32, chain_id: &ChainId) -> Result<Amount, ValidationError> {
+        // println!("current signed_tx {:?}", signed_tx);
+        check_signature(signed_tx, chain_id)?;
         
-        let mut total_output_value = 0;
-        for output in &signed_tx.tx.tx_output {
-             total_output_value += output.value;
+        if signed_tx.witnesses.len() != signed_tx.tx.tx_input.len() {
+            return Err(ValidationError::WitnessCount { inputs: signed_tx.tx.tx_input.len(), witnesses: signed_tx.witnesses.len() });
         }
-  
-        if total_input_value != total_output_value {
-           println!("Input sum didn't match to output sum for tx");
-           return false;
+        for (input, witness) in signed_tx.tx.tx_input.iter().zip(&signed_tx.witnesses) {
+            let output = match self.state_map.get(input) {
+                Some(output) => output,
+                None => return Err(ValidationError::MissingInput(input.clone())),
+            };
+            let confirmed_at = self.confirmed_levels.get(&input.tx_hash).copied().unwrap_or(0);
+            if !output.script.is_satisfied(&Spend::new(witness, level as u64, confirmed_at as u64)) {
+               return Err(ValidationError::UnsatisfiedScript(input.clone()));
+            }
+            if let Some(coinbase_level) = self.coinbase_levels.get(&input.tx_hash) {
+                if level < coinbase_level + COINBASE_MATURITY {
+                    return Err(ValidationError::ImmatureCoinbase(input.clone()));
+                }
+            }
         }
 
-        true
+        let total_input_value = self.input_value(signed_tx)?;
+        let total_output_value = signed_tx.tx.output_value().ok_or(ValidationError::ValueOverflow)?;
+        total_input_value.checked_sub(total_output_value)
+            .ok_or(ValidationError::ValueMismatch { input: total_input_value, output: total_output_value })
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::generate_random_hash;
+    use crate::crypto::key_pair;
+    use crate::script::{LockingScript, Witness};
+    use crate::transaction::{self, SigHash, Transaction};
+    use ring::signature::Ed25519KeyPair;
+
+    fn signed(tx: Transaction, key: &Ed25519KeyPair) -> SignedTransaction {
+        SignedTransaction::signed_by(tx, &ChainId::default(), key)
+    }
+
+    #[test]
+    fn validate_tx_errors() {
+        let owner = key_pair::random();
+        let owner_addr = address::address_from_public_key_ref(owner.public_key());
+        let input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let mut state = UtxoState::default();
+        state.state_map.insert(input.clone(), UtxoOutput::to_address(owner_addr, Amount::new(10)));
+
+        let spend = |value: u64| Transaction {
+            tx_input: vec![input.clone()],
+            tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(value))],
+        };
+
+        assert_eq!(state.validate_tx(&signed(spend(10), &owner), 1, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&signed(spend(9), &owner), 1, &ChainId::default()), Ok(Amount::new(1)));
+        assert_eq!(state.fee(&signed(spend(9), &owner)), Amount::new(1));
+        assert_eq!(state.validate_tx(&signed(spend(11), &owner), 1, &ChainId::default()),
+                   Err(ValidationError::ValueMismatch { input: Amount::new(10), output: Amount::new(11) }));
+        assert_eq!(state.validate_tx(&signed(spend(10), &key_pair::random()), 1, &ChainId::default()),
+                   Err(ValidationError::UnsatisfiedScript(input.clone())));
+
+        let mut bad_signature = signed(spend(10), &owner);
+        bad_signature.witnesses[0].signatures[0].signature[0] ^= 1;
+        assert_eq!(state.validate_tx(&bad_signature, 1, &ChainId::default()), Err(ValidationError::BadSignature));
+        // signed for another network
+        let other_network = ChainId::new("devnet", &generate_random_hash());
+        assert_eq!(state.validate_tx(&signed(spend(10), &owner), 1, &other_network), Err(ValidationError::BadSignature));
+
+        let missing = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let tx = Transaction { tx_input: vec![missing.clone()], tx_output: vec![] };
+        assert_eq!(state.validate_tx(&signed(tx, &owner), 1, &ChainId::default()), Err(ValidationError::MissingInput(missing)));
+    }
+
+    #[test]
+    fn duplicate_inputs() {
+        let owner = key_pair::random();
+        let owner_addr = address::address_from_public_key_ref(owner.public_key());
+        let input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let mut state = UtxoState::default();
+        state.state_map.insert(input.clone(), UtxoOutput::to_address(owner_addr, Amount::new(10)));
+
+        // an input listed twice is not worth twice its value
+        let tx = Transaction {
+            tx_input: vec![input.clone(), input.clone()],
+            tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(15))],
+        };
+        let duplicated = signed(tx, &owner);
+        assert_eq!(state.validate_tx(&duplicated, 1, &ChainId::default()), Err(ValidationError::DuplicateInput(input)));
+        assert_eq!(state.fee(&duplicated), Amount::ZERO);
+    }
+
+    #[test]
+    fn coinbase_maturity() {
+        let miner = key_pair::random();
+        let miner_addr = address::address_from_public_key_ref(miner.public_key());
+        let mut state = UtxoState::default();
+        let coinbase = transaction::coinbase(miner_addr, Amount::new(50));
+        let undo = state.apply_coinbase(&coinbase, 10);
+
+        let reward = UtxoInput { tx_hash: coinbase.tx.hash(), idx: 0 };
+        let spend = signed(Transaction {
+            tx_input: vec![reward.clone()],
+            tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(50))],
+        }, &miner);
+        assert_eq!(state.validate_tx(&spend, 10 + COINBASE_MATURITY - 1, &ChainId::default()), Err(ValidationError::ImmatureCoinbase(reward)));
+        assert_eq!(state.validate_tx(&spend, 10 + COINBASE_MATURITY, &ChainId::default()), Ok(Amount::ZERO));
+
+        state.undo(&undo);
+        assert!(state.state_map.is_empty());
+        assert!(state.coinbase_levels.is_empty());
+    }
+
+    #[test]
+    fn multi_input_multi_output_values() {
+        let owner = key_pair::random();
+        let owner_addr = address::address_from_public_key_ref(owner.public_key());
+        let mut state = UtxoState::default();
+        let mut fund = |value: u64| {
+            let input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+            state.state_map.insert(input.clone(), UtxoOutput::to_address(owner_addr, Amount::new(value)));
+            input
+        };
+        let small = fund(10);
+        let large = fund(20);
+        let huge = fund(u64::MAX - 5);
+
+        let spend = |inputs: &[&UtxoInput], outputs: &[u64]| signed(Transaction {
+            tx_input: inputs.iter().map(|input| (*input).clone()).collect(),
+            tx_output: outputs.iter()
+                .map(|value| UtxoOutput::to_address(address::generate_random_address(), Amount::new(*value)))
+                .collect(),
+        }, &owner);
+
+        // every input counts, whatever the order
+        assert_eq!(state.validate_tx(&spend(&[&small, &large], &[15, 15]), 1, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&spend(&[&large, &small], &[30]), 1, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&spend(&[&small, &large], &[12, 13, 0]), 1, &ChainId::default()), Ok(Amount::new(5)));
+        assert_eq!(state.fee(&spend(&[&small, &large], &[12, 13, 0])), Amount::new(5));
+        assert_eq!(state.validate_tx(&spend(&[&large, &small], &[16, 15]), 1, &ChainId::default()),
+                   Err(ValidationError::ValueMismatch { input: Amount::new(30), output: Amount::new(31) }));
+
+        // values right below the limit are fine, past it they are rejected
+        assert_eq!(state.validate_tx(&spend(&[&huge], &[u64::MAX - 10, 5]), 1, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&spend(&[&huge, &small], &[1]), 1, &ChainId::default()), Err(ValidationError::ValueOverflow));
+        assert_eq!(state.validate_tx(&spend(&[&small], &[u64::MAX, 1]), 1, &ChainId::default()), Err(ValidationError::ValueOverflow));
+        assert_eq!(state.fee(&spend(&[&huge, &small], &[1])), Amount::ZERO);
+
+        let overflowing = spend(&[&small], &[u64::MAX, 1]);
+        assert_eq!(crate::validation::check_tx_structure(&overflowing, &ChainId::default()), Err(ValidationError::ValueOverflow));
+    }
+
+    #[test]
+    fn scripted_outputs() {
+        let (buyer, seller, arbiter) = (key_pair::random(), key_pair::random(), key_pair::random());
+        let addr = |key: &Ed25519KeyPair| address::address_from_public_key_ref(key.public_key());
+        let mut state = UtxoState::default();
+
+        // an escrow confirmed at level 10 that the seller can also take alone from level 20 on
+        let escrow = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let script = LockingScript::Any(vec![
+            LockingScript::Multisig { required: 2, addrs: vec![addr(&buyer), addr(&seller), addr(&arbiter)] },
+            LockingScript::All(vec![LockingScript::RelativeTimelock(10), LockingScript::PayToPubkeyHash(addr(&seller))]),
+        ]);
+        state.state_map.insert(escrow.clone(), UtxoOutput { script, value: Amount::new(10) });
+        state.confirmed_levels.insert(escrow.tx_hash, 10);
+
+        let tx = Transaction {
+            tx_input: vec![escrow.clone()],
+            tx_output: vec![UtxoOutput::to_address(addr(&seller), Amount::new(10))],
+        };
+        let with_keys = |keys: &[&Ed25519KeyPair]| SignedTransaction { witnesses: vec![Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, keys).unwrap()], tx: tx.clone() };
+
+        assert_eq!(state.validate_tx(&with_keys(&[&arbiter, &seller]), 11, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&with_keys(&[&seller]), 19, &ChainId::default()), Err(ValidationError::UnsatisfiedScript(escrow.clone())));
+        assert_eq!(state.validate_tx(&with_keys(&[&seller]), 20, &ChainId::default()), Ok(Amount::ZERO));
+        assert_eq!(state.validate_tx(&SignedTransaction { witnesses: vec![], tx: tx.clone() }, 20, &ChainId::default()),
+                   Err(ValidationError::WitnessCount { inputs: 1, witnesses: 0 }));
+
+        // outputs remember when they were confirmed, until they are undone
+        let undo = state.update_state(&with_keys(&[&buyer, &seller]), 11);
+        assert_eq!(state.confirmed_levels.get(&undo.tx_hash), Some(&11));
+        state.undo(&undo);
+        assert_eq!(state.confirmed_levels.get(&undo.tx_hash), None);
+        assert!(state.state_map.contains_key(&escrow));
+
+        // padding the witness changes the hash of the signed transaction, not its outputs
+        let mut padded = with_keys(&[&buyer, &seller]);
+        padded.witnesses[0].preimages.push(vec![0; 32]);
+        assert_ne!(padded.hash(), with_keys(&[&buyer, &seller]).hash());
+        state.update_state(&padded, 11);
+        let output = UtxoInput { tx_hash: tx.hash(), idx: 0 };
+        assert_eq!(state.confirmed_levels.get(&tx.hash()), Some(&11));
+        let spend = SignedTransaction::signed_by(Transaction {
+            tx_input: vec![output],
+            tx_output: vec![UtxoOutput::to_address(addr(&buyer), Amount::new(10))],
+        }, &ChainId::default(), &seller);
+        assert_eq!(state.validate_tx(&spend, 12, &ChainId::default()), Ok(Amount::ZERO));
+    }
+
+    #[test]
+    fn inputs_of_different_owners() {
+        let (alice, bob) = (key_pair::random(), key_pair::random());
+        let addr = |key: &Ed25519KeyPair| address::address_from_public_key_ref(key.public_key());
+        let mut state = UtxoState::default();
+        let alice_input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        let bob_input = UtxoInput { tx_hash: generate_random_hash(), idx: 0 };
+        state.state_map.insert(alice_input.clone(), UtxoOutput::to_address(addr(&alice), Amount::new(10)));
+        state.state_map.insert(bob_input.clone(), UtxoOutput::to_address(addr(&bob), Amount::new(6)));
+
+        // alice commits to her input and the payment, bob then adds his input and change
+        let mut tx = Transaction {
+            tx_input: vec![alice_input.clone()],
+            tx_output: vec![UtxoOutput::to_address(address::generate_random_address(), Amount::new(10))],
+        };
+        let alice_witness = Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::SINGLE_ANYONE_CAN_PAY, &[&alice]).unwrap();
+        tx.tx_input.push(bob_input);
+        tx.tx_output.push(UtxoOutput::to_address(addr(&bob), Amount::new(5)));
+        let bob_witness = Witness::signed_by(&tx, &ChainId::default(), 1, SigHash::ALL, &[&bob]).unwrap();
+
+        let joint = SignedTransaction { tx: tx.clone(), witnesses: vec![alice_witness.clone(), bob_witness.clone()] };
+        assert_eq!(state.validate_tx(&joint, 1, &ChainId::default()), Ok(Amount::new(1)));
+        let swapped = SignedTransaction { tx: tx.clone(), witnesses: vec![bob_witness.clone(), alice_witness] };
+        assert_eq!(state.validate_tx(&swapped, 1, &ChainId::default()), Err(ValidationError::BadSignature));
+        // a valid signature still has to come from the owner of the spent output
+        let stolen = SignedTransaction {
+            witnesses: vec![Witness::signed_by(&tx, &ChainId::default(), 0, SigHash::ALL, &[&bob]).unwrap(), bob_witness],
+            tx,
+        };
+        assert_eq!(state.validate_tx(&stolen, 1, &ChainId::default()), Err(ValidationError::UnsatisfiedScript(alice_input)));
     }
 }
@@ -3,8 +3,9 @@ use crate::network::server::Handle as ServerHandle;
 use crate::block::{self, *};
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::merkle::{MerkleTree, verify};
-use crate::blockchain::{Blockchain, InsertStatus};
-use crate::miner::{sortition_hash, PROPOSER_INDEX, FIRST_VOTER_IDX};
+use crate::blockchain::Blockchain;
+use crate::miner::{sortition_hash, transaction_index, PROPOSER_INDEX, FIRST_VOTER_IDX};
+use crate::transaction::{Amount, ChainId, SignedTransaction, UtxoInput, COINBASE_IDX};
 
 use log::info;
 use bigint::uint::U256;
@@ -12,46 +13,347 @@ use bigint::uint::U256;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use std::time;
 
+use std::collections::HashSet;
+use std::fmt;
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub enum BlockResult {
-    Pass,
-    Fail,
+// number of proposer ancestors whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// how far, in microseconds, a block timestamp may be ahead of our clock
+pub const MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * 1_000_000;
+// size limits of block content
+pub const MAX_TRANSACTIONS: usize = 1000;
+pub const MAX_PROPOSER_REFS: usize = 1000;
+pub const MAX_TRANSACTION_REFS: usize = 1000;
+pub const MAX_VOTES: usize = 1000;
+// outputs of a transaction are indexed below the coinbase input index
+pub const MAX_OUTPUTS: usize = COINBASE_IDX as usize;
+// reward of a transaction block mined at level 1, halved every HALVING_INTERVAL levels
+pub const INITIAL_BLOCK_REWARD: Amount = Amount::new(50);
+pub const HALVING_INTERVAL: u32 = 210_000;
+// number of ledger levels a coinbase output has to wait before it can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
+// Why a block, header or transaction was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    // a witness signature does not verify against its public key on this network
+    BadSignature,
+    // input is not in the UTXO set, either unknown or already spent
+    MissingInput(UtxoInput),
+    // witness of the input does not satisfy the locking script of the output it spends
+    UnsatisfiedScript(UtxoInput),
+    // transaction needs exactly one witness per input
+    WitnessCount { inputs: usize, witnesses: usize },
+    // output locking script is malformed or exceeds the script limits
+    BadScript,
+    // witness exceeds the witness limits
+    BadWitness,
+    // outputs add up to more than the inputs
+    ValueMismatch { input: Amount, output: Amount },
+    // input or output values add up beyond Amount::MAX
+    ValueOverflow,
+    // hash is not below the difficulty
+    BadPow,
+    // content does not match the chain the hash sortitions to
+    WrongSortition { sortition_id: u32, chain_num: u32 },
+    // sortition proof does not lead to the merkle root of the header
+    BadSortitionProof,
+    // voter chain number outside of 1..=num_voter_chains
+    WrongChainNumber(u32),
+    // proposer content and header disagree on the parent
+    ParentMismatch { header: H256, content: H256 },
+    // header parent is not in the proposer chain
+    UnknownParent(H256),
+    // difficulty differs from the one the proposer chain asks for
+    WrongDifficulty { expected: H256, actual: H256 },
+    // timestamp is not after the median time past
+    TimestampTooOld { median_time_past: u128, timestamp: u128 },
+    // timestamp is too far ahead of our clock
+    TimestampTooNew(u128),
+    // transaction spends nothing
+    NoInputs,
+    // transaction creates nothing
+    NoOutputs,
+    // transaction spends the same output twice
+    DuplicateInput(UtxoInput),
+    // transaction creates more than MAX_OUTPUTS outputs
+    TooManyOutputs(usize),
+    // coinbase anywhere but in front of a transaction block
+    UnexpectedCoinbase,
+    // transaction block does not start with a coinbase
+    MissingCoinbase,
+    // coinbase does not pay a single output to the payout address of the header
+    BadCoinbase,
+    // coinbase mints more than the block reward and the fees of its block
+    WrongReward { allowed: Amount, actual: Amount },
+    // input is a coinbase output younger than COINBASE_MATURITY levels
+    ImmatureCoinbase(UtxoInput),
+    // transaction block carries more than MAX_TRANSACTIONS transactions
+    TooManyTransactions(usize),
+    // proposer references more than MAX_PROPOSER_REFS proposers
+    TooManyProposerRefs(usize),
+    // proposer references more than MAX_TRANSACTION_REFS transaction blocks
+    TooManyTransactionRefs(usize),
+    // voter casts more than MAX_VOTES votes
+    TooManyVotes(usize),
+    // vote is not for a proposer in the proposer chain
+    UnknownVote(H256),
+    // votes have to cover the levels right after the parent's, one per level
+    NonContiguousVote { expected_level: u32, level: u32 },
 }
 
-//PoW and sortition id
-pub fn check_pow_sortition_id(block: &Block, num_voter_chains: u32) -> BlockResult {
-    let sortition_id = sortition_hash(block.hash(), block.header.difficulty, num_voter_chains);
-    if sortition_id.is_none() {
-        println!("New block does not satisy proof-of-work");
-        return BlockResult::Fail;
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::BadSignature => write!(f, "bad transaction signature, or one made for another network"),
+            ValidationError::MissingInput(input) => write!(f, "input {:?} is missing or already spent", input),
+            ValidationError::UnsatisfiedScript(input) => write!(f, "witness does not unlock input {:?}", input),
+            ValidationError::WitnessCount { inputs, witnesses } =>
+                write!(f, "{} witnesses for {} inputs", witnesses, inputs),
+            ValidationError::BadScript => write!(f, "malformed or oversized locking script"),
+            ValidationError::BadWitness => write!(f, "oversized witness"),
+            ValidationError::ValueMismatch { input, output } =>
+                write!(f, "output value {} exceeds input value {}", output, input),
+            ValidationError::ValueOverflow => write!(f, "values add up beyond the largest amount"),
+            ValidationError::BadPow => write!(f, "does not satisfy proof-of-work"),
+            ValidationError::WrongSortition { sortition_id, chain_num } =>
+                write!(f, "sortition hash maps to chain {} but content is for chain {}", sortition_id, chain_num),
+            ValidationError::BadSortitionProof => write!(f, "sortition proof does not match the merkle root"),
+            ValidationError::WrongChainNumber(chain_num) => write!(f, "no voter chain {}", chain_num),
+            ValidationError::ParentMismatch { header, content } =>
+                write!(f, "proposer parent {:?} differs from header parent {:?}", content, header),
+            ValidationError::UnknownParent(parent) => write!(f, "unknown header parent {:?}", parent),
+            ValidationError::WrongDifficulty { expected, actual } =>
+                write!(f, "difficulty {:?} differs from expected {:?}", actual, expected),
+            ValidationError::TimestampTooOld { median_time_past, timestamp } =>
+                write!(f, "timestamp {} is not after median time past {}", timestamp, median_time_past),
+            ValidationError::TimestampTooNew(timestamp) => write!(f, "timestamp {} is too far in the future", timestamp),
+            ValidationError::NoInputs => write!(f, "transaction has no inputs"),
+            ValidationError::NoOutputs => write!(f, "transaction has no outputs"),
+            ValidationError::DuplicateInput(input) => write!(f, "input {:?} is spent twice", input),
+            ValidationError::TooManyOutputs(count) => write!(f, "{} outputs exceed the limit", count),
+            ValidationError::UnexpectedCoinbase => write!(f, "coinbase outside of the first transaction of a block"),
+            ValidationError::MissingCoinbase => write!(f, "transaction block does not start with a coinbase"),
+            ValidationError::BadCoinbase => write!(f, "coinbase does not pay the header payout address"),
+            ValidationError::WrongReward { allowed, actual } =>
+                write!(f, "coinbase mints {} but the block reward and fees allow {}", actual, allowed),
+            ValidationError::ImmatureCoinbase(input) => write!(f, "coinbase output {:?} is not mature yet", input),
+            ValidationError::TooManyTransactions(count) => write!(f, "{} transactions exceed the limit", count),
+            ValidationError::TooManyProposerRefs(count) => write!(f, "{} proposer references exceed the limit", count),
+            ValidationError::TooManyTransactionRefs(count) => write!(f, "{} transaction block references exceed the limit", count),
+            ValidationError::TooManyVotes(count) => write!(f, "{} votes exceed the limit", count),
+            ValidationError::UnknownVote(vote) => write!(f, "vote for unknown proposer {:?}", vote),
+            ValidationError::NonContiguousVote { expected_level, level } =>
+                write!(f, "vote for level {} where level {} was expected", level, expected_level),
+        }
+    }
+}
+
+pub type BlockResult = Result<(), ValidationError>;
+
+//signatures of the witnesses of a transaction for the network `chain_id`, whether they
+//unlock the inputs is up to the locking scripts in the UTXO set
+pub fn check_signature(signed_tx: &SignedTransaction, chain_id: &ChainId) -> Result<(), ValidationError> {
+    if !signed_tx.witnesses.iter().enumerate().all(|(input_index, witness)| witness.verify_signatures(&signed_tx.tx, chain_id, input_index)) {
+        return Err(ValidationError::BadSignature);
+    }
+    Ok(())
+}
+
+//structure of a transaction that does not depend on the UTXO set
+pub fn check_tx_structure(signed_tx: &SignedTransaction, chain_id: &ChainId) -> Result<(), ValidationError> {
+    if signed_tx.tx.tx_input.is_empty() {
+        return Err(ValidationError::NoInputs);
+    }
+    if signed_tx.tx.tx_output.is_empty() {
+        return Err(ValidationError::NoOutputs);
+    }
+    if signed_tx.tx.tx_output.len() > MAX_OUTPUTS {
+        return Err(ValidationError::TooManyOutputs(signed_tx.tx.tx_output.len()));
+    }
+    if signed_tx.tx.is_coinbase() {
+        return Err(ValidationError::UnexpectedCoinbase);
+    }
+    if signed_tx.tx.output_value().is_none() {
+        return Err(ValidationError::ValueOverflow);
+    }
+    if !signed_tx.tx.tx_output.iter().all(|output| output.script.is_well_formed()) {
+        return Err(ValidationError::BadScript);
+    }
+    if signed_tx.witnesses.len() != signed_tx.tx.tx_input.len() {
+        return Err(ValidationError::WitnessCount { inputs: signed_tx.tx.tx_input.len(), witnesses: signed_tx.witnesses.len() });
+    }
+    if !signed_tx.witnesses.iter().all(|witness| witness.is_within_limits()) {
+        return Err(ValidationError::BadWitness);
+    }
+    let mut inputs = HashSet::new();
+    for input in &signed_tx.tx.tx_input {
+        if !inputs.insert(input) {
+            return Err(ValidationError::DuplicateInput(input.clone()));
+        }
+    }
+    check_signature(signed_tx, chain_id)
+}
+
+//content checks that do not need the blockchain, run before a received block is inserted
+pub fn check_content(block: &Block, chain_id: &ChainId) -> BlockResult {
+    match &block.content {
+        Content::Proposer(content) => {
+            if content.transaction_refs.len() > MAX_TRANSACTION_REFS {
+                return Err(ValidationError::TooManyTransactionRefs(content.transaction_refs.len()));
+            }
+            if content.proposer_refs.len() > MAX_PROPOSER_REFS {
+                return Err(ValidationError::TooManyProposerRefs(content.proposer_refs.len()));
+            }
+            Ok(())
+        }
+        Content::Voter(content) => {
+            if content.votes.len() > MAX_VOTES {
+                return Err(ValidationError::TooManyVotes(content.votes.len()));
+            }
+            Ok(())
+        }
+        Content::Transaction(content) => {
+            if content.transactions.len() > MAX_TRANSACTIONS {
+                return Err(ValidationError::TooManyTransactions(content.transactions.len()));
+            }
+            match content.transactions.first() {
+                Some(coinbase) if coinbase.tx.is_coinbase() => {}
+                _ => return Err(ValidationError::MissingCoinbase),
+            }
+            content.transactions[1..].iter().try_for_each(|signed_tx| check_tx_structure(signed_tx, chain_id))
+        }
+    }
+}
+
+//votes of a voter block whose references are all present: one per proposer level,
+//starting right after the last level voted for by its parent
+pub fn check_votes(content: &VoterContent, blockchain: &Blockchain) -> BlockResult {
+    let mut expected_level = blockchain.voter2level[&content.parent_hash] + 1;
+    for vote in &content.votes {
+        let level = match blockchain.proposer_chain.get(vote) {
+            Some(metablock) => metablock.level,
+            None => return Err(ValidationError::UnknownVote(*vote)),
+        };
+        if level != expected_level {
+            return Err(ValidationError::NonContiguousVote { expected_level, level });
+        }
+        expected_level += 1;
+    }
+    Ok(())
+}
+
+// Reward of a transaction block mined on a proposer at level `level - 1`
+pub fn block_reward(level: u32) -> Amount {
+    Amount::new(INITIAL_BLOCK_REWARD.as_u64().checked_shr(level / HALVING_INTERVAL).unwrap_or(0))
+}
+
+//coinbase of a transaction block: a single output paying the payout address of the
+//header. What it may mint depends on the fees, it is checked by `check_reward` once
+//the block's transactions are confirmed
+pub fn check_coinbase(block: &Block, content: &TransactionContent) -> BlockResult {
+    let coinbase = match content.transactions.first() {
+        Some(coinbase) if coinbase.tx.is_coinbase() => &coinbase.tx,
+        _ => return Err(ValidationError::MissingCoinbase),
+    };
+    match coinbase.tx_output.as_slice() {
+        [output] if output.script.address() == Some(block.header.payout_addr) => Ok(()),
+        _ => Err(ValidationError::BadCoinbase),
     }
+}
+
+//coinbase confirmed with the transactions of its block: it mints at most `reward`
+//and the fees of those transactions that made it into the ledger
+pub fn check_reward(coinbase: &SignedTransaction, reward: Amount, fees: Amount) -> BlockResult {
+    let allowed = reward.checked_add(fees).ok_or(ValidationError::ValueOverflow)?;
+    let actual = coinbase.tx.output_value().ok_or(ValidationError::ValueOverflow)?;
+    if actual > allowed {
+        return Err(ValidationError::WrongReward { allowed, actual });
+    }
+    Ok(())
+}
 
+//PoW and sortition id
+pub fn check_pow_sortition_id(block: &Block, num_voter_chains: u32) -> BlockResult {
     let correct_sortition_id = match &block.content {
         Content::Proposer(_) => PROPOSER_INDEX,
         Content::Voter(content) => content.chain_num,
+        Content::Transaction(_) => transaction_index(num_voter_chains),
     };
-    if sortition_id.unwrap() != correct_sortition_id {
-        println!("Sortition check failed: sortition hash {} content mapping {}", sortition_id.unwrap(), correct_sortition_id);
-        return BlockResult::Fail;
+    if let Content::Voter(_) = &block.content {
+        if correct_sortition_id < FIRST_VOTER_IDX || correct_sortition_id > num_voter_chains {
+            return Err(ValidationError::WrongChainNumber(correct_sortition_id));
+        }
+    }
+
+    let sortition_id = sortition_hash(block.hash(), block.header.difficulty, num_voter_chains)
+        .ok_or(ValidationError::BadPow)?;
+    if sortition_id != correct_sortition_id {
+        return Err(ValidationError::WrongSortition { sortition_id, chain_num: correct_sortition_id });
+    }
+    Ok(())
+}
+
+//PoW and sortition id of a header announced for chain `chain_idx`, before its content is known
+pub fn check_header(header: &Header, chain_idx: u32, num_voter_chains: u32) -> BlockResult {
+    match sortition_hash(header.hash(), header.difficulty, num_voter_chains) {
+        Some(sortition_id) if sortition_id == chain_idx => Ok(()),
+        Some(sortition_id) => Err(ValidationError::WrongSortition { sortition_id, chain_num: chain_idx }),
+        None => Err(ValidationError::BadPow),
     }
-    return BlockResult::Pass;
+}
+
+//difficulty must follow the proposer chain the block was mined on, see Blockchain::next_difficulty
+pub fn check_difficulty(block: &Block, blockchain: &Blockchain) -> BlockResult {
+    if let Content::Proposer(content) = &block.content {
+        if content.parent_hash != block.header.parent {
+            return Err(ValidationError::ParentMismatch { header: block.header.parent, content: content.parent_hash });
+        }
+    }
+    if !blockchain.proposer_chain.contains_key(&block.header.parent) {
+        return Err(ValidationError::UnknownParent(block.header.parent));
+    }
+    let expected = blockchain.next_difficulty(&block.header.parent);
+    if block.header.difficulty != expected {
+        return Err(ValidationError::WrongDifficulty { expected, actual: block.header.difficulty });
+    }
+    Ok(())
+}
+
+//timestamp must be past the median of the last proposers and not too far in the future
+pub fn check_timestamp(block: &Block, blockchain: &Blockchain, now: u128) -> BlockResult {
+    if !blockchain.proposer_chain.contains_key(&block.header.parent) {
+        return Err(ValidationError::UnknownParent(block.header.parent));
+    }
+    let median_time_past = blockchain.median_time_past(&block.header.parent);
+    if block.header.timestamp <= median_time_past {
+        return Err(ValidationError::TimestampTooOld { median_time_past, timestamp: block.header.timestamp });
+    }
+    if block.header.timestamp > now + MAX_FUTURE_DRIFT {
+        return Err(ValidationError::TimestampTooNew(block.header.timestamp));
+    }
+    Ok(())
+}
+
+// Current time in microseconds, the unit of block timestamps
+pub fn now_micros() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros()
 }
 
 //check merkle tree there
 pub fn check_sortition_proof(block: &Block, num_voter_chains: u32) -> BlockResult {
-    let sortition_id = sortition_hash(block.hash(), block.header.difficulty, num_voter_chains);
-    if sortition_id.is_none() {
-        return BlockResult::Fail;
-    }
+    let sortition_id = sortition_hash(block.hash(), block.header.difficulty, num_voter_chains)
+        .ok_or(ValidationError::BadPow)?;
     if !verify(
         &block.header.merkle_root,
         &block.content.hash(),
         &block.sortition_proof,
-        sortition_id.unwrap() as usize,
-        (num_voter_chains + FIRST_VOTER_IDX) as usize,
+        sortition_id as usize,
+        (transaction_index(num_voter_chains) + 1) as usize,
     ) {
-        return BlockResult::Fail;
+        return Err(ValidationError::BadSortitionProof);
     }
-    return BlockResult::Pass;
+    Ok(())
 }